@@ -1,12 +1,22 @@
 use crate::layout;
+use crate::memory::PhysicalAddress;
 use crate::process::ThreadToken;
 use crate::scheduler::HardwareThread;
 use crate::sync::{YieldMutex, YieldMutexGuard};
+use core::ops::Range;
 use core::pin::Pin;
 
 static mut HEAP_TOP: usize = 0;
 const PAGE_SIZE: usize = 4096;
 
+/// Maximum number of reserved ranges.
+const MAX_RESERVED: usize = 8;
+
+/// Virtual address ranges inside RAM that the heap must never grow into.
+static mut RESERVED: [Range<usize>; MAX_RESERVED] =
+    [0..0, 0..0, 0..0, 0..0, 0..0, 0..0, 0..0, 0..0];
+static mut NUM_RESERVED: usize = 0;
+
 /// Mutex for the global allocator.
 ///
 /// Using `YieldMutex` instead of sleeping mutex here to prevent re-entering the allocator itself.
@@ -18,7 +28,48 @@ pub fn init() {
         HEAP_TOP = layout::kernel_end().0;
         assert!(HEAP_TOP % PAGE_SIZE == 0);
     }
-    println!("allocator: Initialized.");
+    info!("Initialized.");
+}
+
+/// Reserves a physical range so that the heap never hands it out.
+///
+/// # Safety
+///
+/// Can only be called on the boot hart, before the first allocation.
+pub unsafe fn reserve(range: Range<PhysicalAddress>) {
+    assert!(
+        NUM_RESERVED < MAX_RESERVED,
+        "allocator::reserve: too many reserved ranges"
+    );
+    let start = range
+        .start
+        .to_virt()
+        .expect("allocator::reserve: bad start address")
+        .0;
+    let end = range
+        .end
+        .to_virt()
+        .expect("allocator::reserve: bad end address")
+        .0;
+    RESERVED[NUM_RESERVED] = start..end;
+    NUM_RESERVED += 1;
+    info!("Reserved {:#x}-{:#x}.", range.start.0, range.end.0);
+}
+
+/// Returns the first address at or after `start` where `size` bytes do not overlap any
+/// reserved range.
+fn skip_reserved(mut start: usize, size: usize) -> Option<usize> {
+    let reserved = unsafe { &RESERVED[..NUM_RESERVED] };
+    'retry: loop {
+        let end = start.checked_add(size)?;
+        for r in reserved {
+            if start < r.end && r.start < end {
+                start = r.end.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+                continue 'retry;
+            }
+        }
+        break Some(start);
+    }
 }
 
 /// Enables locking for the global allocator.
@@ -32,7 +83,7 @@ pub unsafe fn enable_locking() {
         "allocator::enable_locking: attempting to enable locking twice"
     );
     LOCKING = true;
-    println!("allocator: Locking enabled.");
+    info!("Locking enabled.");
 }
 
 pub fn heap_usage() -> usize {
@@ -46,7 +97,10 @@ fn foo(_: core::alloc::Layout) -> ! {
 
 #[no_mangle]
 extern "C" fn __dlmalloc_alloc(size: usize) -> usize {
-    let old_top = unsafe { HEAP_TOP };
+    let old_top = match skip_reserved(unsafe { HEAP_TOP }, size) {
+        Some(x) => x,
+        None => return usize::MAX,
+    };
     match old_top.checked_add(size) {
         Some(x) if x <= layout::ram_end().0 => {
             unsafe {
@@ -0,0 +1,175 @@
+//! Kernel command line, taken from `/chosen/bootargs` in the device tree.
+//!
+//! Subsystems declare typed parameters as `Param` statics and list them in `PARAMS`. The command
+//! line is parsed once at boot. Unknown parameters are reported and ignored, and invalid values
+//! stop the boot.
+
+use crate::dtb;
+use crate::init;
+use crate::log;
+use crate::net;
+use crate::scheduler;
+use crate::sync::Once;
+use core::fmt::Debug;
+use core::num::{NonZeroU32, NonZeroU64};
+
+/// All parameters known to the kernel.
+static PARAMS: &[&dyn AnyParam] = &[
+    &scheduler::QUANTUM_US,
+    &scheduler::MAX_TICKS,
+    &init::INIT,
+    &init::TEST,
+    &init::SHUTDOWN,
+    &log::LOG,
+    &net::ADDRESS,
+    &net::GATEWAY,
+];
+
+/// A value that can be parsed from the command line.
+pub trait ParamValue: Copy + Debug + Send + Sync + 'static {
+    /// Parses and validates `s`. `s` is `"true"` for a parameter given without a value.
+    fn parse(s: &'static str) -> Option<Self>;
+}
+
+/// A typed command line parameter.
+pub struct Param<T> {
+    name: &'static str,
+    help: &'static str,
+    default: T,
+    value: Once<T>,
+}
+
+/// Type-erased interface used by the parser.
+trait AnyParam: Sync {
+    fn name(&self) -> &'static str;
+    fn set(&self, value: &'static str) -> Result<(), &'static str>;
+    fn print(&self);
+}
+
+impl<T> Param<T> {
+    pub const fn new(name: &'static str, default: T, help: &'static str) -> Param<T> {
+        Param {
+            name,
+            help,
+            default,
+            value: Once::new(),
+        }
+    }
+}
+
+impl<T: ParamValue> Param<T> {
+    /// Returns the value from the command line, or the default.
+    pub fn get(&self) -> T {
+        match self.value.r#try() {
+            Some(x) => *x,
+            None => self.default,
+        }
+    }
+}
+
+impl<T: ParamValue> AnyParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: &'static str) -> Result<(), &'static str> {
+        if self.value.r#try().is_some() {
+            return Err("given more than once");
+        }
+        let value = T::parse(value).ok_or("invalid value")?;
+        self.value.call_once(|| value);
+        Ok(())
+    }
+
+    fn print(&self) {
+        println!(
+            "- {} = {:?}{} ({})",
+            self.name,
+            self.get(),
+            if self.value.r#try().is_some() {
+                ""
+            } else {
+                " (default)"
+            },
+            self.help
+        );
+    }
+}
+
+impl ParamValue for bool {
+    fn parse(s: &'static str) -> Option<bool> {
+        match s {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(s: &'static str) -> Option<&'static str> {
+        Some(s)
+    }
+}
+
+macro_rules! impl_param_value_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl ParamValue for $t {
+                fn parse(s: &'static str) -> Option<$t> {
+                    match s.strip_prefix("0x") {
+                        Some(hex) => <$t>::from_str_radix(hex, 16).ok(),
+                        None => s.parse().ok(),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_param_value_for_int!(u32, u64, usize);
+
+impl ParamValue for NonZeroU32 {
+    fn parse(s: &'static str) -> Option<NonZeroU32> {
+        NonZeroU32::new(u32::parse(s)?)
+    }
+}
+
+impl ParamValue for NonZeroU64 {
+    fn parse(s: &'static str) -> Option<NonZeroU64> {
+        NonZeroU64::new(u64::parse(s)?)
+    }
+}
+
+/// Returns the raw command line.
+pub fn raw() -> &'static str {
+    dtb::device_tree().chosen().bootargs.unwrap_or("")
+}
+
+/// Parses the command line into the registered parameters.
+///
+/// Must be called once on the boot hart after `dtb::init`, before any parameter is used.
+pub fn init() {
+    for arg in raw().split_whitespace() {
+        let (name, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => (arg, "true"),
+        };
+        match PARAMS.iter().find(|x| x.name() == name) {
+            Some(param) => {
+                if let Err(e) = param.set(value) {
+                    panic!("cmdline: {}: {}: '{}'", name, e, value);
+                }
+            }
+            None => warn!("Ignoring unknown parameter '{}'.", arg),
+        }
+    }
+    info!("Initialized.");
+}
+
+pub fn print() {
+    println!("Kernel parameters:");
+    for param in PARAMS {
+        param.print();
+    }
+}
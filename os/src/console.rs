@@ -1,14 +1,69 @@
+//! Kernel console. Writes to the UART once its driver is up, and to the SBI console before.
+//!
+//! Each `print` holds a console lock, so output of different harts does not interleave within a
+//! call. The lock is reentrant on the same hart and taken with interrupts disabled, so it can be
+//! used from interrupt context and never allocates. A panicking hart steals it.
+
+use crate::drivers::uart;
 use crate::sbi;
+use crate::smp;
 use core::fmt::{self, Write};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+/// Hart ID plus one of the hart holding the console lock, or 0.
+static OWNER: AtomicUsize = AtomicUsize::new(0);
 
 struct Console;
 
+/// Holds the console lock. Interrupts are disabled until dropped.
+struct ConsoleGuard {
+    /// Whether this hart already held the lock.
+    nested: bool,
+    sie: bool,
+}
+
+impl ConsoleGuard {
+    fn lock() -> ConsoleGuard {
+        let sie = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        let me = smp::current_hart_id() as usize + 1;
+        let nested = OWNER.load(Ordering::SeqCst) == me;
+        if !nested {
+            while OWNER
+                .compare_exchange_weak(0, me, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                spin_loop_hint();
+            }
+        }
+        ConsoleGuard { nested, sie }
+    }
+}
+
+impl Drop for ConsoleGuard {
+    fn drop(&mut self) {
+        if !self.nested {
+            OWNER.store(0, Ordering::Release);
+        }
+        if self.sie {
+            unsafe {
+                sstatus::set_sie();
+            }
+        }
+    }
+}
+
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let mut buffer = [0u8; 4];
-        for c in s.chars() {
-            for code_point in c.encode_utf8(&mut buffer).as_bytes().iter() {
-                sbi::console_putchar(*code_point);
+        match uart::uart() {
+            Some(uart) => uart.write(s.as_bytes()),
+            None => {
+                for &byte in s.as_bytes() {
+                    sbi::console_putchar(byte);
+                }
             }
         }
         Ok(())
@@ -16,9 +71,39 @@ impl Write for Console {
 }
 
 pub fn print(args: fmt::Arguments) {
+    let _guard = ConsoleGuard::lock();
     Console.write_fmt(args).unwrap();
 }
 
+/// Takes the console over from whichever hart holds it. Called on panic, after other harts have
+/// been asked to stop.
+pub fn panic_steal() {
+    OWNER.store(smp::current_hart_id() as usize + 1, Ordering::SeqCst);
+    if let Some(uart) = uart::uart() {
+        unsafe {
+            uart.force_unlock();
+        }
+    }
+}
+
+/// Returns the next input byte, if any.
+pub fn getchar() -> Option<u8> {
+    match uart::uart() {
+        Some(uart) => uart.read_byte(),
+        None => match sbi::console_getchar() {
+            -1 => None,
+            x => Some(x as u8),
+        },
+    }
+}
+
+/// Waits until all output has been handed to the hardware.
+pub fn flush() {
+    if let Some(uart) = uart::uart() {
+        uart.flush();
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($fmt: literal $(, $($arg: tt)+)?) => {
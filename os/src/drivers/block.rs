@@ -0,0 +1,84 @@
+//! Block devices.
+
+use crate::error::*;
+use crate::process::ThreadToken;
+use crate::scheduler::HardwareThread;
+use crate::sync::Once;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+static DEVICES: Once<Vec<&'static dyn BlockDevice>> = Once::new();
+
+/// Buffer of a transfer. Its length must be a multiple of the block size.
+pub enum BlockOp<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &str;
+
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    fn read_only(&self) -> bool;
+
+    /// Starts a transfer starting at block `block`. The buffer stays borrowed until the returned
+    /// request is done.
+    ///
+    /// Buffers must be in the kernel's linear mapping, e.g. on the heap.
+    fn submit<'a>(
+        &'a self,
+        op: BlockOp<'a>,
+        block: u64,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<Box<dyn BlockRequest + 'a>>;
+
+    fn read(
+        &self,
+        block: u64,
+        buf: &mut [u8],
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        self.submit(BlockOp::Read(buf), block, ht, token)?
+            .wait(ht, token)
+    }
+
+    fn write(
+        &self,
+        block: u64,
+        buf: &[u8],
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        self.submit(BlockOp::Write(buf), block, ht, token)?
+            .wait(ht, token)
+    }
+}
+
+/// A submitted transfer. Dropping it waits for the transfer to finish.
+pub trait BlockRequest {
+    fn is_done(&self) -> bool;
+
+    /// Waits for the transfer to finish.
+    fn wait(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) -> KernelResult<()>;
+}
+
+/// Records the block devices found by the drivers. Can only be called once.
+pub fn set_devices(devices: Vec<&'static dyn BlockDevice>) {
+    assert!(
+        DEVICES.r#try().is_none(),
+        "block::set_devices: called twice"
+    );
+    DEVICES.call_once(|| devices);
+}
+
+pub fn devices() -> &'static [&'static dyn BlockDevice] {
+    match DEVICES.r#try() {
+        Some(x) => x,
+        None => &[],
+    }
+}
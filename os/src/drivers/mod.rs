@@ -0,0 +1,30 @@
+//! Device drivers.
+
+pub mod block;
+pub mod net;
+pub mod plic;
+pub mod rtc;
+pub mod sifive_test;
+pub mod uart;
+pub mod virtio;
+
+use crate::process::ThreadToken;
+
+/// Sets up interrupt routing for the drivers. The console UART is initialized earlier.
+///
+/// Must be called on the boot hart after `interrupt::init`.
+pub fn init() {
+    plic::init();
+    if let Err(e) = uart::init_irq() {
+        warn!("UART interrupt unavailable: {:?}. Polling instead.", e);
+    }
+    virtio::init();
+}
+
+/// Probes devices that need the allocator and memory protections.
+///
+/// Must be called from the init thread after `memory::remap_kernel` and
+/// `allocator::enable_locking`.
+pub fn start(token: &ThreadToken) {
+    virtio::probe(token);
+}
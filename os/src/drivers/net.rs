@@ -0,0 +1,57 @@
+//! Network devices.
+
+use crate::error::*;
+use crate::process::ThreadToken;
+use crate::sync::Once;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest Ethernet frame, without the FCS.
+pub const MAX_FRAME: usize = 1514;
+
+static DEVICES: Once<Vec<&'static dyn NetDevice>> = Once::new();
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let x = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            x[0], x[1], x[2], x[3], x[4], x[5]
+        )
+    }
+}
+
+/// An Ethernet device. Polled by the network stack.
+pub trait NetDevice: Sync {
+    fn name(&self) -> &str;
+
+    fn mac(&self) -> MacAddr;
+
+    /// Queues a frame for transmission. Returns `Busy` if the device has no room.
+    fn transmit(&self, frame: &[u8], token: &ThreadToken) -> KernelResult<()>;
+
+    /// Copies the next received frame into `buf`, which should hold `MAX_FRAME` bytes, and
+    /// returns its length.
+    fn receive(&self, buf: &mut [u8], token: &ThreadToken) -> Option<usize>;
+}
+
+/// Records the network devices found by the drivers. Can only be called once.
+pub fn set_devices(devices: Vec<&'static dyn NetDevice>) {
+    assert!(DEVICES.r#try().is_none(), "net::set_devices: called twice");
+    DEVICES.call_once(|| devices);
+}
+
+pub fn devices() -> &'static [&'static dyn NetDevice] {
+    match DEVICES.r#try() {
+        Some(x) => x,
+        None => &[],
+    }
+}
@@ -0,0 +1,172 @@
+//! Driver for the RISC-V Platform-Level Interrupt Controller.
+//!
+//! Every hart has a supervisor-mode context, found through `interrupts-extended` in the device
+//! tree. Enabled interrupts are routed to the contexts of all harts; the hart that claims an
+//! interrupt first handles it.
+
+use crate::dtb::{self, read_cells, Node};
+use crate::memory::tlb::MAX_HART_ID;
+use crate::sync::Once;
+use core::ptr;
+
+const COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+/// Interrupt number of supervisor external interrupts, as used in `interrupts-extended`.
+const IRQ_S_EXT: u32 = 9;
+
+/// Sources are numbered from 1. 0 means "no interrupt" when claiming.
+pub const MAX_IRQ: u32 = 1023;
+
+const PRIORITY_BASE: usize = 0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x200000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0;
+const CONTEXT_CLAIM: usize = 4;
+
+static PLIC: Once<Plic> = Once::new();
+
+pub struct Plic {
+    /// Virtual address of the registers.
+    base: usize,
+
+    /// Number of interrupt sources.
+    num_irqs: u32,
+
+    /// Supervisor-mode context of each hart, by hart ID.
+    contexts: [Option<u32>; MAX_HART_ID as usize + 1],
+}
+
+impl Plic {
+    fn from_node(node: &Node) -> Option<Plic> {
+        let mut plic = Plic {
+            base: node.map_mmio()?,
+            num_irqs: node.prop_u32("riscv,ndev")?.min(MAX_IRQ),
+            contexts: [None; MAX_HART_ID as usize + 1],
+        };
+
+        // `interrupts-extended` is a list of `(phandle, interrupt)` pairs, one per context.
+        let dt = dtb::device_tree();
+        let mut cells = node.prop("interrupts-extended")?;
+        let mut context = 0;
+        while let Some((phandle, rest)) = read_cells(cells, 1) {
+            let (irq, rest) = read_cells(rest, 1)?;
+            cells = rest;
+            if irq as u32 == IRQ_S_EXT {
+                // The phandle refers to the interrupt controller of a cpu node.
+                let cpu = dt.find_by_phandle(phandle as u32).and_then(|x| x.parent);
+                let hart = dt.harts().iter().find(|x| Some(x.node) == cpu);
+                if let Some(hart) = hart {
+                    if hart.id <= MAX_HART_ID {
+                        plic.contexts[hart.id as usize] = Some(context);
+                    }
+                }
+            }
+            context += 1;
+        }
+        Some(plic)
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.reg(offset), value) }
+    }
+
+    pub fn num_irqs(&self) -> u32 {
+        self.num_irqs
+    }
+
+    /// Returns the supervisor-mode context of a hart.
+    pub fn context(&self, hart_id: u32) -> Option<u32> {
+        self.contexts.get(hart_id as usize).copied().flatten()
+    }
+
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY_BASE + 4 * irq as usize, priority);
+    }
+
+    /// Sets the priority an interrupt needs to be delivered to a context.
+    pub fn set_threshold(&self, context: u32, threshold: u32) {
+        self.write(
+            CONTEXT_BASE + CONTEXT_STRIDE * context as usize + CONTEXT_THRESHOLD,
+            threshold,
+        );
+    }
+
+    pub fn set_enabled(&self, context: u32, irq: u32, enabled: bool) {
+        let offset = ENABLE_BASE + ENABLE_STRIDE * context as usize + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+        let value = self.read(offset);
+        self.write(offset, if enabled { value | bit } else { value & !bit });
+    }
+
+    /// Routes an interrupt to all harts.
+    ///
+    /// Each enable word holds the bits of 32 interrupts, so concurrent calls must be serialized.
+    pub fn enable(&self, irq: u32) {
+        self.set_priority(irq, 1);
+        for context in self.contexts.iter().filter_map(|x| *x) {
+            self.set_enabled(context, irq, true);
+        }
+    }
+
+    /// Claims the highest priority pending interrupt of a context, if any.
+    pub fn claim(&self, context: u32) -> Option<u32> {
+        match self.read(CONTEXT_BASE + CONTEXT_STRIDE * context as usize + CONTEXT_CLAIM) {
+            0 => None,
+            x => Some(x),
+        }
+    }
+
+    /// Signals that a claimed interrupt has been handled.
+    pub fn complete(&self, context: u32, irq: u32) {
+        self.write(
+            CONTEXT_BASE + CONTEXT_STRIDE * context as usize + CONTEXT_CLAIM,
+            irq,
+        );
+    }
+}
+
+/// Finds the PLIC in the device tree. All interrupts start disabled.
+pub fn init() {
+    let dt = dtb::device_tree();
+    let node = COMPATIBLE
+        .iter()
+        .filter_map(|x| dt.find_compatible(x).next())
+        .next();
+    let plic = match node.and_then(Plic::from_node) {
+        Some(x) => x,
+        None => {
+            warn!("Not found. External interrupts are unavailable.");
+            return;
+        }
+    };
+    for irq in 1..=plic.num_irqs() {
+        plic.set_priority(irq, 0);
+    }
+    for context in plic.contexts.iter().filter_map(|x| *x) {
+        for word in 0..=plic.num_irqs() as usize / 32 {
+            plic.write(ENABLE_BASE + ENABLE_STRIDE * context as usize + 4 * word, 0);
+        }
+        plic.set_threshold(context, 0);
+    }
+    let plic = PLIC.call_once(|| plic);
+    info!(
+        "Initialized. {} source(s), {} hart context(s).",
+        plic.num_irqs(),
+        plic.contexts.iter().filter(|x| x.is_some()).count()
+    );
+}
+
+/// Returns the PLIC, once initialized.
+pub fn plic() -> Option<&'static Plic> {
+    PLIC.r#try()
+}
@@ -0,0 +1,54 @@
+//! Driver for the Goldfish RTC of the QEMU `virt` machine.
+
+use crate::dtb;
+use crate::sync::Once;
+use core::ptr;
+
+const COMPATIBLE: &str = "google,goldfish-rtc";
+
+// Registers, as byte offsets.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+static RTC: Once<Rtc> = Once::new();
+
+pub struct Rtc {
+    /// Virtual address of the registers.
+    base: usize,
+}
+
+impl Rtc {
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    /// Nanoseconds since the Unix epoch.
+    pub fn read_ns(&self) -> u64 {
+        // Reading the low word latches the high word.
+        let low = self.read_reg(TIME_LOW);
+        let high = self.read_reg(TIME_HIGH);
+        (high as u64) << 32 | low as u64
+    }
+}
+
+/// Finds the RTC in the device tree and maps its registers.
+pub fn init() {
+    let node = match dtb::device_tree().find_compatible(COMPATIBLE).next() {
+        Some(x) => x,
+        None => return,
+    };
+    let base = match node.map_mmio() {
+        Some(x) => x,
+        None => {
+            warn!("Bad {} node {}.", COMPATIBLE, node.path);
+            return;
+        }
+    };
+    RTC.call_once(|| Rtc { base });
+    info!("Initialized. {} at {:#x}.", node.path, base);
+}
+
+/// Returns the RTC, if there is one.
+pub fn rtc() -> Option<&'static Rtc> {
+    RTC.r#try()
+}
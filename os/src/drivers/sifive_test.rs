@@ -0,0 +1,65 @@
+//! Driver for the SiFive test device of the QEMU `virt` machine, which powers off or resets the
+//! machine. On power-off QEMU exits with a status given by the kernel.
+
+use crate::dtb;
+use crate::sync::Once;
+use core::num::NonZeroU16;
+use core::ptr;
+
+const COMPATIBLE: &str = "sifive,test0";
+
+// Commands, written to the only register.
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+static DEVICE: Once<TestDevice> = Once::new();
+
+pub struct TestDevice {
+    /// Virtual address of the register.
+    base: usize,
+}
+
+impl TestDevice {
+    fn write(&self, value: u32) {
+        unsafe { ptr::write_volatile(self.base as *mut u32, value) }
+    }
+
+    /// Powers off. QEMU exits with status 0. Returns if the device ignored the command.
+    pub fn pass(&self) {
+        self.write(FINISHER_PASS);
+    }
+
+    /// Powers off. QEMU exits with status `code`, so it must be nonzero to tell from a pass.
+    /// Returns if the device ignored the command.
+    pub fn fail(&self, code: NonZeroU16) {
+        self.write((code.get() as u32) << 16 | FINISHER_FAIL);
+    }
+
+    /// Resets the machine. Returns if the device ignored the command.
+    pub fn reset(&self) {
+        self.write(FINISHER_RESET);
+    }
+}
+
+/// Finds the test device in the device tree and maps its registers.
+pub fn init() {
+    let node = match dtb::device_tree().find_compatible(COMPATIBLE).next() {
+        Some(x) => x,
+        None => return,
+    };
+    let base = match node.map_mmio() {
+        Some(x) => x,
+        None => {
+            warn!("Bad {} node {}.", COMPATIBLE, node.path);
+            return;
+        }
+    };
+    DEVICE.call_once(|| TestDevice { base });
+    info!("Initialized. {} at {:#x}.", node.path, base);
+}
+
+/// Returns the test device, if there is one.
+pub fn device() -> Option<&'static TestDevice> {
+    DEVICE.r#try()
+}
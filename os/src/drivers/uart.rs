@@ -0,0 +1,274 @@
+//! Driver for the NS16550A UART of the QEMU `virt` machine.
+//!
+//! Output is queued in a ring buffer and moved to the transmit FIFO whenever it has room. Input
+//! is moved from the receive FIFO into another ring buffer until read. Both happen in
+//! `handle_irq`, which is also called by `poll` while the UART's interrupt is not routed to us.
+
+use crate::dtb::{self, Node};
+use crate::error::*;
+use crate::interrupt::{register_irq_handler, IrqHandler};
+use crate::sync::{without_interrupts_early, Once};
+use arraydeque::ArrayDeque;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex as SpinMutex;
+
+const COMPATIBLE: &str = "ns16550a";
+
+// Registers, as register indices.
+const RBR: usize = 0;
+const THR: usize = 0;
+const DLL: usize = 0;
+const IER: usize = 1;
+const DLM: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const FCR_ENABLE_AND_CLEAR: u8 = 0x07;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const FIFO_SIZE: usize = 16;
+const BAUD_RATE: u32 = 115200;
+const TX_BUFFER_SIZE: usize = 4096;
+const RX_BUFFER_SIZE: usize = 256;
+
+static UART: Once<Uart> = Once::new();
+
+pub struct Uart {
+    /// Virtual address of the registers.
+    base: usize,
+
+    /// log2 of the distance between registers, in bytes.
+    reg_shift: u32,
+
+    /// Whether registers must be accessed as 32-bit words.
+    wide_io: bool,
+
+    /// Interrupt line, from the device tree.
+    irq: Option<u32>,
+
+    /// Whether `handle_irq` is called on interrupts. Until then, output is written synchronously.
+    irq_enabled: AtomicBool,
+
+    /// Only taken with interrupts disabled.
+    buffers: SpinMutex<Buffers>,
+}
+
+struct Buffers {
+    tx: ArrayDeque<[u8; TX_BUFFER_SIZE]>,
+    rx: ArrayDeque<[u8; RX_BUFFER_SIZE]>,
+}
+
+impl Uart {
+    fn from_node(node: &Node) -> Option<Uart> {
+        Some(Uart {
+            base: node.map_mmio()?,
+            reg_shift: node.prop_u32("reg-shift").unwrap_or(0),
+            wide_io: node.prop_u32("reg-io-width") == Some(4),
+            irq: node.interrupts.first().copied(),
+            irq_enabled: AtomicBool::new(false),
+            buffers: SpinMutex::new(Buffers {
+                tx: ArrayDeque::new(),
+                rx: ArrayDeque::new(),
+            }),
+        })
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        let addr = self.base + (reg << self.reg_shift);
+        unsafe {
+            if self.wide_io {
+                ptr::read_volatile(addr as *const u32) as u8
+            } else {
+                ptr::read_volatile(addr as *const u8)
+            }
+        }
+    }
+
+    fn write_reg(&self, reg: usize, value: u8) {
+        let addr = self.base + (reg << self.reg_shift);
+        unsafe {
+            if self.wide_io {
+                ptr::write_volatile(addr as *mut u32, value as u32);
+            } else {
+                ptr::write_volatile(addr as *mut u8, value);
+            }
+        }
+    }
+
+    fn configure(&self, clock_frequency: Option<u32>) {
+        self.write_reg(IER, 0);
+        if let Some(clock) = clock_frequency {
+            let divisor = clock / (16 * BAUD_RATE);
+            if divisor != 0 {
+                self.write_reg(LCR, LCR_DLAB);
+                self.write_reg(DLL, divisor as u8);
+                self.write_reg(DLM, (divisor >> 8) as u8);
+            }
+        }
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_AND_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    pub fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+
+    /// Moves bytes between the FIFOs and the ring buffers.
+    fn service(&self, buffers: &mut Buffers) {
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            // Drop input nobody reads.
+            let _ = buffers.rx.push_back(byte);
+        }
+        if self.read_reg(LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match buffers.tx.pop_front() {
+                    Some(byte) => self.write_reg(THR, byte),
+                    None => break,
+                }
+            }
+        }
+        let ier = if buffers.tx.is_empty() {
+            IER_RX_AVAILABLE
+        } else {
+            IER_RX_AVAILABLE | IER_TX_EMPTY
+        };
+        self.write_reg(IER, ier);
+    }
+
+    /// Queues `bytes` for output. Waits while the ring buffer is full.
+    pub fn write(&self, bytes: &[u8]) {
+        without_interrupts_early(|| {
+            let mut buffers = self.buffers.lock();
+            for &byte in bytes {
+                while buffers.tx.is_full() {
+                    self.service(&mut buffers);
+                }
+                let _ = buffers.tx.push_back(byte);
+            }
+            self.service(&mut buffers);
+            if !self.irq_enabled.load(Ordering::SeqCst) {
+                while !buffers.tx.is_empty() {
+                    self.service(&mut buffers);
+                }
+            }
+        })
+    }
+
+    /// Waits until all queued output is in the transmit FIFO.
+    pub fn flush(&self) {
+        without_interrupts_early(|| {
+            let mut buffers = self.buffers.lock();
+            while !buffers.tx.is_empty() {
+                self.service(&mut buffers);
+            }
+        })
+    }
+
+    /// Returns the next received byte, if any.
+    pub fn read_byte(&self) -> Option<u8> {
+        without_interrupts_early(|| {
+            let mut buffers = self.buffers.lock();
+            self.service(&mut buffers);
+            buffers.rx.pop_front()
+        })
+    }
+
+    /// Releases the buffer lock, which might be held by a stopped or panicking hart.
+    ///
+    /// # Safety
+    ///
+    /// Only for the panic path, when no other hart is using the UART anymore.
+    pub unsafe fn force_unlock(&self) {
+        self.buffers.force_unlock();
+    }
+
+    /// Services the UART without its interrupt. Called periodically.
+    pub fn poll(&self) {
+        without_interrupts_early(|| self.handle_irq(0))
+    }
+}
+
+impl IrqHandler for Uart {
+    fn handle_irq(&self, _: u32) {
+        // Another hart may be holding the lock, in which case it services the UART for us.
+        if let Some(mut buffers) = self.buffers.try_lock() {
+            self.service(&mut buffers);
+        }
+    }
+}
+
+/// Finds the UART in the device tree and makes it the console.
+pub fn init() {
+    let dt = dtb::device_tree();
+    // Prefer the UART named by `/chosen/stdout-path`, which might carry options after a `:`.
+    let stdout = dt
+        .chosen()
+        .stdout_path
+        .map(|x| x.split(':').next().unwrap_or(x));
+    let node = dt
+        .find_compatible(COMPATIBLE)
+        .find(|x| Some(x.path.as_str()) == stdout)
+        .or_else(|| dt.find_compatible(COMPATIBLE).next());
+    let node = match node {
+        Some(x) => x,
+        None => {
+            warn!(
+                "No {} in the device tree. Using the SBI console.",
+                COMPATIBLE
+            );
+            return;
+        }
+    };
+    let uart = match Uart::from_node(node) {
+        Some(x) => x,
+        None => {
+            warn!("Bad {} node {}.", COMPATIBLE, node.path);
+            return;
+        }
+    };
+    uart.configure(node.prop_u32("clock-frequency"));
+    let uart = UART.call_once(|| uart);
+    info!(
+        "Initialized. {} at {:#x}, IRQ {:?}.",
+        node.path,
+        uart.base,
+        uart.irq()
+    );
+}
+
+/// Attaches the UART to its interrupt, after which output is no longer written synchronously.
+///
+/// Must be called after `plic::init`.
+pub fn init_irq() -> KernelResult<()> {
+    let uart = uart().ok_or(KernelError::NotSupported)?;
+    let irq = uart.irq().ok_or(KernelError::NotSupported)?;
+    register_irq_handler(irq, uart)?;
+    uart.irq_enabled.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns the UART, once initialized.
+pub fn uart() -> Option<&'static Uart> {
+    UART.r#try()
+}
+
+/// Services the UART if its interrupt is not routed to us yet.
+pub fn poll() {
+    if let Some(uart) = uart() {
+        if !uart.irq_enabled.load(Ordering::SeqCst) {
+            uart.poll();
+        }
+    }
+}
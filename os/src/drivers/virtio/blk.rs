@@ -0,0 +1,272 @@
+//! virtio-blk driver.
+//!
+//! Each request is a chain of three buffers: a header, the data, and a status byte the device
+//! writes when done. The interrupt handler completes requests as the device returns them.
+
+use super::{Buffer, Transport, VirtQueue};
+use crate::drivers::block::{BlockDevice, BlockOp, BlockRequest};
+use crate::error::*;
+use crate::interrupt::{register_irq_handler, IrqHandler};
+use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::process::ThreadToken;
+use crate::scheduler::HardwareThread;
+use crate::sync::{without_interrupts, Completion};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use spin::Mutex as SpinMutex;
+
+const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 64;
+
+const F_RO: u64 = 1 << 5;
+
+const CONFIG_CAPACITY: usize = 0;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    capacity: u64,
+    read_only: bool,
+
+    /// Only locked with interrupts disabled.
+    inner: SpinMutex<Inner>,
+}
+
+struct Inner {
+    queue: VirtQueue,
+
+    /// Requests by head descriptor.
+    inflight: Vec<Option<*const RequestMemory>>,
+}
+
+unsafe impl Send for Inner {}
+
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Memory the device reads the header from and writes the status to. Stays put while the
+/// request is in flight.
+#[repr(C)]
+struct RequestMemory {
+    header: Header,
+    status: u8,
+    completion: Completion,
+}
+
+pub struct Request<'a> {
+    memory: Box<RequestMemory>,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+fn phys<T: ?Sized>(x: &T) -> PhysicalAddress {
+    VirtualAddress::from(x)
+        .to_phys()
+        .expect("virtio-blk: buffer outside of the linear mapping")
+}
+
+impl VirtioBlk {
+    fn handle_used(&self) {
+        let mut inner = self.inner.lock();
+        while let Some((head, _)) = inner.queue.pop_used() {
+            let memory = match inner.inflight[head as usize].take() {
+                Some(x) => unsafe { &*x },
+                None => {
+                    warn!("{}: Unknown request {} returned.", self.name, head);
+                    continue;
+                }
+            };
+            let status = unsafe { core::ptr::read_volatile(&memory.status) };
+            if let Err(e) = memory.completion.complete(status as usize) {
+                warn!(
+                    "{}: Cannot wake waiter of request {}: {:?}",
+                    self.name, head, e
+                );
+            }
+        }
+    }
+}
+
+impl IrqHandler for VirtioBlk {
+    fn handle_irq(&self, _: u32) {
+        self.transport.ack_interrupt();
+        self.handle_used();
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn submit<'a>(
+        &'a self,
+        op: BlockOp<'a>,
+        block: u64,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<Box<dyn BlockRequest + 'a>> {
+        let (kind, data) = match op {
+            BlockOp::Read(buf) => (
+                T_IN,
+                Buffer {
+                    pa: phys(buf),
+                    len: buf.len() as u32,
+                    device_writable: true,
+                },
+            ),
+            BlockOp::Write(buf) => {
+                if self.read_only {
+                    return Err(KernelError::NotSupported);
+                }
+                (
+                    T_OUT,
+                    Buffer {
+                        pa: phys(buf),
+                        len: buf.len() as u32,
+                        device_writable: false,
+                    },
+                )
+            }
+        };
+        let len = data.len as usize;
+        let end = block.checked_add((len / SECTOR_SIZE) as u64);
+        if len == 0 || len % SECTOR_SIZE != 0 || end.map(|x| x > self.capacity).unwrap_or(true) {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        let memory = Box::new(RequestMemory {
+            header: Header {
+                kind,
+                reserved: 0,
+                sector: block,
+            },
+            status: 0xff,
+            completion: Completion::new(),
+        });
+        let buffers = [
+            Buffer {
+                pa: phys(&memory.header),
+                len: size_of::<Header>() as u32,
+                device_writable: false,
+            },
+            data,
+            Buffer {
+                pa: phys(&memory.status),
+                len: 1,
+                device_writable: true,
+            },
+        ];
+        loop {
+            let pushed = without_interrupts(ht, || {
+                let mut inner = self.inner.lock();
+                let head = inner.queue.push(&buffers)?;
+                inner.inflight[head as usize] = Some(&*memory as *const RequestMemory);
+                self.transport.notify(inner.queue.index());
+                Some(head)
+            });
+            if pushed.is_some() {
+                break;
+            }
+            // The queue is full. Wait for requests to finish.
+            ht.do_yield(token);
+        }
+        Ok(Box::new(Request {
+            memory,
+            _buffer: PhantomData,
+        }))
+    }
+}
+
+impl<'a> BlockRequest for Request<'a> {
+    fn is_done(&self) -> bool {
+        self.memory.completion.is_done()
+    }
+
+    fn wait(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) -> KernelResult<()> {
+        match self.memory.completion.wait(ht, token) as u8 {
+            S_OK => Ok(()),
+            S_IOERR => Err(KernelError::IoError),
+            S_UNSUPP => Err(KernelError::NotSupported),
+            _ => Err(KernelError::IoError),
+        }
+    }
+}
+
+impl<'a> Drop for Request<'a> {
+    fn drop(&mut self) {
+        // The device may still use the memory and the buffer.
+        if !self.memory.completion.is_done() {
+            unsafe {
+                self.memory.completion.wait(
+                    HardwareThread::this_hart(),
+                    ThreadToken::assume_thread_context(),
+                );
+            }
+        }
+    }
+}
+
+/// Sets up a virtio-blk device and attaches it to its interrupt.
+pub fn probe(
+    transport: Transport,
+    name: String,
+    token: &ThreadToken,
+) -> KernelResult<&'static VirtioBlk> {
+    let irq = transport.irq().ok_or(KernelError::NotSupported)?;
+    let features = transport.negotiate(F_RO)?;
+    let queue = match transport.setup_queue(0, QUEUE_SIZE, token) {
+        Ok(x) => x,
+        Err(e) => {
+            transport.fail();
+            return Err(e);
+        }
+    };
+    let device = VirtioBlk {
+        name,
+        capacity: transport.config_u64(CONFIG_CAPACITY),
+        read_only: features & F_RO != 0,
+        inner: SpinMutex::new(Inner {
+            inflight: vec![None; QUEUE_SIZE as usize],
+            queue,
+        }),
+        transport,
+    };
+    // Devices live until shutdown.
+    let device: &'static VirtioBlk = Box::leak(Box::new(device));
+    register_irq_handler(irq, device)?;
+    device.transport.driver_ok();
+    info!(
+        "{}: {} sectors{}.",
+        device.name,
+        device.capacity,
+        if device.read_only { ", read-only" } else { "" }
+    );
+    Ok(device)
+}
@@ -0,0 +1,277 @@
+//! VirtIO over MMIO.
+//!
+//! Supports both the legacy (version 1) interface, which QEMU uses by default, and the modern
+//! (version 2) one. Devices are found through `virtio,mmio` nodes in the device tree.
+
+pub mod blk;
+pub mod net;
+mod queue;
+
+pub use queue::{Buffer, VirtQueue};
+
+use crate::drivers::block::{self, BlockDevice};
+use crate::drivers::net::NetDevice;
+use crate::dtb::{self, Node};
+use crate::error::*;
+use crate::memory::PhysicalAddress;
+use crate::process::ThreadToken;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr;
+
+const COMPATIBLE: &str = "virtio,mmio";
+const MAGIC: u32 = 0x74726976; // "virt"
+
+pub const DEVICE_ID_NET: u32 = 1;
+pub const DEVICE_ID_BLK: u32 = 2;
+
+/// Required by modern devices.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03c;
+const REG_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080;
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0a0;
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+const PAGE_SIZE: u32 = 4096;
+
+/// Registers of a VirtIO MMIO device.
+pub struct Transport {
+    /// Virtual address of the registers.
+    base: usize,
+    version: u32,
+    device_id: u32,
+    irq: Option<u32>,
+}
+
+impl Transport {
+    fn from_node(node: &Node) -> Option<Transport> {
+        let &(pa, _) = node.reg.first()?;
+        let transport = Transport {
+            base: PhysicalAddress(pa as usize).to_virt()?.0,
+            version: 0,
+            device_id: 0,
+            irq: node.interrupts.first().copied(),
+        };
+        if transport.read(REG_MAGIC) != MAGIC {
+            return None;
+        }
+        Some(Transport {
+            version: transport.read(REG_VERSION),
+            device_id: transport.read(REG_DEVICE_ID),
+            ..transport
+        })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// Device type. 0 means there is no device in this slot.
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    pub fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile((self.base + REG_CONFIG + offset) as *const u8) }
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.read(REG_CONFIG + offset)
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+
+    /// Resets the device and negotiates features. Returns the accepted subset of `supported`.
+    pub fn negotiate(&self, mut supported: u64) -> KernelResult<u64> {
+        self.write(REG_STATUS, 0);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        if self.is_legacy() {
+            // Legacy devices only have the first 32 feature bits.
+            supported &= 0xffff_ffff;
+        } else {
+            supported |= F_VERSION_1;
+        }
+        let mut device = 0;
+        for sel in 0..2 {
+            self.write(REG_DEVICE_FEATURES_SEL, sel);
+            device |= (self.read(REG_DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let features = device & supported;
+        for sel in 0..2 {
+            self.write(REG_DRIVER_FEATURES_SEL, sel);
+            self.write(REG_DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+
+        if self.is_legacy() {
+            self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE);
+        } else {
+            self.write(
+                REG_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+            );
+            if self.read(REG_STATUS) & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(KernelError::NotSupported);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Sets up virtqueue `index` with at most `max_size` entries.
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        token: &ThreadToken,
+    ) -> KernelResult<VirtQueue> {
+        self.write(REG_QUEUE_SEL, index as u32);
+        let size = (self.read(REG_QUEUE_NUM_MAX) as u16).min(max_size);
+        if size == 0 {
+            return Err(KernelError::NotSupported);
+        }
+        let queue = VirtQueue::new(index, size, token)?;
+        self.write(REG_QUEUE_NUM, size as u32);
+        if self.is_legacy() {
+            self.write(REG_QUEUE_ALIGN, PAGE_SIZE);
+            self.write(
+                REG_QUEUE_PFN,
+                (queue.desc_pa().0 / PAGE_SIZE as usize) as u32,
+            );
+        } else {
+            self.write_u64(REG_QUEUE_DESC, queue.desc_pa().0 as u64);
+            self.write_u64(REG_QUEUE_DRIVER, queue.avail_pa().0 as u64);
+            self.write_u64(REG_QUEUE_DEVICE, queue.used_pa().0 as u64);
+            self.write(REG_QUEUE_READY, 1);
+        }
+        Ok(queue)
+    }
+
+    /// Lets the device start processing queues.
+    pub fn driver_ok(&self) {
+        let status = self.read(REG_STATUS);
+        self.write(REG_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        let status = self.read(REG_STATUS);
+        self.write(REG_STATUS, status | STATUS_FAILED);
+    }
+
+    /// Tells the device that there are new buffers in queue `index`.
+    pub fn notify(&self, index: u16) {
+        self.write(REG_QUEUE_NOTIFY, index as u32);
+    }
+
+    /// Acknowledges an interrupt. Returns the interrupt status bits.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        self.write(REG_INTERRUPT_ACK, status);
+        status
+    }
+}
+
+fn nodes() -> impl Iterator<Item = &'static Node> {
+    dtb::device_tree().find_compatible(COMPATIBLE)
+}
+
+/// Keeps the registers of all VirtIO slots mapped.
+///
+/// Must be called on the boot hart before `memory::remap_kernel`.
+pub fn init() {
+    for node in nodes() {
+        node.map_mmio();
+    }
+}
+
+/// Names block devices like Linux: `vda` to `vdz`, then `vdaa`, `vdab` and so on.
+fn block_device_name(mut index: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push((b'a' + (index % 26) as u8) as char);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    format!("vd{}", letters.iter().rev().collect::<String>())
+}
+
+/// Probes all VirtIO devices and starts drivers for the supported ones.
+///
+/// Must be called from a thread after `memory::remap_kernel`.
+pub fn probe(token: &ThreadToken) {
+    let mut block_devices: Vec<&'static dyn BlockDevice> = Vec::new();
+    let mut net_devices: Vec<&'static dyn NetDevice> = Vec::new();
+    for node in nodes() {
+        let transport = match Transport::from_node(node) {
+            Some(x) if x.device_id() != 0 => x,
+            _ => continue,
+        };
+        match transport.device_id() {
+            DEVICE_ID_BLK => {
+                let name = block_device_name(block_devices.len());
+                match blk::probe(transport, name, token) {
+                    Ok(x) => block_devices.push(x),
+                    Err(e) => warn!("{}: virtio-blk failed: {:?}", node.path, e),
+                }
+            }
+            DEVICE_ID_NET => {
+                let name = format!("eth{}", net_devices.len());
+                match net::probe(transport, name, token) {
+                    Ok(x) => net_devices.push(x),
+                    Err(e) => warn!("{}: virtio-net failed: {:?}", node.path, e),
+                }
+            }
+            x => debug!("{}: Ignoring device type {}.", node.path, x),
+        }
+    }
+    block::set_devices(block_devices);
+    crate::drivers::net::set_devices(net_devices);
+}
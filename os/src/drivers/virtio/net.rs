@@ -0,0 +1,216 @@
+//! virtio-net driver.
+//!
+//! The network stack polls the device, so it is asked not to interrupt. Every receive and
+//! transmit buffer is a page from the boot `PagePool` holding the virtio-net header followed by
+//! the frame.
+
+use super::{Buffer, Transport, VirtQueue, F_VERSION_1};
+use crate::drivers::net::{MacAddr, NetDevice, MAX_FRAME};
+use crate::error::*;
+use crate::memory::{boot_page_pool, VirtualAddress, VirtualPageNumber};
+use crate::process::ThreadToken;
+use crate::sync::YieldMutex;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+const F_MAC: u64 = 1 << 5;
+const F_ANY_LAYOUT: u64 = 1 << 27;
+
+const CONFIG_MAC: usize = 0;
+
+/// Used when the device does not provide an address. Locally administered.
+const DEFAULT_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+pub struct VirtioNet {
+    name: String,
+    transport: Transport,
+    mac: MacAddr,
+
+    /// Length of the header before each frame. The legacy header has no `num_buffers`.
+    header_len: usize,
+
+    inner: YieldMutex<Inner>,
+}
+
+struct Inner {
+    rx: VirtQueue,
+    tx: VirtQueue,
+
+    /// Buffers in the queues, by head descriptor.
+    rx_buffers: Vec<Option<VirtualAddress>>,
+    tx_buffers: Vec<Option<VirtualAddress>>,
+
+    /// Transmit buffers not in the queue.
+    tx_free: Vec<VirtualAddress>,
+}
+
+impl Inner {
+    fn push_rx(&mut self, page: VirtualAddress) {
+        let head = self
+            .rx
+            .push(&[Buffer {
+                pa: page.to_phys().unwrap(),
+                len: 4096,
+                device_writable: true,
+            }])
+            .expect("VirtioNet: more receive buffers than descriptors");
+        self.rx_buffers[head as usize] = Some(page);
+    }
+
+    fn reclaim_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            if let Some(page) = self.tx_buffers[head as usize].take() {
+                self.tx_free.push(page);
+            }
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn transmit(&self, frame: &[u8], token: &ThreadToken) -> KernelResult<()> {
+        if frame.len() > MAX_FRAME {
+            return Err(KernelError::InvalidArgument);
+        }
+        let mut inner = self.inner.lock(token);
+        inner.reclaim_tx();
+        let page = inner.tx_free.pop().ok_or(KernelError::Busy)?;
+        unsafe {
+            let data = page.as_mut_ptr::<u8>();
+            ptr::write_bytes(data, 0, self.header_len);
+            ptr::copy_nonoverlapping(frame.as_ptr(), data.add(self.header_len), frame.len());
+        }
+        let head = inner
+            .tx
+            .push(&[Buffer {
+                pa: page.to_phys().unwrap(),
+                len: (self.header_len + frame.len()) as u32,
+                device_writable: false,
+            }])
+            .expect("VirtioNet: more transmit buffers than descriptors");
+        inner.tx_buffers[head as usize] = Some(page);
+        self.transport.notify(TX_QUEUE);
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8], token: &ThreadToken) -> Option<usize> {
+        let mut inner = self.inner.lock(token);
+        let (head, len) = inner.rx.pop_used()?;
+        let page = inner.rx_buffers[head as usize]
+            .take()
+            .expect("VirtioNet: unknown receive buffer");
+        let len = (len as usize)
+            .saturating_sub(self.header_len)
+            .min(buf.len())
+            .min(4096 - self.header_len);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                page.as_ptr::<u8>().add(self.header_len),
+                buf.as_mut_ptr(),
+                len,
+            );
+        }
+        inner.push_rx(page);
+        self.transport.notify(RX_QUEUE);
+        Some(len)
+    }
+}
+
+/// Sets up a virtio-net device.
+pub fn probe(
+    transport: Transport,
+    name: String,
+    token: &ThreadToken,
+) -> KernelResult<&'static VirtioNet> {
+    let features = transport.negotiate(F_MAC | F_ANY_LAYOUT)?;
+    let mut queues = Vec::with_capacity(2);
+    for &index in &[RX_QUEUE, TX_QUEUE] {
+        match transport.setup_queue(index, QUEUE_SIZE, token) {
+            Ok(mut x) => {
+                x.suppress_interrupts();
+                queues.push(x);
+            }
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        }
+    }
+    let tx = queues.pop().unwrap();
+    let rx = queues.pop().unwrap();
+
+    let mac = if features & F_MAC != 0 {
+        let mut x = [0; 6];
+        for (i, b) in x.iter_mut().enumerate() {
+            *b = transport.config_u8(CONFIG_MAC + i);
+        }
+        MacAddr(x)
+    } else {
+        DEFAULT_MAC
+    };
+    let (rx_size, tx_size) = (rx.size() as usize, tx.size() as usize);
+    let pages = match allocate_pages(rx_size + tx_size, token) {
+        Ok(x) => x,
+        Err(e) => {
+            transport.fail();
+            return Err(e);
+        }
+    };
+    let mut inner = Inner {
+        rx,
+        tx,
+        rx_buffers: vec![None; rx_size],
+        tx_buffers: vec![None; tx_size],
+        tx_free: Vec::with_capacity(tx_size),
+    };
+    let mut pages = pages.into_iter().map(|x| x.start_address());
+    for page in pages.by_ref().take(rx_size) {
+        inner.push_rx(page);
+    }
+    inner.tx_free.extend(pages);
+
+    let device = VirtioNet {
+        name,
+        mac,
+        header_len: if features & F_VERSION_1 != 0 { 12 } else { 10 },
+        inner: YieldMutex::new(inner),
+        transport,
+    };
+    // Devices live until shutdown.
+    let device: &'static VirtioNet = Box::leak(Box::new(device));
+    device.transport.driver_ok();
+    device.transport.notify(RX_QUEUE);
+    info!("{}: MAC {:?}.", device.name, device.mac);
+    Ok(device)
+}
+
+/// Allocates `n` buffer pages. Frees them all again if one allocation fails.
+fn allocate_pages(n: usize, token: &ThreadToken) -> KernelResult<Vec<VirtualPageNumber>> {
+    let mut pages = Vec::with_capacity(n);
+    for _ in 0..n {
+        match boot_page_pool().allocate(token) {
+            Ok(x) => pages.push(x),
+            Err(e) => {
+                for page in pages {
+                    boot_page_pool().free(page, token);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(pages)
+}
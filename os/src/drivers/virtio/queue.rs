@@ -0,0 +1,208 @@
+//! Split virtqueues.
+//!
+//! The descriptor table, available ring and used ring are laid out as required by legacy
+//! devices: in physically contiguous pages from the boot `PagePool`, with the used ring on its
+//! own page.
+
+use crate::error::*;
+use crate::memory::{boot_page_pool, PhysicalAddress, VirtualAddress};
+use crate::process::ThreadToken;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer to hand to the device.
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer {
+    pub pa: PhysicalAddress,
+    pub len: u32,
+
+    /// Whether the device writes to this buffer, rather than reading from it.
+    pub device_writable: bool,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+
+    /// Start of the queue memory.
+    base: VirtualAddress,
+
+    /// Offset of the used ring from `base`.
+    used_offset: usize,
+
+    /// Free descriptors. Has room for all of them, so it never allocates after `new`.
+    free: Vec<u16>,
+
+    /// Index into the used ring of the next entry to look at.
+    last_used: u16,
+}
+
+unsafe impl Send for VirtQueue {}
+
+fn align_up(x: usize, align: usize) -> usize {
+    (x + align - 1) & !(align - 1)
+}
+
+impl VirtQueue {
+    pub fn new(index: u16, size: u16, token: &ThreadToken) -> KernelResult<VirtQueue> {
+        let n = size as usize;
+        let avail_size = 2 * (3 + n);
+        let used_offset = align_up(size_of::<Descriptor>() * n + avail_size, 4096);
+        let used_size = 2 * 3 + size_of::<UsedElem>() * n;
+        let num_pages = align_up(used_offset + used_size, 4096) / 4096;
+
+        // Pages from the pool are zeroed.
+        let base = boot_page_pool()
+            .allocate_contiguous(num_pages, token)?
+            .start_address();
+        Ok(VirtQueue {
+            index,
+            size,
+            base,
+            used_offset,
+            free: (0..size).rev().collect(),
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Asks the device not to interrupt when it returns buffers, for queues that are polled.
+    pub fn suppress_interrupts(&mut self) {
+        unsafe { ptr::write_volatile(self.avail(0), AVAIL_F_NO_INTERRUPT) };
+    }
+
+    pub fn desc_pa(&self) -> PhysicalAddress {
+        self.pa(0)
+    }
+
+    pub fn avail_pa(&self) -> PhysicalAddress {
+        self.pa(size_of::<Descriptor>() * self.size as usize)
+    }
+
+    pub fn used_pa(&self) -> PhysicalAddress {
+        self.pa(self.used_offset)
+    }
+
+    fn pa(&self, offset: usize) -> PhysicalAddress {
+        VirtualAddress(self.base.0 + offset)
+            .to_phys()
+            .expect("VirtQueue: bad queue address")
+    }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        (self.base.0 + size_of::<Descriptor>() * i as usize) as *mut Descriptor
+    }
+
+    /// Pointer to the `i`-th `u16` of the available ring: flags, idx, then the ring.
+    fn avail(&self, i: usize) -> *mut u16 {
+        (self.avail_va() + 2 * i) as *mut u16
+    }
+
+    fn avail_va(&self) -> usize {
+        self.base.0 + size_of::<Descriptor>() * self.size as usize
+    }
+
+    fn used_idx(&self) -> *const u16 {
+        (self.base.0 + self.used_offset + 2) as *const u16
+    }
+
+    fn used_elem(&self, i: u16) -> *const UsedElem {
+        (self.base.0 + self.used_offset + 4 + size_of::<UsedElem>() * i as usize) as *const UsedElem
+    }
+
+    /// Makes a chain of `buffers` available to the device. Returns the head descriptor, or
+    /// `None` if there are not enough free descriptors.
+    ///
+    /// Does not notify the device.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let mut next = 0;
+        for (i, buf) in buffers.iter().enumerate().rev() {
+            let d = self.free.pop().unwrap();
+            let mut flags = 0;
+            if buf.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            if i + 1 != buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            unsafe {
+                ptr::write_volatile(
+                    self.desc(d),
+                    Descriptor {
+                        addr: buf.pa.0 as u64,
+                        len: buf.len,
+                        flags,
+                        next,
+                    },
+                );
+            }
+            next = d;
+        }
+        let head = next;
+        unsafe {
+            let idx = ptr::read_volatile(self.avail(1));
+            ptr::write_volatile(self.avail(2 + (idx % self.size) as usize), head);
+            // The descriptors and ring entry must be visible before the index.
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.avail(1), idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        Some(head)
+    }
+
+    /// Returns the head descriptor of the next chain the device is done with, and the number of
+    /// bytes it wrote. The chain is freed.
+    ///
+    /// Does not allocate.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(self.used_idx()) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = unsafe { ptr::read_volatile(self.used_elem(self.last_used % self.size)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = elem.id as u16;
+        let mut d = head;
+        loop {
+            let desc = unsafe { ptr::read_volatile(self.desc(d)) };
+            self.free.push(d);
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            d = desc.next;
+        }
+        Some((head, elem.len))
+    }
+}
@@ -0,0 +1,348 @@
+//! Low-level parser for the flattened device tree (FDT) blob.
+//!
+//! Works directly on the blob in memory and never allocates, so it can be used before the
+//! allocator is initialized.
+
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_MIN_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug)]
+pub enum FdtError {
+    BadMagic(u32),
+    BadVersion(u32),
+    Truncated,
+    BadToken(u32),
+    BadString,
+    Unbalanced,
+}
+
+/// A validated FDT blob.
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: &'a [u8],
+    boot_cpuid: u32,
+}
+
+/// A token in the structure block.
+#[derive(Copy, Clone, Debug)]
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Property { name: &'a str, value: &'a [u8] },
+}
+
+/// Iterator over the tokens of a validated structure block.
+pub struct Tokens<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+/// Iterator over the memory reservation block.
+pub struct Reservations<'a> {
+    rest: &'a [u8],
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let raw = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let high = read_u32(bytes, offset)? as u64;
+    let low = read_u32(bytes, offset.checked_add(4)?)? as u64;
+    Some((high << 32) | low)
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+/// Reads a NUL-terminated string starting at `offset`.
+fn read_cstr(bytes: &[u8], offset: usize) -> Option<&str> {
+    let rest = bytes.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    str::from_utf8(&rest[..len]).ok()
+}
+
+/// Reads a big-endian value made up of `cells` 32-bit cells from the front of `value`.
+///
+/// Returns the value and the remaining bytes.
+pub fn read_cells(value: &[u8], cells: u32) -> Option<(u64, &[u8])> {
+    let len = cells as usize * 4;
+    if cells > 2 || value.len() < len {
+        return None;
+    }
+    let mut result: u64 = 0;
+    for i in 0..cells as usize {
+        result = (result << 32) | read_u32(value, i * 4)? as u64;
+    }
+    Some((result, &value[len..]))
+}
+
+/// Reads a single big-endian `u32` property value.
+pub fn prop_u32(value: &[u8]) -> Option<u32> {
+    if value.len() != 4 {
+        return None;
+    }
+    read_u32(value, 0)
+}
+
+/// Reads a `u32` or `u64` property value, depending on its length.
+pub fn prop_u64(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => read_u32(value, 0).map(|x| x as u64),
+        8 => read_u64(value, 0),
+        _ => None,
+    }
+}
+
+/// Reads a string property value.
+pub fn prop_str(value: &[u8]) -> Option<&str> {
+    let value = match value.split_last() {
+        Some((0, rest)) => rest,
+        _ => return None,
+    };
+    str::from_utf8(value).ok()
+}
+
+/// Iterates over a string list property value, e.g. `compatible`.
+pub fn prop_str_list<'a>(value: &'a [u8]) -> impl Iterator<Item = &'a str> + 'a {
+    value
+        .split(|&b| b == 0)
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| str::from_utf8(x).ok())
+}
+
+impl<'a> Fdt<'a> {
+    /// Parses an FDT blob at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to readable memory that is at least as large as the size declared in the
+    /// header, and the memory must live for `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Fdt<'a>, FdtError> {
+        let header = core::slice::from_raw_parts(ptr, FDT_HEADER_SIZE);
+        let magic = read_u32(header, 0).unwrap();
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = read_u32(header, 4).unwrap() as usize;
+        if total_size < FDT_HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        Self::from_bytes(core::slice::from_raw_parts(ptr, total_size))
+    }
+
+    /// Parses and validates an FDT blob.
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Fdt<'a>, FdtError> {
+        let header = |offset| read_u32(blob, offset).ok_or(FdtError::Truncated);
+
+        let magic = header(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = header(4)? as usize;
+        let off_dt_struct = header(8)? as usize;
+        let off_dt_strings = header(12)? as usize;
+        let off_mem_rsvmap = header(16)? as usize;
+        let last_comp_version = header(24)?;
+        let boot_cpuid = header(28)?;
+        let size_dt_strings = header(32)? as usize;
+        let size_dt_struct = header(36)? as usize;
+
+        if last_comp_version > FDT_MIN_COMPATIBLE_VERSION {
+            return Err(FdtError::BadVersion(last_comp_version));
+        }
+
+        let blob = blob.get(..total_size).ok_or(FdtError::Truncated)?;
+        let section = |start: usize, size: usize| {
+            start
+                .checked_add(size)
+                .and_then(|end| blob.get(start..end))
+                .ok_or(FdtError::Truncated)
+        };
+        let fdt = Fdt {
+            blob,
+            structure: section(off_dt_struct, size_dt_struct)?,
+            strings: section(off_dt_strings, size_dt_strings)?,
+            mem_rsvmap: blob.get(off_mem_rsvmap..).ok_or(FdtError::Truncated)?,
+            boot_cpuid,
+        };
+        fdt.validate()?;
+        Ok(fdt)
+    }
+
+    /// Walks the whole structure block once, so that `Tokens` never sees malformed data.
+    fn validate(&self) -> Result<(), FdtError> {
+        let mut offset = 0;
+        let mut depth: usize = 0;
+        loop {
+            let token = read_u32(self.structure, offset).ok_or(FdtError::Truncated)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_cstr(self.structure, offset).ok_or(FdtError::BadString)?;
+                    offset = align4(offset + name.len() + 1);
+                    depth += 1;
+                }
+                FDT_END_NODE => {
+                    depth = depth.checked_sub(1).ok_or(FdtError::Unbalanced)?;
+                }
+                FDT_PROP => {
+                    // Properties belong to the innermost node.
+                    if depth == 0 {
+                        return Err(FdtError::Unbalanced);
+                    }
+                    let len = read_u32(self.structure, offset).ok_or(FdtError::Truncated)? as usize;
+                    let name_offset =
+                        read_u32(self.structure, offset + 4).ok_or(FdtError::Truncated)? as usize;
+                    read_cstr(self.strings, name_offset).ok_or(FdtError::BadString)?;
+                    offset += 8;
+                    let end = offset
+                        .checked_add(len)
+                        .filter(|&end| end <= self.structure.len())
+                        .ok_or(FdtError::Truncated)?;
+                    offset = align4(end);
+                }
+                FDT_NOP => {}
+                FDT_END => {
+                    if depth != 0 {
+                        return Err(FdtError::Unbalanced);
+                    }
+                    return Ok(());
+                }
+                x => return Err(FdtError::BadToken(x)),
+            }
+        }
+    }
+
+    /// Returns the size of the whole blob, in bytes.
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Returns the physical ID of the boot CPU, as recorded in the header.
+    pub fn boot_cpuid(&self) -> u32 {
+        self.boot_cpuid
+    }
+
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens {
+            fdt: *self,
+            offset: 0,
+        }
+    }
+
+    /// Returns the entries of the memory reservation block as `(address, size)` pairs.
+    pub fn reservations(&self) -> Reservations<'a> {
+        Reservations {
+            rest: self.mem_rsvmap,
+        }
+    }
+
+    /// Calls `f` with `(address, size)` of each region described by the `/memory` nodes.
+    pub fn for_each_memory_region<F: FnMut(u64, u64)>(&self, mut f: F) {
+        // Defaults from the device tree specification.
+        let mut address_cells = 2;
+        let mut size_cells = 1;
+        let mut depth = 0;
+        let mut in_memory_node = false;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    in_memory_node =
+                        depth == 2 && (name == "memory" || name.starts_with("memory@"));
+                }
+                Token::EndNode => {
+                    depth -= 1;
+                    in_memory_node = false;
+                }
+                Token::Property { name, value } => {
+                    if depth == 1 {
+                        match name {
+                            "#address-cells" => {
+                                address_cells = prop_u32(value).unwrap_or(address_cells)
+                            }
+                            "#size-cells" => size_cells = prop_u32(value).unwrap_or(size_cells),
+                            _ => {}
+                        }
+                    } else if in_memory_node && name == "reg" {
+                        let mut rest = value;
+                        while let Some((address, next)) = read_cells(rest, address_cells) {
+                            let (size, next) = match read_cells(next, size_cells) {
+                                Some(x) => x,
+                                None => break,
+                            };
+                            f(address, size);
+                            rest = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let structure = self.fdt.structure;
+        loop {
+            let token = read_u32(structure, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_cstr(structure, self.offset).unwrap();
+                    self.offset = align4(self.offset + name.len() + 1);
+                    break Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => break Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = read_u32(structure, self.offset).unwrap() as usize;
+                    let name_offset = read_u32(structure, self.offset + 4).unwrap() as usize;
+                    let name = read_cstr(self.fdt.strings, name_offset).unwrap();
+                    let start = self.offset + 8;
+                    let value = &structure[start..start + len];
+                    self.offset = align4(start + len);
+                    break Some(Token::Property { name, value });
+                }
+                FDT_NOP => {}
+                _ => {
+                    // FDT_END. Validated in `Fdt::from_bytes`.
+                    self.offset = structure.len();
+                    break None;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Reservations<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let address = read_u64(self.rest, 0)?;
+        let size = read_u64(self.rest, 8)?;
+        if address == 0 && size == 0 {
+            self.rest = &[];
+            return None;
+        }
+        self.rest = &self.rest[16..];
+        Some((address, size))
+    }
+}
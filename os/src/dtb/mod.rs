@@ -0,0 +1,493 @@
+//! Device tree support.
+//!
+//! The blob passed by the firmware is parsed in two stages:
+//!
+//! 1. `early_init` validates the blob in place and makes the memory regions available without
+//!    allocating, so that the kernel layout can be set up before the allocator.
+//! 2. `init` walks the blob once more and builds a `DeviceTree` summary on the heap.
+//!
+//! The blob itself is never copied. Its physical range is reserved from the allocator, so all
+//! strings and property values borrowed from it stay valid for `'static`.
+
+mod fdt;
+
+pub use fdt::{prop_str, prop_str_list, prop_u32, prop_u64, read_cells, Fdt, FdtError, Token};
+
+use crate::memory::{self, PhysicalAddress};
+use crate::sync::Once;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+static mut EARLY_FDT: Option<Fdt<'static>> = None;
+static mut DTB_PA: PhysicalAddress = PhysicalAddress(0);
+static DEVICE_TREE: Once<DeviceTree> = Once::new();
+
+/// Maximum node depth we keep cell sizes for.
+const MAX_DEPTH: usize = 16;
+
+pub struct DeviceTree {
+    fdt: Fdt<'static>,
+    nodes: Vec<Node>,
+    memory: Vec<MemoryRegion>,
+    reserved: Vec<MemoryRegion>,
+    harts: Vec<HartInfo>,
+    timebase_frequency: u64,
+    chosen: Chosen,
+}
+
+/// A node in the device tree.
+pub struct Node {
+    pub path: String,
+    pub name: &'static str,
+    pub parent: Option<usize>,
+    pub phandle: Option<u32>,
+    pub compatible: Vec<&'static str>,
+
+    /// `(address, size)` pairs from `reg`, decoded with the parent's cell sizes.
+    pub reg: Vec<(u64, u64)>,
+
+    /// Raw cells of `interrupts`.
+    pub interrupts: Vec<u32>,
+
+    pub interrupt_parent: Option<u32>,
+    pub enabled: bool,
+    props: Vec<(&'static str, &'static [u8])>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryRegion {
+    pub start: PhysicalAddress,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct HartInfo {
+    /// The hart ID, from the `reg` property of the cpu node.
+    pub id: u32,
+
+    /// Index of the cpu node in `DeviceTree::nodes`.
+    pub node: usize,
+
+    pub enabled: bool,
+    pub isa: Option<&'static str>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Chosen {
+    pub bootargs: Option<&'static str>,
+    pub stdout_path: Option<&'static str>,
+
+    /// Physical range of the initrd, from `linux,initrd-start` and `linux,initrd-end`.
+    pub initrd: Option<Range<PhysicalAddress>>,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> PhysicalAddress {
+        PhysicalAddress(self.start.0 + self.size)
+    }
+
+    pub fn range(&self) -> Range<PhysicalAddress> {
+        self.start..self.end()
+    }
+}
+
+impl Node {
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        self.props.iter().find(|x| x.0 == name).map(|x| x.1)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop(name).and_then(prop_u32)
+    }
+
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        self.prop(name).and_then(prop_u64)
+    }
+
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        self.prop(name).and_then(prop_str)
+    }
+
+    pub fn props(&self) -> impl Iterator<Item = (&'static str, &'static [u8])> + '_ {
+        self.props.iter().copied()
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible.iter().any(|x| *x == compatible)
+    }
+
+    /// Keeps the registers at the first `reg` entry mapped, and returns their virtual address.
+    ///
+    /// Must be called on the boot hart before `memory::remap_kernel`.
+    pub fn map_mmio(&self) -> Option<usize> {
+        let &(pa, size) = self.reg.first()?;
+        let start = PhysicalAddress(pa as usize);
+        let end = PhysicalAddress(pa.checked_add(size)? as usize);
+        let base = start.to_virt()?.0;
+        unsafe {
+            memory::register_mmio(start..end);
+        }
+        Some(base)
+    }
+}
+
+impl DeviceTree {
+    fn build(fdt: Fdt<'static>) -> DeviceTree {
+        let mut dt = DeviceTree {
+            fdt,
+            nodes: vec![],
+            memory: vec![],
+            reserved: vec![],
+            harts: vec![],
+            timebase_frequency: 0,
+            chosen: Chosen::default(),
+        };
+
+        // `(#address-cells, #size-cells)` declared by each open node, for its children.
+        let mut cells = [(2u32, 1u32); MAX_DEPTH];
+        let mut stack: Vec<usize> = vec![];
+        // Depth within a subtree that is too deep to keep, or 0.
+        let mut skipped = 0usize;
+
+        for token in fdt.tokens() {
+            if skipped != 0 {
+                match token {
+                    Token::BeginNode(_) => skipped += 1,
+                    Token::EndNode => skipped -= 1,
+                    Token::Property { .. } => {}
+                }
+                continue;
+            }
+            match token {
+                Token::BeginNode(name) => {
+                    let depth = stack.len();
+                    if depth == MAX_DEPTH {
+                        let parent = &dt.nodes[*stack.last().unwrap()];
+                        warn!("Skipping node {} in {}: too deep.", name, parent.path);
+                        skipped = 1;
+                        continue;
+                    }
+                    let parent = stack.last().copied();
+                    let path = match parent {
+                        None => String::from("/"),
+                        Some(p) if dt.nodes[p].parent.is_none() => format!("/{}", name),
+                        Some(p) => format!("{}/{}", dt.nodes[p].path, name),
+                    };
+                    cells[depth] = (2, 1);
+                    stack.push(dt.nodes.len());
+                    dt.nodes.push(Node {
+                        path,
+                        name,
+                        parent,
+                        phandle: None,
+                        compatible: vec![],
+                        reg: vec![],
+                        interrupts: vec![],
+                        interrupt_parent: None,
+                        enabled: true,
+                        props: vec![],
+                    });
+                }
+                Token::EndNode => {
+                    stack.pop().expect("DeviceTree::build: unbalanced tree");
+                }
+                Token::Property { name, value } => {
+                    let depth = stack.len() - 1;
+                    let node = &mut dt.nodes[*stack.last().unwrap()];
+                    node.props.push((name, value));
+                    match name {
+                        "#address-cells" => cells[depth].0 = prop_u32(value).unwrap_or(2),
+                        "#size-cells" => cells[depth].1 = prop_u32(value).unwrap_or(1),
+                        "phandle" | "linux,phandle" => node.phandle = prop_u32(value),
+                        "compatible" => node.compatible = prop_str_list(value).collect(),
+                        "interrupt-parent" => node.interrupt_parent = prop_u32(value),
+                        "status" => {
+                            node.enabled = match prop_str(value) {
+                                Some("okay") | Some("ok") => true,
+                                _ => false,
+                            }
+                        }
+                        "interrupts" => {
+                            node.interrupts = value.chunks_exact(4).filter_map(prop_u32).collect();
+                        }
+                        "reg" if depth > 0 => {
+                            let (address_cells, size_cells) = cells[depth - 1];
+                            let mut rest = value;
+                            while let Some((address, next)) = read_cells(rest, address_cells) {
+                                let (size, next) = match read_cells(next, size_cells) {
+                                    Some(x) => x,
+                                    None => break,
+                                };
+                                node.reg.push((address, size));
+                                rest = next;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        dt.collect_memory();
+        dt.collect_cpus();
+        dt.collect_chosen();
+        dt
+    }
+
+    fn collect_memory(&mut self) {
+        let mut memory = vec![];
+        self.fdt.for_each_memory_region(|start, size| {
+            memory.push(MemoryRegion {
+                start: PhysicalAddress(start as usize),
+                size: size as usize,
+            })
+        });
+        self.memory = memory;
+
+        self.reserved = self
+            .fdt
+            .reservations()
+            .map(|(start, size)| MemoryRegion {
+                start: PhysicalAddress(start as usize),
+                size: size as usize,
+            })
+            .collect();
+        if let Some(resv) = self.find_by_path("/reserved-memory") {
+            let regions: Vec<MemoryRegion> = self
+                .children(resv)
+                .flat_map(|node| node.reg.iter())
+                .map(|&(start, size)| MemoryRegion {
+                    start: PhysicalAddress(start as usize),
+                    size: size as usize,
+                })
+                .collect();
+            self.reserved.extend(regions);
+        }
+    }
+
+    fn collect_cpus(&mut self) {
+        let cpus = match self.find_by_path("/cpus") {
+            Some(x) => x,
+            None => return,
+        };
+        let cpus_timebase = self.nodes[cpus].prop_u64("timebase-frequency");
+
+        let mut harts = vec![];
+        let mut timebase = cpus_timebase;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.parent != Some(cpus) || node.prop_str("device_type") != Some("cpu") {
+                continue;
+            }
+            let id = match node.reg.first() {
+                Some(&(id, _)) => id as u32,
+                None => continue,
+            };
+            if timebase.is_none() {
+                timebase = node.prop_u64("timebase-frequency");
+            }
+            harts.push(HartInfo {
+                id,
+                node: i,
+                enabled: node.enabled,
+                isa: node.prop_str("riscv,isa"),
+            });
+        }
+        harts.sort_by_key(|x| x.id);
+        self.harts = harts;
+        self.timebase_frequency = timebase.unwrap_or(0);
+    }
+
+    fn collect_chosen(&mut self) {
+        let chosen = match self.find_by_path("/chosen") {
+            Some(x) => &self.nodes[x],
+            None => return,
+        };
+        let chosen = Chosen {
+            bootargs: chosen.prop_str("bootargs"),
+            stdout_path: chosen.prop_str("stdout-path"),
+            initrd: initrd_range(
+                chosen.prop("linux,initrd-start"),
+                chosen.prop("linux,initrd-end"),
+            ),
+        };
+        self.chosen = chosen;
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn find_by_path(&self, path: &str) -> Option<usize> {
+        self.nodes.iter().position(|x| x.path == path)
+    }
+
+    pub fn find_by_phandle(&self, phandle: u32) -> Option<&Node> {
+        self.nodes.iter().find(|x| x.phandle == Some(phandle))
+    }
+
+    pub fn children(&self, parent: usize) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().filter(move |x| x.parent == Some(parent))
+    }
+
+    /// Returns enabled nodes that are compatible with `compatible`.
+    pub fn find_compatible<'a>(&'a self, compatible: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.nodes
+            .iter()
+            .filter(move |x| x.enabled && x.is_compatible(compatible))
+    }
+
+    pub fn memory(&self) -> &[MemoryRegion] {
+        &self.memory
+    }
+
+    pub fn reserved_memory(&self) -> &[MemoryRegion] {
+        &self.reserved
+    }
+
+    pub fn harts(&self) -> &[HartInfo] {
+        &self.harts
+    }
+
+    /// Frequency of the `time` CSR, in Hz.
+    pub fn timebase_frequency(&self) -> u64 {
+        self.timebase_frequency
+    }
+
+    pub fn chosen(&self) -> &Chosen {
+        &self.chosen
+    }
+
+    pub fn boot_cpuid(&self) -> u32 {
+        self.fdt.boot_cpuid()
+    }
+
+    pub fn print(&self) {
+        println!("Device tree:");
+        for region in &self.memory {
+            println!("- Memory: {:#x}-{:#x}", region.start.0, region.end().0);
+        }
+        for region in &self.reserved {
+            println!("- Reserved: {:#x}-{:#x}", region.start.0, region.end().0);
+        }
+        for hart in &self.harts {
+            println!(
+                "- Hart {}: {} ({})",
+                hart.id,
+                if hart.enabled { "enabled" } else { "disabled" },
+                hart.isa.unwrap_or("unknown isa")
+            );
+        }
+        println!("- Timebase frequency: {} Hz", self.timebase_frequency);
+        if let Some(bootargs) = self.chosen.bootargs {
+            println!("- Boot arguments: {}", bootargs);
+        }
+        if let Some(stdout_path) = self.chosen.stdout_path {
+            println!("- Stdout path: {}", stdout_path);
+        }
+        if let Some(ref initrd) = self.chosen.initrd {
+            println!("- Initrd: {:#x}-{:#x}", initrd.start.0, initrd.end.0);
+        }
+        for node in &self.nodes {
+            if node.enabled && !node.compatible.is_empty() && !node.reg.is_empty() {
+                println!(
+                    "- Device {} ({}) at {:#x}",
+                    node.path, node.compatible[0], node.reg[0].0
+                );
+            }
+        }
+    }
+}
+
+/// Validates the device tree blob at `dtb_pa`.
+///
+/// # Safety
+///
+/// Can only be called once on the boot hart, before any other function in this module.
+pub unsafe fn early_init(dtb_pa: PhysicalAddress) {
+    let va = dtb_pa
+        .to_virt()
+        .expect("dtb::early_init: bad device tree address");
+    let fdt = Fdt::from_ptr(va.as_ptr()).expect("dtb::early_init: invalid device tree");
+    DTB_PA = dtb_pa;
+    EARLY_FDT = Some(fdt);
+}
+
+fn early_fdt() -> &'static Fdt<'static> {
+    unsafe { EARLY_FDT.as_ref().expect("dtb: early_init() not called") }
+}
+
+/// Returns the physical range of the device tree blob.
+pub fn blob_range() -> Range<PhysicalAddress> {
+    let start = unsafe { DTB_PA };
+    start..PhysicalAddress(start.0 + early_fdt().total_size())
+}
+
+/// Returns the memory region that contains `pa`, without allocating.
+pub fn early_memory_region_containing(pa: PhysicalAddress) -> Option<MemoryRegion> {
+    let mut result = None;
+    early_fdt().for_each_memory_region(|start, size| {
+        let region = MemoryRegion {
+            start: PhysicalAddress(start as usize),
+            size: size as usize,
+        };
+        if region.range().contains(&pa) {
+            result = Some(region);
+        }
+    });
+    result
+}
+
+fn initrd_range(start: Option<&[u8]>, end: Option<&[u8]>) -> Option<Range<PhysicalAddress>> {
+    let start = start.and_then(prop_u64)? as usize;
+    let end = end.and_then(prop_u64)? as usize;
+    if start < end {
+        Some(PhysicalAddress(start)..PhysicalAddress(end))
+    } else {
+        None
+    }
+}
+
+/// Returns the physical range of the initrd declared in `/chosen`, without allocating.
+pub fn early_initrd_range() -> Option<Range<PhysicalAddress>> {
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut start = None;
+    let mut end = None;
+    for token in early_fdt().tokens() {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                in_chosen = depth == 2 && name == "chosen";
+            }
+            Token::EndNode => {
+                depth -= 1;
+                in_chosen = false;
+            }
+            Token::Property { name, value } if in_chosen => match name {
+                "linux,initrd-start" => start = Some(value),
+                "linux,initrd-end" => end = Some(value),
+                _ => {}
+            },
+            Token::Property { .. } => {}
+        }
+    }
+    initrd_range(start, end)
+}
+
+/// Builds the `DeviceTree` summary. Must be called after the allocator is initialized.
+pub fn init() {
+    let dt = DEVICE_TREE.call_once(|| DeviceTree::build(*early_fdt()));
+    dt.print();
+    info!("Initialized.");
+}
+
+pub fn device_tree() -> &'static DeviceTree {
+    DEVICE_TREE
+        .r#try()
+        .expect("device_tree: device tree not initialized")
+}
@@ -4,6 +4,10 @@
 .globl _start
 _start:
 
+# Keep the hart ID in `tp` until the hart has a `HardwareThread`, which is then pointed to by `gp`.
+mv tp, a0
+li gp, 0
+
 # Prepare the page table.
 la t0, boot_page_table
 srli t0, t0, 12 # PhysAddr -> PPN
@@ -12,13 +16,15 @@ or t0, t0, t1
 csrw satp, t0
 sfence.vma
 
-# Calculate per-core boot stack offset.
-slli t1, a0, 16 # mul 65536
+# Elect the boot hart. Everyone else waits for a stack in `park`.
+la t0, boot_hart_claimed
+li t1, 1
+amoswap.w t1, t1, (t0)
+bnez t1, park
 
 # Load boot stack.
 li t0, 0xffffffff00000000
 la sp, boot_stack_top
-sub sp, sp, t1
 or sp, sp, t0
 
 # Calculate virtual address of rust_main.
@@ -29,19 +35,45 @@ or t0, t0, t1
 # Jump to rust_main.
 jr t0
 
+# Application processors spin here without a stack until the boot hart hands one out through
+# `AP_BOOT_SLOT`, which holds the ID of the hart to start and the top of its stack.
+park:
+la t0, AP_BOOT_SLOT
+1:
+ld t1, 0(t0)
+bne t1, a0, 1b
+fence r, r
+ld sp, 8(t0)
+
+# Calculate virtual address of rust_ap_main.
+li t0, 0xffffffff00000000
+la t1, rust_ap_main
+or t0, t0, t1
+
+# Jump to rust_ap_main.
+jr t0
+
 # Boot page table.
 .section .data.boot_page_table
 boot_page_table:
 .quad 0
 .quad 0
 .quad (0x80000 << 10) | 0xf # Identity mapping.
-.zero 507 * 8
-.quad (0x80000 << 10) | 0xf # Kernel mapping.
+.zero 505 * 8
+.quad (0x00000 << 10) | 0x7 # Device registers in the first 1 GB. Not executable.
 .quad 0
+.quad (0x80000 << 10) | 0xf # Kernel mapping.
+.quad (0xc0000 << 10) | 0xf # Kernel mapping, second 1 GB.
+
+# Not in .bss, which is not guaranteed to be zeroed this early.
+.section .data
+.align 2
+boot_hart_claimed:
+.word 0
 
 .section .bss.stack
 .globl boot_stack
 boot_stack:
-.space 65536 * 16 # 64 KBytes, 16 cores max
+.space 65536 # 64 KBytes, boot hart only
 .globl boot_stack_top
-boot_stack_top:
\ No newline at end of file
+boot_stack_top:
@@ -2,6 +2,11 @@
 #[repr(i32)]
 pub enum KernelError {
     OutOfMemory = -1,
+    InvalidArgument = -2,
+    Busy = -3,
+    NotSupported = -4,
+    Timeout = -5,
+    IoError = -6,
 }
 
 pub type KernelResult<T> = Result<T, KernelError>;
@@ -1,31 +1,127 @@
 use crate::allocator;
+use crate::cmdline::{Param, ParamValue};
+use crate::console;
+use crate::drivers;
+use crate::log;
 use crate::memory::{boot_page_pool, remap_kernel};
+use crate::monitor;
+use crate::net;
+use crate::power::{self, ExitStatus};
 use crate::process::{spawn, KernelTask, LockedProcess, Thread, ThreadToken};
-use crate::sbi;
 use crate::scheduler::{HardwareThread, HardwareThreadId, SimplePolicy};
 use crate::smp;
 use crate::sync::lock;
 use crate::tests;
 use alloc::boxed::Box;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
-pub fn start() -> ! {
-    let ht = HardwareThread::new(
-        HardwareThreadId(0),
+/// Task run by the init thread after boot.
+pub static INIT: Param<InitTask> = Param::new(
+    "init",
+    InitTask::Tests,
+    "task of the init thread: tests, idle or monitor",
+);
+
+pub static TEST: Param<TestSelection> = Param::new(
+    "test",
+    TestSelection("all"),
+    "comma-separated tests to run, all or none",
+);
+
+pub static SHUTDOWN: Param<bool> =
+    Param::new("shutdown", true, "shut down when the init task is done");
+
+/// Index of the running test in `TESTS`, plus one. 0 when no test is running.
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(0);
+
+/// In-kernel tests, by name.
+const TESTS: &[(&str, fn(&HardwareThread, &ThreadToken))] = &[
+    ("mutex", tests::test_mutex),
+    ("hotplug", tests::test_hart_hotplug),
+    ("ipi", tests::test_ipi_call),
+    ("blk", tests::test_block),
+    ("net", tests::test_net),
+    ("timer", tests::test_timer),
+    ("paging", tests::test_demand_paging),
+    ("fork", tests::test_fork),
+    ("unmap", tests::test_unmap),
+    ("kmap", tests::test_kernel_mapping),
+];
+
+#[derive(Copy, Clone, Debug)]
+pub enum InitTask {
+    /// Run the tests selected with `test=`.
+    Tests,
+
+    /// Do nothing.
+    Idle,
+
+    /// Run the debug monitor until it exits.
+    Monitor,
+}
+
+/// `all`, `none`, or a comma-separated list of names from `TESTS`.
+#[derive(Copy, Clone, Debug)]
+pub struct TestSelection(&'static str);
+
+impl ParamValue for InitTask {
+    fn parse(s: &'static str) -> Option<InitTask> {
+        match s {
+            "tests" => Some(InitTask::Tests),
+            "idle" => Some(InitTask::Idle),
+            "monitor" => Some(InitTask::Monitor),
+            _ => None,
+        }
+    }
+}
+
+impl ParamValue for TestSelection {
+    fn parse(s: &'static str) -> Option<TestSelection> {
+        match s {
+            "all" | "none" => Some(TestSelection(s)),
+            _ if s
+                .split(',')
+                .all(|name| TESTS.iter().any(|&(x, _)| x == name)) =>
+            {
+                Some(TestSelection(s))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl TestSelection {
+    pub fn contains(&self, name: &str) -> bool {
+        match self.0 {
+            "all" => true,
+            "none" => false,
+            x => x.split(',').any(|x| x == name),
+        }
+    }
+}
+
+pub fn start(hart_id: u32) -> ! {
+    let ht = smp::register_hardware_thread(HardwareThread::new(
+        HardwareThreadId(hart_id),
         Box::new(SimplePolicy::new()),
         make_init_thread(),
-    );
+    ));
     unsafe { ht.start() }
 }
 
-pub unsafe fn ap_start(hart_id: u32) -> ! {
-    println!("AP start: {}", hart_id);
-    let ht = HardwareThread::new(
+/// Creates the `HardwareThread` for an AP. Called on the hart that starts the AP.
+pub fn make_ap_hardware_thread(hart_id: u32) -> Pin<Box<HardwareThread>> {
+    HardwareThread::new(
         HardwareThreadId(hart_id),
         Box::new(SimplePolicy::new()),
         make_apd_thread(),
-    );
+    )
+}
+
+pub unsafe fn ap_start(hart_id: u32) -> ! {
+    debug!("AP start: {}", hart_id);
+    let ht = smp::hardware_thread(hart_id).expect("ap_start: no HardwareThread");
     unsafe { ht.start() }
 }
 
@@ -34,7 +130,7 @@ fn make_init_thread() -> Box<Thread> {
 }
 
 /// Application Processor Daemon thread.
-fn make_apd_thread() -> Box<Thread> {
+pub fn make_apd_thread() -> Box<Thread> {
     Thread::new(apd_thread, 0, 0).unwrap()
 }
 
@@ -44,16 +140,10 @@ fn init_thread(ht: &HardwareThread, token: &ThreadToken, _: usize, _: usize) ->
         remap_kernel(token);
     }
 
-    println!("Init thread started. Starting application processors.");
-    for i in 1..smp::num_harts() {
+    info!("Init thread started. Starting application processors.");
+    unsafe {
         // Now locking is not yet enabled. So serially boot APs.
-        print!("Hart {}... ", i);
-        unsafe {
-            smp::boot_ap(i);
-            while !smp::ap_boot_done() {}
-            smp::clear_ap_boot_done();
-        }
-        println!("ok.");
+        smp::boot_aps();
     }
 
     unsafe {
@@ -61,12 +151,25 @@ fn init_thread(ht: &HardwareThread, token: &ThreadToken, _: usize, _: usize) ->
         allocator::enable_locking();
     }
 
-    println!("Allocator locks enabled.");
+    info!("Allocator locks enabled.");
+    log::start_daemon(ht, token);
+    drivers::start(token);
+    net::start(ht, token);
 
-    run_tests(ht, token);
+    monitor::start_hotkey_watcher(ht, token);
 
-    sbi::shutdown();
-    //ht.exit_thread(token);
+    match INIT.get() {
+        InitTask::Tests => run_tests(ht, token),
+        InitTask::Idle => {}
+        InitTask::Monitor => monitor::run(ht, token),
+    }
+
+    if SHUTDOWN.get() {
+        log::flush();
+        console::flush();
+        power::exit(ExitStatus::Success);
+    }
+    ht.exit_thread(token);
 }
 
 fn apd_thread(ht: &HardwareThread, token: &ThreadToken, _: usize, _: usize) -> ! {
@@ -74,19 +177,39 @@ fn apd_thread(ht: &HardwareThread, token: &ThreadToken, _: usize, _: usize) -> !
         smp::set_ap_boot_done();
     }
     loop {
+        if smp::offline_requested(ht.id().0) {
+            let e = ht.go_offline(token);
+            warn!("Hart {} cannot go offline: {:?}", ht.id().0, e);
+            smp::offline_failed(ht.id().0);
+        }
         for _ in 0..1000000 {
             unsafe {
                 llvm_asm!("" :::: "volatile");
             }
         }
-        println!("apd thread tick");
+        trace!("apd thread tick");
+    }
+}
+
+/// Name of the running test, if any.
+pub fn current_test() -> Option<&'static str> {
+    match CURRENT_TEST.load(Ordering::SeqCst) {
+        0 => None,
+        x => Some(TESTS[x - 1].0),
     }
 }
 
 fn run_tests(ht: &HardwareThread, token: &ThreadToken) {
     println!("running tests");
 
-    tests::test_mutex(ht, token);
+    let selection = TEST.get();
+    for (i, &(name, test)) in TESTS.iter().enumerate() {
+        if selection.contains(name) {
+            CURRENT_TEST.store(i + 1, Ordering::SeqCst);
+            test(ht, token);
+        }
+    }
+    CURRENT_TEST.store(0, Ordering::SeqCst);
 
     println!("all tests passed");
 }
@@ -0,0 +1,115 @@
+//! Parser for cpio archives in the "new ASCII" (newc) format, as produced by
+//! `cpio -o -H newc`.
+//!
+//! Works directly on the archive in memory and never allocates.
+
+use core::str;
+
+const HEADER_SIZE: usize = 110;
+const MAGIC_NEWC: &[u8] = b"070701";
+const MAGIC_NEWC_CRC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+#[derive(Debug)]
+pub enum CpioError {
+    BadMagic,
+    BadHeader,
+    BadName,
+    Truncated,
+}
+
+/// An entry of the archive.
+#[derive(Copy, Clone, Debug)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// Iterator over the entries of an archive. Stops at the trailer or at the first error.
+pub struct Entries<'a> {
+    rest: &'a [u8],
+    done: bool,
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+/// Reads the `index`-th 8-digit hexadecimal field of a header.
+fn header_field(header: &[u8], index: usize) -> Result<u32, CpioError> {
+    let start = 6 + index * 8;
+    let digits = str::from_utf8(&header[start..start + 8]).map_err(|_| CpioError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioError::BadHeader)
+}
+
+impl<'a> Entry<'a> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(archive: &'a [u8]) -> Entries<'a> {
+        Entries {
+            rest: archive,
+            done: false,
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let archive = self.rest;
+        let header = archive.get(..HEADER_SIZE).ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC_NEWC && &header[..6] != MAGIC_NEWC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+        let mode = header_field(header, 1)?;
+        let file_size = header_field(header, 6)? as usize;
+        let name_size = header_field(header, 11)? as usize;
+
+        // `name_size` includes the terminating NUL.
+        let name = archive
+            .get(HEADER_SIZE..HEADER_SIZE + name_size)
+            .ok_or(CpioError::Truncated)?;
+        let name = match name.split_last() {
+            Some((0, x)) => str::from_utf8(x).map_err(|_| CpioError::BadName)?,
+            _ => return Err(CpioError::BadName),
+        };
+
+        let data_start = align4(HEADER_SIZE + name_size);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.rest = archive.get(align4(data_start + file_size)..).unwrap_or(&[]);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
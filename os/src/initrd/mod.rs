@@ -0,0 +1,109 @@
+//! Initial RAM disk.
+//!
+//! The firmware (or QEMU's `-initrd`) loads a cpio newc archive into RAM and declares its range in
+//! `/chosen`. The range is reserved from the allocator at boot and never freed, so file contents
+//! are borrowed from it for `'static`.
+
+mod cpio;
+
+pub use cpio::{CpioError, Entries, Entry};
+
+use crate::dtb;
+use crate::sync::Once;
+use alloc::vec::Vec;
+use core::slice;
+
+static INITRD: Once<Initrd> = Once::new();
+
+/// The files of the initrd.
+pub struct Initrd {
+    files: Vec<File>,
+    size: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct File {
+    /// Path without a leading `/` or `./`.
+    pub path: &'static str,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+impl File {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+}
+
+impl Initrd {
+    fn parse(archive: &'static [u8]) -> Result<Initrd, CpioError> {
+        let mut files = vec![];
+        for entry in Entries::new(archive) {
+            let entry = entry?;
+            let path = entry.name.trim_start_matches("./").trim_start_matches('/');
+            if path.is_empty() || path == "." {
+                continue;
+            }
+            files.push(File {
+                path,
+                mode: entry.mode,
+                data: entry.data,
+            });
+        }
+        Ok(Initrd {
+            files,
+            size: archive.len(),
+        })
+    }
+
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+
+    /// Looks up a file by path. A leading `/` is ignored.
+    pub fn find(&self, path: &str) -> Option<&File> {
+        let path = path.trim_start_matches('/');
+        self.files.iter().find(|x| x.path == path)
+    }
+
+    /// Size of the archive, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Parses the initrd, if any.
+///
+/// Must be called after `dtb::init`. The range must have been reserved with
+/// `allocator::reserve` before the first allocation.
+pub fn init() {
+    let range = match dtb::device_tree().chosen().initrd.clone() {
+        Some(x) => x,
+        None => {
+            info!("Not present.");
+            return;
+        }
+    };
+    let start = range
+        .start
+        .to_virt()
+        .expect("initrd::init: bad initrd address");
+    let archive: &'static [u8] =
+        unsafe { slice::from_raw_parts(start.as_ptr(), range.end.0 - range.start.0) };
+    match Initrd::parse(archive) {
+        Ok(initrd) => {
+            let initrd = INITRD.call_once(|| initrd);
+            info!(
+                "Initialized. {} file(s), {} bytes.",
+                initrd.files().len(),
+                initrd.size()
+            );
+        }
+        Err(e) => error!("Bad archive: {:?}", e),
+    }
+}
+
+/// Returns the initrd, or `None` if there is none.
+pub fn initrd() -> Option<&'static Initrd> {
+    INITRD.r#try()
+}
@@ -1,6 +1,9 @@
 use super::context::Context;
+use super::irq;
+use crate::memory::{AccessType, PageFault, VirtualAddress};
 use crate::process::{RawThreadState, ThreadToken};
 use crate::scheduler::{EntryReason, HardwareThread};
+use crate::smp;
 use core::{mem, ptr};
 use riscv::register::{
     scause::{Exception, Interrupt, Scause, Trap},
@@ -21,7 +24,7 @@ pub fn init() {
     unsafe {
         stvec::write(__interrupt as usize, stvec::TrapMode::Direct);
     }
-    println!("interrupt/handler: Initialized.");
+    info!("Initialized.");
 }
 
 pub unsafe fn ap_init() {
@@ -37,7 +40,7 @@ pub unsafe fn ap_init() {
 pub extern "C" fn handle_interrupt(context: &mut Context, scause: Scause, stval: usize) -> ! {
     let token = InterruptToken(());
     let ts: &mut RawThreadState = if context.was_user() {
-        println!("user mode interrupt entry");
+        trace!("user mode interrupt entry");
         unsafe { mem::transmute(context) }
     } else {
         let ts: &mut RawThreadState;
@@ -52,7 +55,18 @@ pub extern "C" fn handle_interrupt(context: &mut Context, scause: Scause, stval:
     };
     match scause.cause() {
         Trap::Exception(Exception::Breakpoint) => on_breakpoint(ts, &token),
+        Trap::Exception(Exception::LoadPageFault) => {
+            on_page_fault(ts, &token, stval, AccessType::Read)
+        }
+        Trap::Exception(Exception::StorePageFault) => {
+            on_page_fault(ts, &token, stval, AccessType::Write)
+        }
+        Trap::Exception(Exception::InstructionPageFault) => {
+            on_page_fault(ts, &token, stval, AccessType::Execute)
+        }
         Trap::Interrupt(Interrupt::SupervisorTimer) => on_stimer(ts, &token),
+        Trap::Interrupt(Interrupt::SupervisorSoft) => on_ssoft(ts, &token),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => on_sext(ts, &token),
         _ => panic!(
             "Unknown interrupt: {:?}\n{:#x?}\nstval: {:?}",
             scause.cause(),
@@ -68,6 +82,33 @@ fn on_breakpoint(ts: &mut RawThreadState, token: &InterruptToken) -> ! {
     unsafe { ts.enter_kernel(token, EntryReason::Breakpoint(bkpt_addr)) }
 }
 
+fn on_page_fault(
+    ts: &mut RawThreadState,
+    token: &InterruptToken,
+    address: usize,
+    access: AccessType,
+) -> ! {
+    let fault = PageFault {
+        address: VirtualAddress(address),
+        access,
+        user: ts.was_user(),
+    };
+    unsafe { ts.enter_kernel(token, EntryReason::PageFault(fault)) }
+}
+
 fn on_stimer(ts: &mut RawThreadState, token: &InterruptToken) -> ! {
     unsafe { ts.enter_kernel(token, EntryReason::Timer) }
 }
+
+fn on_ssoft(ts: &mut RawThreadState, token: &InterruptToken) -> ! {
+    // Clear SSIP before handling the mailbox, so that messages posted meanwhile raise it again.
+    unsafe {
+        llvm_asm!("csrc sip, $0" :: "r"(1usize << 1) :: "volatile");
+        ts.enter_kernel(token, EntryReason::Ipi)
+    }
+}
+
+fn on_sext(ts: &mut RawThreadState, token: &InterruptToken) -> ! {
+    irq::dispatch(smp::current_hart_id());
+    unsafe { ts.enter_kernel(token, EntryReason::External) }
+}
@@ -0,0 +1,53 @@
+//! External interrupts.
+//!
+//! Drivers attach an `IrqHandler` to an interrupt number of the PLIC. On a supervisor external
+//! interrupt, `dispatch` claims pending interrupts of this hart and calls their handlers.
+
+use crate::drivers::plic::{self, MAX_IRQ};
+use crate::error::*;
+use crate::sync::without_interrupts_early;
+use spin::Mutex as SpinMutex;
+
+pub trait IrqHandler: Sync {
+    /// Handles an interrupt. Called in interrupt context, so must neither block nor allocate.
+    fn handle_irq(&self, irq: u32);
+}
+
+/// Handlers by interrupt number. Only locked with interrupts disabled.
+static HANDLERS: SpinMutex<[Option<&'static dyn IrqHandler>; MAX_IRQ as usize + 1]> =
+    SpinMutex::new([None; MAX_IRQ as usize + 1]);
+
+/// Attaches `handler` to `irq` and routes `irq` to all harts.
+pub fn register_irq_handler(irq: u32, handler: &'static dyn IrqHandler) -> KernelResult<()> {
+    let plic = plic::plic().ok_or(KernelError::NotSupported)?;
+    if irq == 0 || irq > plic.num_irqs() {
+        return Err(KernelError::InvalidArgument);
+    }
+    without_interrupts_early(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = &mut handlers[irq as usize];
+        if slot.is_some() {
+            return Err(KernelError::Busy);
+        }
+        *slot = Some(handler);
+        plic.enable(irq);
+        Ok(())
+    })
+}
+
+/// Handles all pending external interrupts of this hart.
+pub fn dispatch(hart_id: u32) {
+    let plic = plic::plic().expect("irq::dispatch: external interrupt without a PLIC");
+    let context = match plic.context(hart_id) {
+        Some(x) => x,
+        None => panic!("irq::dispatch: hart {} has no PLIC context", hart_id),
+    };
+    while let Some(irq) = plic.claim(context) {
+        let handler = HANDLERS.lock()[irq as usize];
+        match handler {
+            Some(handler) => handler.handle_irq(irq),
+            None => warn!("Spurious interrupt {}.", irq),
+        }
+        plic.complete(context, irq);
+    }
+}
@@ -1,15 +1,17 @@
 mod context;
 mod handler;
+mod irq;
 
 pub use context::Context;
 pub use handler::InterruptToken;
+pub use irq::{register_irq_handler, IrqHandler};
 
 pub fn init() {
     handler::init();
 
     // Don't enable supervisor-mode interrupts yet. Do this in the first process.
 
-    println!("interrupt: Initialized.");
+    info!("Initialized.");
 }
 
 pub unsafe fn ap_init() {
@@ -1,4 +1,5 @@
-use crate::memory::VirtualAddress;
+use crate::dtb;
+use crate::memory::{PhysicalAddress, VirtualAddress};
 
 extern "C" {
     static KERNEL_START: Data;
@@ -9,12 +10,50 @@ extern "C" {
     static KERNEL_END: Data;
 }
 
-const RAM_START: usize = 0xffffffff80000000;
-const RAM_END: usize = 0xffffffff88000000;
+static mut RAM_START: usize = 0;
+static mut RAM_END: usize = 0;
 const KERNEL_IDMAP_START: usize = 0xffffffff00000000;
 
+/// End of the physical range mapped by the boot page table.
+const BOOT_MAP_PHYS_END: usize = 0x1_0000_0000;
+
+/// End of the RAM we use. The identity map ends at the top of the address space, so its last
+/// page is left out to keep the virtual end address representable.
+const MAX_RAM_END: usize = BOOT_MAP_PHYS_END - 0x1000;
+
 pub enum Data {}
 
+/// Discovers the RAM region that contains the kernel image from the device tree.
+///
+/// # Safety
+///
+/// Can only be called once on the boot hart, after `dtb::early_init` and before `ram_start` or
+/// `ram_end` is used.
+pub unsafe fn init() {
+    let kernel_pa = kernel_start()
+        .to_phys()
+        .expect("layout::init: bad kernel_start");
+    let region = dtb::early_memory_region_containing(kernel_pa)
+        .expect("layout::init: no memory region contains the kernel");
+    let mut end = region.end().0;
+    if end > MAX_RAM_END {
+        println!(
+            "layout: Only using memory below {:#x} ({:#x} available).",
+            MAX_RAM_END, end
+        );
+        end = MAX_RAM_END;
+    }
+    RAM_START = region
+        .start
+        .to_virt()
+        .expect("layout::init: bad RAM start")
+        .0;
+    RAM_END = PhysicalAddress(end)
+        .to_virt()
+        .expect("layout::init: bad RAM end")
+        .0;
+}
+
 pub fn print() {
     unsafe {
         println!("Kernel image layout:");
@@ -24,8 +63,8 @@ pub fn print() {
         println!("- Data start: {:p}", &DATA_START);
         println!("- BSS start: {:p}", &BSS_START);
         println!("- Kernel end: {:p}", &KERNEL_END);
-        println!("Assuming start of RAM at {:p}", RAM_START as *const u8);
-        println!("Assuming end of RAM at {:p}", RAM_END as *const u8);
+        println!("- RAM start: {:p}", RAM_START as *const u8);
+        println!("- RAM end: {:p}", RAM_END as *const u8);
     }
 }
 
@@ -54,11 +93,11 @@ pub fn kernel_end() -> VirtualAddress {
 }
 
 pub fn ram_start() -> VirtualAddress {
-    VirtualAddress(RAM_START)
+    VirtualAddress(unsafe { RAM_START })
 }
 
 pub fn ram_end() -> VirtualAddress {
-    VirtualAddress(RAM_END)
+    VirtualAddress(unsafe { RAM_END })
 }
 
 pub fn kernel_idmap_start() -> VirtualAddress {
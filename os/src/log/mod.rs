@@ -0,0 +1,305 @@
+//! Leveled kernel logging.
+//!
+//! `error!`, `warn!`, `info!`, `debug!` and `trace!` record a message together with the current
+//! hart, thread and time into a lock-free ring buffer. Records are printed to the console by the
+//! log daemon thread, or right away while it is not running yet. After a panic, `panic_flush`
+//! prints whatever is left.
+//!
+//! Levels are set with the `log=` kernel parameter, e.g. `log=info,smp=debug,memory::tlb=trace`.
+//! The most specific module prefix wins.
+
+#[macro_export]
+macro_rules! log {
+    ($level: expr, $($arg: tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+mod ring;
+
+pub use ring::Record;
+
+use ring::{Read, Ring};
+
+use crate::cmdline::{Param, ParamValue};
+use crate::process::{KernelTask, ThreadToken};
+use crate::scheduler::HardwareThread;
+use crate::smp;
+use crate::sync::Once;
+use crate::timekeeping;
+use alloc::boxed::Box;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Maximum number of per-module filters.
+const MAX_FILTERS: usize = 16;
+
+/// Time between drains by the log daemon.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+const DEFAULT_LEVEL: Level = Level::Info;
+
+pub static LOG: Param<LogSpec> =
+    Param::new("log", LogSpec("info"), "log levels, e.g. info,smp=debug");
+
+static RING: Ring = Ring::new();
+static FILTERS: Once<Filters> = Once::new();
+
+/// Sequence number of the next record to print.
+static PRINTED_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Held while printing records.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the log daemon thread is running.
+static DAEMON_RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Validated value of the `log=` parameter.
+#[derive(Copy, Clone, Debug)]
+pub struct LogSpec(&'static str);
+
+struct Filters {
+    default: Level,
+    modules: [(&'static str, Level); MAX_FILTERS],
+    num_modules: usize,
+}
+
+struct LogDaemon;
+
+impl Level {
+    fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl ParamValue for LogSpec {
+    fn parse(s: &'static str) -> Option<LogSpec> {
+        Filters::parse(s).map(|_| LogSpec(s))
+    }
+}
+
+impl Filters {
+    fn parse(s: &'static str) -> Option<Filters> {
+        let mut filters = Filters {
+            default: DEFAULT_LEVEL,
+            modules: [("", DEFAULT_LEVEL); MAX_FILTERS],
+            num_modules: 0,
+        };
+        for item in s.split(',') {
+            match item.find('=') {
+                Some(i) => {
+                    if filters.num_modules == MAX_FILTERS {
+                        return None;
+                    }
+                    filters.modules[filters.num_modules] =
+                        (&item[..i], Level::parse(&item[i + 1..])?);
+                    filters.num_modules += 1;
+                }
+                None => filters.default = Level::parse(item)?,
+            }
+        }
+        Some(filters)
+    }
+
+    fn level_for(&self, module: &str) -> Level {
+        let mut best: Option<(&str, Level)> = None;
+        for &(prefix, level) in &self.modules[..self.num_modules] {
+            let matches = module.starts_with(prefix)
+                && (module.len() == prefix.len() || module[prefix.len()..].starts_with("::"));
+            if matches && best.map(|x| x.0.len() < prefix.len()).unwrap_or(true) {
+                best = Some((prefix, level));
+            }
+        }
+        best.map(|x| x.1).unwrap_or(self.default)
+    }
+}
+
+/// Strips the crate name from a module path.
+fn short_module(module: &'static str) -> &'static str {
+    match module.find("::") {
+        Some(i) => &module[i + 2..],
+        None => module,
+    }
+}
+
+pub fn enabled(level: Level, module: &'static str) -> bool {
+    let max = match FILTERS.r#try() {
+        Some(x) => x.level_for(short_module(module)),
+        None => DEFAULT_LEVEL,
+    };
+    level <= max
+}
+
+/// Records a message. Used by the logging macros.
+pub fn log(level: Level, module: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let thread = HardwareThread::try_this_hart().map(|x| x.current_thread_id().0);
+    RING.push(
+        level,
+        smp::current_hart_id(),
+        thread,
+        timekeeping::ticks(),
+        short_module(module),
+        args,
+    );
+    if !DAEMON_RUNNING.load(Ordering::SeqCst) {
+        drain();
+    }
+}
+
+/// Applies the `log=` parameter. Must be called after `cmdline::init`.
+pub fn init() {
+    FILTERS.call_once(|| Filters::parse(LOG.get().0).unwrap());
+    info!("Initialized.");
+}
+
+/// Starts the log daemon thread, which prints records from then on.
+pub fn start_daemon(ht: &HardwareThread, token: &ThreadToken) {
+    crate::process::spawn(ht, Box::new(LogDaemon), token).expect("log: cannot spawn daemon");
+}
+
+impl KernelTask for LogDaemon {
+    fn run(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) {
+        DAEMON_RUNNING.store(true, Ordering::SeqCst);
+        loop {
+            drain();
+            ht.sleep_until(timekeeping::now_monotonic() + DRAIN_INTERVAL, token);
+        }
+    }
+}
+
+/// Prints all pending records, unless another hart is already doing so.
+fn drain() {
+    if DRAINING
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        print_pending(false);
+        DRAINING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Prints records until one is not ready. With `skip_incomplete`, records that are still being
+/// written are skipped instead.
+fn print_pending(skip_incomplete: bool) {
+    let mut seq = PRINTED_SEQ.load(Ordering::SeqCst);
+    loop {
+        match RING.read(seq) {
+            Read::Record(record) => {
+                print_record(&record);
+                seq += 1;
+            }
+            Read::Lost(oldest) => {
+                println!("[log: {} record(s) lost]", oldest - seq);
+                seq = oldest;
+            }
+            // Either caught up, or a writer is still filling this slot. The writer might be
+            // interrupted by us, so don't wait for it.
+            Read::NotReady if skip_incomplete && seq < RING.next_seq() => seq += 1,
+            Read::NotReady => break,
+        }
+    }
+    PRINTED_SEQ.store(seq, Ordering::SeqCst);
+}
+
+pub fn print_record(record: &Record) {
+    let time = timekeeping::ticks_to_duration(record.timestamp);
+    let (secs, micros) = (time.as_secs(), time.subsec_micros());
+    let thread = match record.thread {
+        Some(x) => x as i64,
+        None => -1,
+    };
+    println!(
+        "[{:5}.{:06}] {}/{} {:5} {}: {}",
+        secs,
+        micros,
+        record.hart,
+        thread,
+        record.level.name(),
+        record.module,
+        record.message()
+    );
+}
+
+/// Calls `f` for each record still in the ring, oldest first.
+pub fn for_each_record<F: FnMut(&Record)>(mut f: F) {
+    let end = RING.next_seq();
+    let mut seq = RING.oldest_seq();
+    while seq < end {
+        match RING.read(seq) {
+            Read::Record(record) => {
+                f(&record);
+                seq += 1;
+            }
+            Read::Lost(oldest) => seq = oldest.max(seq + 1),
+            Read::NotReady => seq += 1,
+        }
+    }
+}
+
+/// Prints all pending records now, e.g. before shutting down.
+pub fn flush() {
+    drain();
+}
+
+/// Prints the records that the log daemon has not printed yet. Called on panic.
+pub fn panic_flush() {
+    // The draining hart might be the one that panicked, or stopped. Take over.
+    DRAINING.store(true, Ordering::SeqCst);
+    print_pending(true);
+    DRAINING.store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,206 @@
+//! Lock-free ring buffer of log records.
+//!
+//! Writers claim a sequence number with `fetch_add` and fill the slot it maps to. Each slot has a
+//! sequence word that works like a seqlock: odd while being written, and `2 * (seq + 1)` once
+//! record `seq` is complete. Readers copy a slot and check the word again, so they never block
+//! writers. When writers lap a slow reader, the oldest records are lost.
+
+use super::Level;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+
+/// Number of records kept. Must be a power of two.
+pub const RING_SIZE: usize = 256;
+
+/// Longer messages are truncated.
+pub const MAX_MESSAGE: usize = 200;
+
+#[derive(Copy, Clone)]
+pub struct Record {
+    pub seq: u64,
+    pub level: Level,
+    pub hart: u32,
+    pub thread: Option<u64>,
+
+    /// Value of the `time` CSR.
+    pub timestamp: u64,
+
+    pub module: &'static str,
+    len: usize,
+    message: [u8; MAX_MESSAGE],
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Slot {
+    /// Only accessed through `Slot::state`.
+    state: u64,
+    record: Record,
+}
+
+pub struct Ring {
+    slots: UnsafeCell<[Slot; RING_SIZE]>,
+    next_seq: AtomicU64,
+}
+
+unsafe impl Sync for Ring {}
+
+/// Result of reading a record.
+pub enum Read {
+    Record(Record),
+
+    /// The record has not been written yet.
+    NotReady,
+
+    /// The record has been overwritten. The oldest available record has this sequence number.
+    Lost(u64),
+}
+
+/// Formats into a fixed buffer, truncating at `MAX_MESSAGE` bytes.
+struct MessageWriter<'a> {
+    buf: &'a mut [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+impl fmt::Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_MESSAGE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+impl Record {
+    const EMPTY: Record = Record {
+        seq: 0,
+        level: Level::Error,
+        hart: 0,
+        thread: None,
+        timestamp: 0,
+        module: "",
+        len: 0,
+        message: [0; MAX_MESSAGE],
+    };
+
+    /// Returns the message, cut at the last complete character if it was truncated.
+    pub fn message(&self) -> &str {
+        let bytes = &self.message[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(x) => x,
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        state: 0,
+        record: Record::EMPTY,
+    };
+
+    fn state(&self) -> &AtomicU64 {
+        // `AtomicU64` has the same in-memory representation as `u64`.
+        unsafe { &*(&self.state as *const u64 as *const AtomicU64) }
+    }
+}
+
+fn done_state(seq: u64) -> u64 {
+    2 * (seq + 1)
+}
+
+impl Ring {
+    pub const fn new() -> Ring {
+        Ring {
+            slots: UnsafeCell::new([Slot::EMPTY; RING_SIZE]),
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    fn slot(&self, seq: u64) -> *mut Slot {
+        unsafe { &mut (*self.slots.get())[seq as usize % RING_SIZE] }
+    }
+
+    /// Sequence number of the next record to be written.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::SeqCst)
+    }
+
+    /// Appends a record. Never allocates, and only spins if another writer is still filling the
+    /// same slot one lap earlier.
+    pub fn push(
+        &self,
+        level: Level,
+        hart: u32,
+        thread: Option<u64>,
+        timestamp: u64,
+        module: &'static str,
+        args: fmt::Arguments,
+    ) {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let slot = self.slot(seq);
+        let state = unsafe { (*slot).state() };
+
+        // Take the slot over from the previous lap.
+        loop {
+            let current = state.load(Ordering::SeqCst);
+            if current >= done_state(seq) {
+                // Lapped by a whole ring of newer records. Drop this one.
+                return;
+            }
+            if current % 2 == 0
+                && state
+                    .compare_exchange(
+                        current,
+                        done_state(seq) - 1,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    )
+                    .is_ok()
+            {
+                break;
+            }
+            spin_loop_hint();
+        }
+
+        let record = unsafe { &mut (*slot).record };
+        record.seq = seq;
+        record.level = level;
+        record.hart = hart;
+        record.thread = thread;
+        record.timestamp = timestamp;
+        record.module = module;
+        let mut writer = MessageWriter {
+            buf: &mut record.message,
+            len: 0,
+        };
+        let _ = fmt::write(&mut writer, args);
+        record.len = writer.len;
+
+        state.store(done_state(seq), Ordering::SeqCst);
+    }
+
+    /// Reads record `seq`.
+    pub fn read(&self, seq: u64) -> Read {
+        let slot = self.slot(seq);
+        let state = unsafe { (*slot).state() };
+        let before = state.load(Ordering::SeqCst);
+        if before < done_state(seq) {
+            return Read::NotReady;
+        }
+        let record = unsafe { ptr::read_volatile(&(*slot).record) };
+        let after = state.load(Ordering::SeqCst);
+        if before == done_state(seq) && after == before {
+            Read::Record(record)
+        } else {
+            Read::Lost(self.oldest_seq())
+        }
+    }
+
+    /// Sequence number of the oldest record that may still be available.
+    pub fn oldest_seq(&self) -> u64 {
+        self.next_seq().saturating_sub(RING_SIZE as u64)
+    }
+}
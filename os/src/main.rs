@@ -16,19 +16,29 @@ extern crate alloc;
 
 #[macro_use]
 mod console;
+#[macro_use]
+mod log;
 mod allocator;
+mod cmdline;
+mod drivers;
+mod dtb;
 mod error;
 mod init;
+mod initrd;
 mod interrupt;
 mod layout;
 mod memory;
+mod monitor;
+mod net;
 mod panic;
+mod power;
 mod process;
 mod sbi;
 mod scheduler;
 mod smp;
 mod sync;
 mod tests;
+mod timekeeping;
 mod user;
 
 use memory::PhysicalAddress;
@@ -40,25 +50,40 @@ global_asm!(include_str!("entry.asm"));
 #[global_allocator]
 static ALLOC: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
 
+/// Entry of the boot hart.
 #[no_mangle]
 pub unsafe extern "C" fn rust_main(hart_id: u32, dtb_pa: PhysicalAddress) -> ! {
-    let x: u32 = 42;
-    if hart_id == 0 {
-        kernel_boot(dtb_pa);
-    } else {
-        smp::ap_boot(hart_id);
-    }
-}
-
-unsafe fn kernel_boot(dtb_pa: PhysicalAddress) -> ! {
-    println!("Kernel booting on Hart 0. DTB: {:x?}", dtb_pa);
-    smp::wait_for_ap();
-    println!("Number of Harts: {}", smp::num_harts());
+    smp::set_boot_hart(hart_id);
+    println!("Kernel booting on Hart {}. DTB: {:x?}", hart_id, dtb_pa);
+    sbi::init();
+    dtb::early_init(dtb_pa);
+    layout::init();
     layout::print();
+    allocator::reserve(dtb::blob_range());
+    if let Some(range) = dtb::early_initrd_range() {
+        allocator::reserve(range);
+    }
     allocator::init();
+    dtb::init();
+    drivers::uart::init();
+    drivers::rtc::init();
+    drivers::sifive_test::init();
+    timekeeping::init();
+    cmdline::init();
+    log::init();
+    cmdline::print();
+    initrd::init();
+    smp::init();
     memory::init();
     interrupt::init();
+    drivers::init();
     scheduler::init();
 
-    init::start();
+    init::start(hart_id);
+}
+
+/// Entry of application processors.
+#[no_mangle]
+pub unsafe extern "C" fn rust_ap_main(hart_id: u32) -> ! {
+    smp::ap_boot(hart_id);
 }
@@ -1,11 +1,13 @@
-use super::{LockedPagePool, Mapping, PageTableEntryFlags, Segment, SegmentBacking};
+use super::{
+    LockedPagePool, Mapping, PageTableEntryFlags, PhysicalAddress, Segment, SegmentBacking,
+};
 use crate::error::*;
 use crate::layout;
 use crate::process::ThreadToken;
 
 pub unsafe fn remap_kernel(pool: LockedPagePool, token: &ThreadToken) -> KernelResult<Mapping> {
     let mut mapping = Mapping::new_without_kernel_region(pool, token)?;
-    let ksegs: &[Segment] = &[
+    let mut ksegs = vec![
         Segment {
             range: layout::text_start().vpn()..layout::rodata_start().vpn(),
             backing: SegmentBacking::Linear {
@@ -41,7 +43,19 @@ pub unsafe fn remap_kernel(pool: LockedPagePool, token: &ThreadToken) -> KernelR
                 | PageTableEntryFlags::WRITABLE,
         },
     ];
-    for seg in ksegs {
+    for range in super::mmio_regions() {
+        let start = range.start.ppn();
+        let end = PhysicalAddress(range.end.0 + 0xfff).ppn();
+        ksegs.push(Segment {
+            range: start.to_virt().expect("remap_kernel: bad MMIO start")
+                ..end.to_virt().expect("remap_kernel: bad MMIO end"),
+            backing: SegmentBacking::Linear { phys_start: start },
+            flags: PageTableEntryFlags::VALID
+                | PageTableEntryFlags::READABLE
+                | PageTableEntryFlags::WRITABLE,
+        });
+    }
+    for seg in &ksegs {
         mapping.map_segment(seg, token)?;
     }
     mapping.activate_thread(token);
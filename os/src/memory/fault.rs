@@ -0,0 +1,60 @@
+//! Page fault classification.
+
+use super::{PageTableEntryFlags, VirtualAddress};
+
+/// End of the lower half of the Sv39 address space, where user mappings live.
+pub const USER_END: usize = 1 << 38;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PageFault {
+    pub address: VirtualAddress,
+    pub access: AccessType,
+
+    /// Whether the fault was raised in user mode.
+    pub user: bool,
+}
+
+impl AccessType {
+    pub fn to_index(self) -> usize {
+        match self {
+            AccessType::Read => 0,
+            AccessType::Write => 1,
+            AccessType::Execute => 2,
+        }
+    }
+
+    pub fn from_index(index: usize) -> Option<AccessType> {
+        match index {
+            0 => Some(AccessType::Read),
+            1 => Some(AccessType::Write),
+            2 => Some(AccessType::Execute),
+            _ => None,
+        }
+    }
+}
+
+impl PageFault {
+    /// Whether the address is in the user half, where faults may be resolved.
+    pub fn is_user_address(&self) -> bool {
+        self.address.0 < USER_END
+    }
+
+    /// Whether `flags` allow the access. User mode can only access user pages.
+    pub fn is_permitted_by(&self, flags: PageTableEntryFlags) -> bool {
+        if self.user && !flags.contains(PageTableEntryFlags::USER) {
+            return false;
+        }
+        flags.contains(match self.access {
+            AccessType::Read => PageTableEntryFlags::READABLE,
+            AccessType::Write => PageTableEntryFlags::WRITABLE,
+            AccessType::Execute => PageTableEntryFlags::EXECUTABLE,
+        })
+    }
+}
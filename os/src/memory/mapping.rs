@@ -1,4 +1,6 @@
-use super::LockedPagePool;
+use super::fault::USER_END;
+use super::tlb::{self, HartSet, TlbBatch};
+use super::{boot_mapping, AccessType, LockedPagePool, PageFault};
 use super::{
     PageTable, PageTableEntry, PageTableEntryFlags, PageTableHandle, PhysicalPageNumber,
     VirtualPageNumber,
@@ -8,17 +10,21 @@ use crate::layout;
 use crate::process::ThreadToken;
 use crate::scheduler::HardwareThread;
 use crate::sync::without_interrupts;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
 use alloc::vec::Vec;
+use core::mem;
 use core::ops::Range;
+use core::ptr;
 
 pub struct Mapping {
-    /// All page tables used in this process.
-    ///
-    /// `tables[0]` is the root table.
-    tables: Vec<PageTableHandle>,
+    /// All page tables used in this process, including the root table, by their PPN.
+    tables: BTreeMap<PhysicalPageNumber, PageTableHandle>,
 
-    /// All non-page-table owned pages in this process.
-    owned_pages: Vec<VirtualPageNumber>,
+    /// All non-page-table owned pages in this process, by the page they are mapped at.
+    ///
+    /// Values are kernel addresses. Pages shared copy-on-write are counted by the pool.
+    owned_pages: BTreeMap<VirtualPageNumber, VirtualPageNumber>,
 
     /// PPN of the root table.
     root_ppn: PhysicalPageNumber,
@@ -26,6 +32,14 @@ pub struct Mapping {
     /// Page pool from which pages in this mapping are allocated from.
     pool: LockedPagePool,
 
+    /// Harts that may have TLB entries of this mapping.
+    ///
+    /// A superset: a hart is added when it activates this mapping and never removed.
+    active_harts: HartSet,
+
+    /// Whether the kernel half is shared with the boot mapping, and so must not be changed.
+    shares_kernel_half: bool,
+
     ready_for_auto_drop: bool,
 }
 
@@ -38,8 +52,23 @@ pub struct Segment {
 
 #[derive(Clone, Debug)]
 pub enum SegmentBacking {
-    Linear { phys_start: PhysicalPageNumber },
+    Linear {
+        phys_start: PhysicalPageNumber,
+    },
     Owned,
+
+    /// Like `Owned`, but each page is allocated, zeroed and mapped on its first fault.
+    OwnedLazy,
+}
+
+/// A walk of `Mapping::update_range`.
+struct Update<'a> {
+    range: Range<VirtualPageNumber>,
+    f: &'a mut dyn FnMut(VirtualPageNumber, PageTableEntry) -> PageTableEntry,
+    batch: TlbBatch,
+
+    /// Tables unlinked, to be released after the TLB flush.
+    freed: Vec<PageTableHandle>,
 }
 
 impl Mapping {
@@ -49,97 +78,371 @@ impl Mapping {
     ) -> KernelResult<Self> {
         let root_table = PageTable::new(pool.clone(), token)?;
         let root_ppn = root_table.ppn();
+        let mut tables = BTreeMap::new();
+        tables.insert(root_ppn, root_table);
         Ok(Mapping {
-            tables: vec![root_table],
-            owned_pages: vec![],
+            tables,
+            owned_pages: BTreeMap::new(),
             root_ppn,
             pool,
+            active_harts: HartSet::new(),
+            shares_kernel_half: false,
             ready_for_auto_drop: false,
         })
     }
 
+    /// Returns all tables and owned pages to the pool.
+    ///
+    /// The mapping must not be active on any hart.
     pub fn release(mut self, token: &ThreadToken) {
-        for &vpn in &self.owned_pages {
+        for &vpn in self.owned_pages.values() {
             self.pool.free(vpn, token);
         }
+        for (_, table) in mem::replace(&mut self.tables, BTreeMap::new()) {
+            table.release(token);
+        }
         self.ready_for_auto_drop = true;
     }
 
-    pub fn fork(&self, pool: LockedPagePool, token: &ThreadToken) -> KernelResult<Self> {
+    /// Creates a mapping with an empty user half, sharing the kernel half of the boot mapping.
+    pub fn new(pool: LockedPagePool, token: &ThreadToken) -> KernelResult<Self> {
         let mut new_mapping = unsafe { Mapping::new_without_kernel_region(pool, token)? };
 
-        let ram_mapping_vpn = layout::ram_start().vpn();
-        let levels = ram_mapping_vpn.levels();
-
-        // We are going to reuse the first level entry (1 GB).
-        // So verify that other levels are zero, just to make sure.
-        for subindex in &levels[1..] {
-            assert_eq!(*subindex, 0);
+        // Share the kernel half (RAM and device registers) by reusing its first level entries
+        // (1 GB each). The kernel never maps new first level entries after boot.
+        let boot_root = unsafe { &*boot_mapping().root_table() };
+        let root = unsafe { &mut *new_mapping.root_table() };
+        let first_kernel_level = layout::kernel_idmap_start().vpn().levels()[0];
+        for i in first_kernel_level..root.entries.len() {
+            root.entries[i] = boot_root.entries[i];
         }
-
-        new_mapping.tables[0].entries[levels[0]] = self.tables[0].entries[levels[0]];
+        new_mapping.shares_kernel_half = true;
 
         Ok(new_mapping)
     }
 
+    /// Duplicates the user half.
+    ///
+    /// Writable owned pages become read-only and shared copy-on-write in both mappings. Other
+    /// owned pages are shared as they are, and the pool counts references to all shared pages.
+    pub fn fork(&mut self, token: &ThreadToken) -> KernelResult<Self> {
+        let mut child = Mapping::new(self.pool.clone(), token)?;
+        let mut batch = TlbBatch::new();
+        let result = self.fork_user_half(&mut child, &mut batch, token);
+        self.flush_tlb_batch(batch);
+        match result {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                child.release(token);
+                Err(e)
+            }
+        }
+    }
+
+    fn fork_user_half(
+        &mut self,
+        child: &mut Mapping,
+        batch: &mut TlbBatch,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let leaves: Vec<_> = self
+            .leaves()
+            .take_while(|x| x.0.start_address().0 < USER_END)
+            .collect();
+        for (vpn, level, entry) in leaves {
+            let mut flags = entry.flags();
+            if let Some(&kernel_vpn) = self.owned_pages.get(&vpn) {
+                if flags.contains(PageTableEntryFlags::WRITABLE) {
+                    flags.remove(PageTableEntryFlags::WRITABLE);
+                    flags.insert(PageTableEntryFlags::COPY_ON_WRITE);
+                    *self.entry(vpn, token)? = PageTableEntry::new(entry.ppn(), flags);
+                    batch.add(vpn);
+                }
+                child.map_one_batched(vpn, entry.ppn(), flags, batch, token)?;
+                self.pool.add_ref(kernel_vpn, token);
+                child.owned_pages.insert(vpn, kernel_vpn);
+            } else {
+                child.map_leaf_batched(vpn, entry.ppn(), flags, level, batch, token)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the level 2 (4 KB) entry for `vpn`.
     pub fn entry(
         &mut self,
         vpn: VirtualPageNumber,
         token: &ThreadToken,
     ) -> KernelResult<&mut PageTableEntry> {
-        let root_table_ptr: *mut PageTable = self
-            .root_ppn
-            .start_address()
-            .to_virt()
-            .expect("Mapping::entry: bad root_ppn")
-            .as_mut_ptr();
-        let mut entry = &mut unsafe { &mut *root_table_ptr }.entries[vpn.levels()[0]];
-        for subindex in &vpn.levels()[1..] {
+        self.check_mappable(&(vpn..VirtualPageNumber(vpn.0 + 1)))?;
+        self.entry_at(vpn, 2, token)
+    }
+
+    /// Returns the entry for `vpn` at `level`: 0 for 1 GB, 1 for 2 MB or 2 for 4 KB pages.
+    ///
+    /// Allocates missing tables, and splits huge leaves above `level`.
+    fn entry_at(
+        &mut self,
+        vpn: VirtualPageNumber,
+        level: usize,
+        token: &ThreadToken,
+    ) -> KernelResult<&mut PageTableEntry> {
+        let levels = vpn.levels();
+        let mut entry = &mut unsafe { &mut *self.root_table() }.entries[levels[0]];
+        for l in 1..=level {
             if entry.is_empty() {
-                //println!("Heap usage before: {}", crate::allocator::heap_usage());
                 let new_table = PageTable::new(self.pool.clone(), token)?;
-                //println!("Heap usage after: {}", crate::allocator::heap_usage());
                 let new_ppn = new_table.ppn();
-                self.tables.push(new_table);
+                self.tables.insert(new_ppn, new_table);
                 *entry = PageTableEntry::new(new_ppn, PageTableEntryFlags::VALID);
+            } else if entry.is_leaf() {
+                self.split(entry, l - 1, token)?;
             }
-            entry = &mut unsafe { &mut *entry.next_level() }.entries[*subindex];
+            entry = &mut unsafe { &mut *entry.next_level() }.entries[levels[l]];
         }
         Ok(entry)
     }
 
-    pub fn map_one(
+    /// Replaces a huge leaf at `level` with a table of leaves one level down that map the same,
+    /// e.g. before part of it is remapped.
+    ///
+    /// Needs no TLB flush, since translations do not change.
+    fn split(
+        &mut self,
+        entry: &mut PageTableEntry,
+        level: usize,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let mut table = PageTable::new(self.pool.clone(), token)?;
+        let child_pages = pages_per_entry(level + 1);
+        let flags = entry.flags();
+        for (i, x) in table.entries.iter_mut().enumerate() {
+            *x = PageTableEntry::new(PhysicalPageNumber(entry.ppn().0 + i * child_pages), flags);
+        }
+        let ppn = table.ppn();
+        self.tables.insert(ppn, table);
+        *entry = PageTableEntry::new(ppn, PageTableEntryFlags::VALID);
+        Ok(())
+    }
+
+    /// Resolves a fault on a page of `seg`, whose flags must permit the access.
+    ///
+    /// Fails with `InvalidArgument` if the fault cannot be resolved.
+    pub fn handle_page_fault(
+        &mut self,
+        seg: &Segment,
+        fault: PageFault,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let vpn = fault.address.vpn();
+        debug_assert!(seg.range.start <= vpn && vpn < seg.range.end);
+        match (self.leaf(vpn).map(|(x, _)| x), &seg.backing) {
+            // Spurious, e.g. from a stale TLB entry on this hart. The kernel may need
+            // `sstatus.SUM` for user pages, so its faults on them are never spurious.
+            (Some(entry), _)
+                if fault.is_permitted_by(entry.flags())
+                    && (fault.user || !entry.flags().contains(PageTableEntryFlags::USER)) =>
+            {
+                tlb::flush_local(Some(vpn..VirtualPageNumber(vpn.0 + 1)));
+                Ok(())
+            }
+            (Some(entry), _)
+                if fault.access == AccessType::Write
+                    && entry.flags().contains(PageTableEntryFlags::COPY_ON_WRITE)
+                    && fault.is_permitted_by(entry.flags() | PageTableEntryFlags::WRITABLE) =>
+            {
+                self.copy_on_write(vpn, entry, token)
+            }
+            (None, SegmentBacking::OwnedLazy) => self.map_zeroed_page(vpn, seg.flags, token),
+            _ => Err(KernelError::InvalidArgument),
+        }
+    }
+
+    /// Gives `vpn` a writable page of its own, copying the shared one unless it is the last
+    /// reference.
+    fn copy_on_write(
+        &mut self,
+        vpn: VirtualPageNumber,
+        entry: PageTableEntry,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let shared = *self
+            .owned_pages
+            .get(&vpn)
+            .expect("Mapping::copy_on_write: page not owned");
+        let mut flags = entry.flags();
+        flags.remove(PageTableEntryFlags::COPY_ON_WRITE);
+        flags.insert(PageTableEntryFlags::WRITABLE);
+
+        if self.pool.ref_count(shared, token) == 1 {
+            // Nobody else can take a reference to it.
+            return self.map_one(vpn, entry.ppn(), flags, token);
+        }
+        let copy = self.pool.allocate(token)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                shared.start_address().as_ptr::<u8>(),
+                copy.start_address().as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        let ppn = copy
+            .to_phys()
+            .expect("Mapping::copy_on_write: bad kernel vpn");
+        if let Err(e) = self.map_one(vpn, ppn, flags, token) {
+            self.pool.free(copy, token);
+            return Err(e);
+        }
+        self.owned_pages.insert(vpn, copy);
+        self.pool.free(shared, token);
+        Ok(())
+    }
+
+    /// Maps a newly allocated zero page at `vpn`, which must be unmapped.
+    fn map_zeroed_page(
+        &mut self,
+        vpn: VirtualPageNumber,
+        flags: PageTableEntryFlags,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let kernel_vpn = self.pool.allocate(token)?;
+        unsafe {
+            ptr::write_bytes(kernel_vpn.start_address().as_mut_ptr::<u8>(), 0, 4096);
+        }
+        let ppn = kernel_vpn
+            .to_phys()
+            .expect("Mapping::map_zeroed_page: bad kernel vpn");
+        if let Err(e) = self.map_one(vpn, ppn, flags, token) {
+            self.pool.free(kernel_vpn, token);
+            return Err(e);
+        }
+        self.owned_pages.insert(vpn, kernel_vpn);
+        // This hart may have cached the invalid entry. Others fault again and find it spurious.
+        tlb::flush_local(Some(vpn..VirtualPageNumber(vpn.0 + 1)));
+        Ok(())
+    }
+
+    /// Maps a page. Replacing a valid entry requires a TLB flush, which is added to `batch`.
+    fn map_one_batched(
         &mut self,
         vpn: VirtualPageNumber,
         ppn: PhysicalPageNumber,
         flags: PageTableEntryFlags,
+        batch: &mut TlbBatch,
         token: &ThreadToken,
     ) -> KernelResult<()> {
-        let entry = self.entry(vpn, token)?;
+        self.map_leaf_batched(vpn, ppn, flags, 2, batch, token)
+    }
+
+    /// Maps a leaf at `level`. `vpn` and `ppn` must be aligned to its size.
+    ///
+    /// An existing table at `level` is kept, and filled with leaves one level down instead.
+    fn map_leaf_batched(
+        &mut self,
+        vpn: VirtualPageNumber,
+        ppn: PhysicalPageNumber,
+        flags: PageTableEntryFlags,
+        level: usize,
+        batch: &mut TlbBatch,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let pages = pages_per_entry(level);
+        let entry = self.entry_at(vpn, level, token)?;
+        if level < 2 && !entry.is_empty() && !entry.is_leaf() {
+            let child_pages = pages_per_entry(level + 1);
+            for i in 0..512 {
+                self.map_leaf_batched(
+                    VirtualPageNumber(vpn.0 + i * child_pages),
+                    PhysicalPageNumber(ppn.0 + i * child_pages),
+                    flags,
+                    level + 1,
+                    batch,
+                    token,
+                )?;
+            }
+            return Ok(());
+        }
+        if entry.flags().contains(PageTableEntryFlags::VALID) {
+            batch.add_range(vpn..VirtualPageNumber(vpn.0 + pages));
+        }
         *entry = PageTableEntry::new(ppn, flags);
         Ok(())
     }
 
+    pub fn map_one(
+        &mut self,
+        vpn: VirtualPageNumber,
+        ppn: PhysicalPageNumber,
+        flags: PageTableEntryFlags,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        self.check_mappable(&(vpn..VirtualPageNumber(vpn.0 + 1)))?;
+        let mut batch = TlbBatch::new();
+        let result = self.map_one_batched(vpn, ppn, flags, &mut batch, token);
+        self.flush_tlb_batch(batch);
+        result
+    }
+
+    /// Maps `seg`. Fails with `Busy` if it overlaps owned pages, which are never replaced, and with
+    /// `InvalidArgument` if it reaches into a shared kernel half.
     pub fn map_segment(&mut self, seg: &Segment, token: &ThreadToken) -> KernelResult<()> {
-        println!("Mapping segment: {:x?}", seg);
+        self.check_mappable(&seg.range)?;
+        if self.owned_pages.range(seg.range.clone()).next().is_some() {
+            return Err(KernelError::Busy);
+        }
+        let mut batch = TlbBatch::new();
+        let result = self.map_segment_batched(seg, &mut batch, token);
+        self.flush_tlb_batch(batch);
+        result
+    }
+
+    fn map_segment_batched(
+        &mut self,
+        seg: &Segment,
+        batch: &mut TlbBatch,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        debug!("Mapping segment: {:x?}", seg);
+        if let SegmentBacking::OwnedLazy = seg.backing {
+            return Ok(());
+        }
+        if let SegmentBacking::Linear { phys_start } = seg.backing {
+            // Use the largest leaves that alignment and the remaining size allow.
+            let mut vpn = seg.range.start.0;
+            while vpn < seg.range.end.0 {
+                let ppn = phys_start.0 + (vpn - seg.range.start.0);
+                let level = (0..2)
+                    .find(|&l| {
+                        let n = pages_per_entry(l);
+                        vpn % n == 0 && ppn % n == 0 && seg.range.end.0 - vpn >= n
+                    })
+                    .unwrap_or(2);
+                self.map_leaf_batched(
+                    VirtualPageNumber(vpn),
+                    PhysicalPageNumber(ppn),
+                    seg.flags,
+                    level,
+                    batch,
+                    token,
+                )?;
+                vpn += pages_per_entry(level);
+            }
+            return Ok(());
+        }
         for vpn in seg.range.start.0..seg.range.end.0 {
             let vpn = VirtualPageNumber(vpn);
             match seg.backing {
-                SegmentBacking::Linear { mut phys_start } => {
-                    // Calculate the physical address of the backing frame.
-                    let page_offset = vpn.0 - seg.range.start.0;
-                    phys_start.0 += page_offset;
-                    self.map_one(vpn, phys_start, seg.flags, token)?;
-                }
+                SegmentBacking::Linear { .. } | SegmentBacking::OwnedLazy => unreachable!(),
                 SegmentBacking::Owned => {
                     let kernel_vpn = self.pool.allocate(token)?;
-                    self.owned_pages.push(kernel_vpn);
-                    self.map_one(
+                    self.owned_pages.insert(vpn, kernel_vpn);
+                    self.map_one_batched(
                         vpn,
                         kernel_vpn
                             .to_phys()
                             .expect("Mapping::map_segment: bad kernel vpn for owned segment"),
                         seg.flags,
+                        batch,
                         token,
                     )?;
                 }
@@ -148,16 +451,209 @@ impl Mapping {
         Ok(())
     }
 
+    /// Unmaps all pages in `range`, which must be in the user half. Owned pages, and tables left
+    /// empty, are returned to the pool.
+    ///
+    /// Huge pages that `range` covers in part are split first, which may fail with
+    /// `OutOfMemory`. Pages before the failure stay unmapped.
+    pub fn unmap_range(
+        &mut self,
+        range: Range<VirtualPageNumber>,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        check_user_range(&range)?;
+        let result = self.update_range(range.clone(), &mut |_, _| PageTableEntry::default(), token);
+        // After the flush, even if the update failed.
+        let unmapped: Vec<_> = self
+            .owned_pages
+            .range(range)
+            .map(|(&vpn, _)| vpn)
+            .filter(|&vpn| self.leaf(vpn).is_none())
+            .collect();
+        for vpn in unmapped {
+            let kernel_vpn = self.owned_pages.remove(&vpn).unwrap();
+            self.pool.free(kernel_vpn, token);
+        }
+        result
+    }
+
+    /// Replaces the flags of all mapped pages in `range`, which must be in the user half.
+    ///
+    /// Shared owned pages stay copy-on-write if `flags` are writable. Fails like `unmap_range`.
+    pub fn protect_range(
+        &mut self,
+        range: Range<VirtualPageNumber>,
+        flags: PageTableEntryFlags,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        check_user_range(&range)?;
+        let leaf_flags = PageTableEntryFlags::READABLE
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::EXECUTABLE;
+        if !flags.contains(PageTableEntryFlags::VALID)
+            || !flags.intersects(leaf_flags)
+            || flags.contains(PageTableEntryFlags::COPY_ON_WRITE)
+        {
+            return Err(KernelError::InvalidArgument);
+        }
+        let pool = &self.pool;
+        let shared: BTreeSet<_> = self
+            .owned_pages
+            .range(range.clone())
+            .filter(|&(_, &kernel_vpn)| pool.ref_count(kernel_vpn, token) > 1)
+            .map(|(&vpn, _)| vpn)
+            .collect();
+        self.update_range(
+            range,
+            &mut |vpn, entry| {
+                let mut new_flags = flags;
+                let cow = entry.flags().contains(PageTableEntryFlags::COPY_ON_WRITE)
+                    || shared.contains(&vpn);
+                if cow && flags.contains(PageTableEntryFlags::WRITABLE) {
+                    new_flags.remove(PageTableEntryFlags::WRITABLE);
+                    new_flags.insert(PageTableEntryFlags::COPY_ON_WRITE);
+                }
+                PageTableEntry::new(entry.ppn(), new_flags)
+            },
+            token,
+        )
+    }
+
+    /// Replaces each valid leaf in `range` with `f(vpn, leaf)`, then flushes the TLBs and
+    /// releases tables left empty.
+    fn update_range(
+        &mut self,
+        range: Range<VirtualPageNumber>,
+        f: &mut dyn FnMut(VirtualPageNumber, PageTableEntry) -> PageTableEntry,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let mut update = Update {
+            range,
+            f,
+            batch: TlbBatch::new(),
+            freed: Vec::new(),
+        };
+        let root = self.root_table();
+        let result = self.update_leaves(root, 0, 0, &mut update, token);
+        if update.freed.is_empty() {
+            self.flush_tlb_batch(update.batch);
+        } else {
+            // Walks may have cached the unlinked tables, which only a full flush removes.
+            self.flush_tlb_all();
+        }
+        for table in update.freed {
+            table.release(token);
+        }
+        result.map(|_| ())
+    }
+
+    /// Updates the leaves of `table` at `level`, whose first entry maps `first_vpn`, and unlinks
+    /// tables below it that are left empty. Returns whether `table` is left empty.
+    fn update_leaves(
+        &mut self,
+        table: *mut PageTable,
+        level: usize,
+        first_vpn: usize,
+        update: &mut Update,
+        token: &ThreadToken,
+    ) -> KernelResult<bool> {
+        let pages = pages_per_entry(level);
+        for i in 0..512 {
+            let start = first_vpn + i * pages;
+            let end = start + pages;
+            if end <= update.range.start.0 || update.range.end.0 <= start {
+                continue;
+            }
+            let entry = &mut unsafe { &mut *table }.entries[i];
+            if !entry.flags().contains(PageTableEntryFlags::VALID) {
+                continue;
+            }
+            if level == 2 || entry.is_leaf() {
+                if update.range.start.0 <= start && end <= update.range.end.0 {
+                    let new_entry = (update.f)(VirtualPageNumber(start), *entry);
+                    if new_entry.get() != entry.get() {
+                        *entry = new_entry;
+                        update
+                            .batch
+                            .add_range(VirtualPageNumber(start)..VirtualPageNumber(end));
+                    }
+                    continue;
+                }
+                self.split(entry, level, token)?;
+            }
+            if self.update_leaves(entry.next_level(), level + 1, start, update, token)? {
+                // Tables shared with the boot mapping are not ours to release.
+                if let Some(child) = self.tables.remove(&entry.ppn()) {
+                    *entry = PageTableEntry::default();
+                    update.freed.push(child);
+                }
+            }
+        }
+        Ok(unsafe { &*table }.entries.iter().all(|x| x.is_empty()))
+    }
+
     /// Activates this mapping in a thread context.
     ///
     /// This method is safe because each `Mapping` is guaranteed to include the kernel region.
     pub fn activate_thread(&self, _: &ThreadToken) {
         let new_satp = self.root_ppn.0 | (8 << 60); // Sv39
-        without_interrupts(HardwareThread::this_hart(), || unsafe {
+        let ht = HardwareThread::this_hart();
+        // Before switching, so that a concurrent shootdown cannot miss this hart.
+        self.active_harts.insert(ht.id().0);
+        without_interrupts(ht, || unsafe {
             llvm_asm!("csrw satp, $0" :: "r"(new_satp) :: "volatile");
             llvm_asm!("sfence.vma" :::: "volatile");
         });
     }
+
+    /// Fails with `InvalidArgument` if `range` is not in the user half of a mapping that shares
+    /// the kernel half.
+    fn check_mappable(&self, range: &Range<VirtualPageNumber>) -> KernelResult<()> {
+        if self.shares_kernel_half {
+            check_user_range(range)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn root_table(&self) -> *mut PageTable {
+        self.root_ppn
+            .start_address()
+            .to_virt()
+            .expect("Mapping: bad root_ppn")
+            .as_mut_ptr()
+    }
+
+    /// Returns the set of harts that may have TLB entries of this mapping, one bit per hart ID.
+    pub fn active_harts(&self) -> u64 {
+        self.active_harts.bits()
+    }
+
+    /// Flushes `range` from the TLBs of all harts that have this mapping active.
+    pub fn flush_tlb(&self, range: Range<VirtualPageNumber>) {
+        tlb::shootdown(self.active_harts(), Some(range));
+    }
+
+    /// Flushes all TLB entries on all harts that have this mapping active.
+    pub fn flush_tlb_all(&self) {
+        tlb::shootdown(self.active_harts(), None);
+    }
+
+    /// Flushes the pages collected in `batch` on all harts that have this mapping active.
+    pub fn flush_tlb_batch(&self, batch: TlbBatch) {
+        batch.flush(self.active_harts());
+    }
+}
+
+fn check_user_range(range: &Range<VirtualPageNumber>) -> KernelResult<()> {
+    if range.start > range.end || range.end.start_address().0 > USER_END {
+        return Err(KernelError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Number of 4 KB pages mapped by an entry at `level`.
+pub(super) fn pages_per_entry(level: usize) -> usize {
+    1 << (9 * (2 - level))
 }
 
 impl Drop for Mapping {
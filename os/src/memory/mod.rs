@@ -1,25 +1,36 @@
 mod address;
 mod boot;
+mod fault;
 mod mapping;
 mod page_table;
 mod pool;
+pub mod tlb;
+mod walk;
 
 pub use address::{PhysicalAddress, PhysicalPageNumber, VirtualAddress, VirtualPageNumber};
+pub use fault::{AccessType, PageFault};
 pub use mapping::{Mapping, Segment, SegmentBacking};
 pub use page_table::{
     Entry as PageTableEntry, Flags as PageTableEntryFlags, Table as PageTable,
     TableHandle as PageTableHandle,
 };
-pub use pool::{LockedPagePool, PagePool};
+pub use pool::{LockedPagePool, PagePool, PAGES_PER_SET};
+pub use tlb::TlbBatch;
+pub use walk::{LeafRun, LeafRuns, Leaves};
 
 use crate::process::ThreadToken;
 use crate::sync::Once;
+use alloc::vec::Vec;
+use core::ops::Range;
 
 static mut BOOT_MAPPING: Option<Mapping> = None;
 static BOOT_PAGE_POOL: Once<LockedPagePool> = Once::new();
 
+/// Device registers that `remap_kernel` maps into the kernel half.
+static mut MMIO_REGIONS: Vec<Range<PhysicalAddress>> = Vec::new();
+
 pub fn init() {
-    println!("memory: Initialized.");
+    info!("Initialized.");
 }
 
 pub fn boot_page_pool() -> &'static LockedPagePool {
@@ -40,7 +51,25 @@ pub unsafe fn remap_kernel(token: &ThreadToken) {
         boot::remap_kernel(boot_page_pool().clone(), token)
             .expect("memory::remap_kernel: remap_kernel failed"),
     );
-    println!("memory: Kernel remapped.");
+    info!("Kernel remapped.");
+}
+
+/// Registers device registers to be kept mapped at `PhysicalAddress::to_virt` after the kernel
+/// is remapped. Until then, the boot page table maps the first 1 GB of physical memory.
+///
+/// # Safety
+///
+/// Must be called on the boot hart before `remap_kernel`.
+pub unsafe fn register_mmio(range: Range<PhysicalAddress>) {
+    assert!(
+        BOOT_MAPPING.is_none(),
+        "memory::register_mmio: kernel already remapped"
+    );
+    MMIO_REGIONS.push(range);
+}
+
+fn mmio_regions() -> &'static [Range<PhysicalAddress>] {
+    unsafe { &MMIO_REGIONS }
 }
 
 pub fn boot_mapping() -> &'static Mapping {
@@ -22,7 +22,7 @@ unsafe impl Send for TableHandle {}
 unsafe impl Sync for TableHandle {}
 
 impl TableHandle {
-    fn release(mut self, token: &ThreadToken) {
+    pub fn release(mut self, token: &ThreadToken) {
         self.pool
             .free(VirtualAddress::from(self.table).vpn(), token);
         self.ready_for_auto_drop = true;
@@ -75,7 +75,7 @@ impl Entry {
     pub fn new(page_number: PhysicalPageNumber, flags: Flags) -> Self {
         Entry(
             *0usize
-                .set_bits(0..8, flags.bits())
+                .set_bits(0..9, flags.bits())
                 .set_bits(10..54, page_number.0),
         )
     }
@@ -88,6 +88,13 @@ impl Entry {
         self.0 == 0
     }
 
+    /// Whether this is a valid entry that maps memory, rather than pointing to a table.
+    pub fn is_leaf(&self) -> bool {
+        let flags = self.flags();
+        flags.contains(Flags::VALID)
+            && flags.intersects(Flags::READABLE | Flags::WRITABLE | Flags::EXECUTABLE)
+    }
+
     pub fn next_level(&self) -> *mut Table {
         self.ppn()
             .start_address()
@@ -101,7 +108,7 @@ impl Entry {
     }
 
     pub fn flags(&self) -> Flags {
-        Flags::from_bits(self.0.get_bits(0..8)).unwrap()
+        Flags::from_bits(self.0.get_bits(0..9)).unwrap()
     }
 }
 
@@ -116,5 +123,9 @@ bitflags! {
         const GLOBAL = 1 << 5;
         const ACCESSED = 1 << 6;
         const DIRTY = 1 << 7;
+
+        /// Reserved for software: a read-only view of a writable page that is shared after a
+        /// fork, and copied on the first write.
+        const COPY_ON_WRITE = 1 << 8;
     }
 }
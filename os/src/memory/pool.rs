@@ -9,13 +9,16 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::pin::Pin;
 
-const PAGES_PER_SET: u8 = 64; // 256 KB
+pub const PAGES_PER_SET: u8 = 64; // 256 KB
 
 pub struct PagePool {
     sets: Vec<PageSetInfo>,
     usable_pages: BTreeSet<(u32, u8)>, // (set_index, page_index)
     allocated_pages: BTreeMap<VirtualPageNumber, (u32, u8)>,
 
+    /// References beyond the first to allocated pages, e.g. pages shared copy-on-write.
+    extra_refs: BTreeMap<VirtualPageNumber, usize>,
+
     /// Used to determine when to shrink.
     free_count_before_shrink: usize,
 }
@@ -36,6 +39,12 @@ struct PageSet {
 #[repr(C, align(4096))]
 struct Page([u8; 4096]);
 
+impl Page {
+    fn vpn(&self) -> VirtualPageNumber {
+        VirtualAddress::from(self).vpn()
+    }
+}
+
 impl LockedPagePool {
     pub fn new() -> LockedPagePool {
         LockedPagePool(Arc::pin(Mutex::new(PagePool::new())))
@@ -46,9 +55,31 @@ impl LockedPagePool {
         result
     }
 
+    pub fn allocate_contiguous(
+        &self,
+        n: usize,
+        token: &ThreadToken,
+    ) -> KernelResult<VirtualPageNumber> {
+        self.0.as_ref().lock(token).allocate_contiguous(n)
+    }
+
     pub fn free(&self, vpn: VirtualPageNumber, token: &ThreadToken) {
         self.0.as_ref().lock(token).free(vpn);
     }
+
+    pub fn add_ref(&self, vpn: VirtualPageNumber, token: &ThreadToken) {
+        self.0.as_ref().lock(token).add_ref(vpn);
+    }
+
+    pub fn ref_count(&self, vpn: VirtualPageNumber, token: &ThreadToken) -> usize {
+        self.0.as_ref().lock(token).ref_count(vpn)
+    }
+
+    /// Returns the number of used pages in each page set.
+    pub fn set_usage(&self, token: &ThreadToken) -> Vec<usize> {
+        let pool = self.0.as_ref().lock(token);
+        pool.sets.iter().map(|x| x.used_pages).collect()
+    }
 }
 
 impl PagePool {
@@ -57,6 +88,7 @@ impl PagePool {
             sets: Vec::new(),
             usable_pages: BTreeSet::new(),
             allocated_pages: BTreeMap::new(),
+            extra_refs: BTreeMap::new(),
 
             free_count_before_shrink: 0,
         }
@@ -65,13 +97,28 @@ impl PagePool {
     pub fn allocate(&mut self) -> KernelResult<VirtualPageNumber> {
         loop {
             match self.usable_pages.pop_first() {
+                Some((major, minor)) => break Ok(self.take(major, minor)),
+                None => {
+                    self.grow()?;
+                }
+            }
+        }
+    }
+
+    /// Allocates `n` pages that are contiguous in physical memory, e.g. for DMA, and returns the
+    /// first one. The pages are freed one by one.
+    pub fn allocate_contiguous(&mut self, n: usize) -> KernelResult<VirtualPageNumber> {
+        if n == 0 || n > PAGES_PER_SET as usize {
+            return Err(KernelError::InvalidArgument);
+        }
+        loop {
+            match self.find_free_run(n) {
                 Some((major, minor)) => {
-                    let set_info = &mut self.sets[major as usize];
-                    let page = &mut set_info.set.pages[minor as usize] as *mut Page;
-                    set_info.used_pages += 1;
-                    let vpn = VirtualAddress::from(page).vpn();
-                    self.allocated_pages.insert(vpn, (major, minor));
-                    break Ok(vpn);
+                    for i in 0..n as u8 {
+                        self.usable_pages.remove(&(major, minor + i));
+                        self.take(major, minor + i);
+                    }
+                    break Ok(self.sets[major as usize].set.pages[minor as usize].vpn());
                 }
                 None => {
                     self.grow()?;
@@ -80,7 +127,63 @@ impl PagePool {
         }
     }
 
+    /// Finds `n` usable pages in a row within one set.
+    fn find_free_run(&self, n: usize) -> Option<(u32, u8)> {
+        let mut start = None;
+        let mut len = 0;
+        let mut prev: Option<(u32, u8)> = None;
+        for &(major, minor) in &self.usable_pages {
+            match prev {
+                Some((prev_major, prev_minor))
+                    if prev_major == major && prev_minor + 1 == minor =>
+                {
+                    len += 1
+                }
+                _ => {
+                    start = Some((major, minor));
+                    len = 1;
+                }
+            }
+            if len == n {
+                return start;
+            }
+            prev = Some((major, minor));
+        }
+        None
+    }
+
+    /// Marks a page that is no longer in `usable_pages` as allocated.
+    fn take(&mut self, major: u32, minor: u8) -> VirtualPageNumber {
+        let set_info = &mut self.sets[major as usize];
+        set_info.used_pages += 1;
+        let vpn = set_info.set.pages[minor as usize].vpn();
+        self.allocated_pages.insert(vpn, (major, minor));
+        vpn
+    }
+
+    /// Adds a reference to an allocated page, so that it takes one more `free` to free it.
+    pub fn add_ref(&mut self, vpn: VirtualPageNumber) {
+        assert!(
+            self.allocated_pages.contains_key(&vpn),
+            "PagePool::add_ref: page not allocated: {:x?}",
+            vpn
+        );
+        *self.extra_refs.entry(vpn).or_insert(0) += 1;
+    }
+
+    pub fn ref_count(&self, vpn: VirtualPageNumber) -> usize {
+        1 + self.extra_refs.get(&vpn).copied().unwrap_or(0)
+    }
+
+    /// Drops a reference to a page, and frees it with the last one.
     pub fn free(&mut self, vpn: VirtualPageNumber) {
+        if let Some(n) = self.extra_refs.get_mut(&vpn) {
+            *n -= 1;
+            if *n == 0 {
+                self.extra_refs.remove(&vpn);
+            }
+            return;
+        }
         let (major, minor) = match self.allocated_pages.remove(&vpn) {
             Some(x) => x,
             None => panic!(
@@ -0,0 +1,164 @@
+//! TLB maintenance across harts.
+
+use super::VirtualPageNumber;
+use crate::sbi;
+use crate::scheduler::HardwareThread;
+use crate::smp::{self, ipi, HartStatus};
+use core::ops::Range;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Harts are tracked in a 64-bit mask.
+pub const MAX_HART_ID: u32 = 63;
+
+/// Flushes of more pages than this are done as a full flush.
+pub const FLUSH_ALL_THRESHOLD: usize = 64;
+
+/// A set of harts, one bit per hart ID.
+pub struct HartSet(AtomicU64);
+
+/// TLB invalidations collected to be flushed at once.
+///
+/// Keeps a single range covering all added pages, which becomes a full flush when it grows over
+/// `FLUSH_ALL_THRESHOLD` pages.
+#[derive(Clone, Debug, Default)]
+pub struct TlbBatch {
+    range: Option<Range<VirtualPageNumber>>,
+}
+
+fn hart_bit(hart_id: u32) -> u64 {
+    assert!(hart_id <= MAX_HART_ID, "tlb: hart ID {} too large", hart_id);
+    1 << hart_id
+}
+
+impl HartSet {
+    pub const fn new() -> HartSet {
+        HartSet(AtomicU64::new(0))
+    }
+
+    pub fn insert(&self, hart_id: u32) {
+        self.0.fetch_or(hart_bit(hart_id), Ordering::SeqCst);
+    }
+
+    pub fn remove(&self, hart_id: u32) {
+        self.0.fetch_and(!hart_bit(hart_id), Ordering::SeqCst);
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl TlbBatch {
+    pub fn new() -> TlbBatch {
+        TlbBatch { range: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_none()
+    }
+
+    pub fn add(&mut self, vpn: VirtualPageNumber) {
+        self.add_range(vpn..VirtualPageNumber(vpn.0 + 1));
+    }
+
+    pub fn add_range(&mut self, range: Range<VirtualPageNumber>) {
+        if range.start >= range.end {
+            return;
+        }
+        self.range = Some(match self.range.take() {
+            Some(x) => x.start.min(range.start)..x.end.max(range.end),
+            None => range,
+        });
+    }
+
+    /// Flushes the collected pages on the harts in `harts`.
+    pub fn flush(self, harts: u64) {
+        if let Some(range) = self.range {
+            shootdown(harts, Some(range));
+        }
+    }
+}
+
+/// Flushes `range`, or the whole TLB if `None`, on the current hart.
+pub fn flush_local(range: Option<Range<VirtualPageNumber>>) {
+    match range {
+        Some(range) if range.end.0 - range.start.0 <= FLUSH_ALL_THRESHOLD => {
+            for vpn in range.start.0..range.end.0 {
+                let addr = VirtualPageNumber(vpn).start_address().0;
+                unsafe {
+                    llvm_asm!("sfence.vma $0, zero" :: "r"(addr) :: "volatile");
+                }
+            }
+        }
+        _ => unsafe {
+            llvm_asm!("sfence.vma" :::: "volatile");
+        },
+    }
+}
+
+/// Flushes `range`, or the whole TLB if `None`, on the harts in `harts`, and waits until all of
+/// them are done.
+///
+/// Uses SBI RFENCE if available, and IPIs otherwise. Must be called without interrupt guards held,
+/// since the target harts may be waiting for us in the same way.
+pub fn shootdown(harts: u64, range: Option<Range<VirtualPageNumber>>) {
+    if harts == 0 {
+        return;
+    }
+    let ht = HardwareThread::this_hart();
+    assert!(
+        !ht.has_active_intr_guards(),
+        "tlb::shootdown: must not hold any interrupt guards"
+    );
+    let range = range.filter(|x| x.end.0 - x.start.0 <= FLUSH_ALL_THRESHOLD);
+
+    let this_hart = ht.id().0;
+    if harts & hart_bit(this_hart) != 0 {
+        flush_local(range.clone());
+    }
+
+    // Offline harts flush their whole TLB when they are started again.
+    let remote = smp::harts()
+        .iter()
+        .filter(|x| x.id() != this_hart && x.status() == HartStatus::Online)
+        .filter(|x| x.id() <= MAX_HART_ID && harts & hart_bit(x.id()) != 0)
+        .fold(0u64, |mask, x| mask | hart_bit(x.id()));
+    if remote == 0 {
+        return;
+    }
+
+    if sbi::extensions().rfence {
+        let (start, size) = match &range {
+            Some(x) => (x.start.start_address().0, (x.end.0 - x.start.0) * PAGE_SIZE),
+            None => (0, usize::MAX),
+        };
+        // SBI remote fences return after the target harts are done.
+        if let Err(e) = sbi::remote_sfence_vma(remote as usize, 0, start, size) {
+            panic!("tlb::shootdown: remote_sfence_vma failed: {:?}", e);
+        }
+        return;
+    }
+
+    let mut tickets = [0usize; MAX_HART_ID as usize + 1];
+    for hart_id in 0..=MAX_HART_ID {
+        if remote & hart_bit(hart_id) == 0 {
+            continue;
+        }
+        let ticket = match &range {
+            Some(x) => ipi::send_tlb_flush(hart_id, x.clone()),
+            None => ipi::send_tlb_flush_all(hart_id),
+        };
+        // The hart went offline in the meantime. Nothing to wait for.
+        tickets[hart_id as usize] = ticket.unwrap_or(0);
+    }
+    for hart_id in 0..=MAX_HART_ID {
+        if remote & hart_bit(hart_id) == 0 {
+            continue;
+        }
+        while !ipi::tlb_flush_done(hart_id, tickets[hart_id as usize]) {
+            spin_loop_hint();
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Software walks of page tables, for debugging and tests.
+
+use super::mapping::pages_per_entry;
+use super::{
+    Mapping, PageTable, PageTableEntry, PageTableEntryFlags, PhysicalAddress, PhysicalPageNumber,
+    VirtualAddress, VirtualPageNumber,
+};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr;
+
+/// Valid leaf entries of a mapping in address order, with the page they map and their level: 0
+/// for 1 GB, 1 for 2 MB or 2 for 4 KB pages.
+pub struct Leaves<'a> {
+    /// Tables on the path to the next entry, from the root.
+    tables: [*const PageTable; 3],
+
+    /// Index of the next entry in each table on the path.
+    indices: [usize; 3],
+
+    level: usize,
+    _mapping: PhantomData<&'a Mapping>,
+}
+
+/// Pages mapped to contiguous frames by adjacent leaves with the same level and flags.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LeafRun {
+    pub start: VirtualPageNumber,
+    pub pages: usize,
+    pub ppn: PhysicalPageNumber,
+    pub flags: PageTableEntryFlags,
+    pub level: usize,
+}
+
+/// Leaves of a mapping, coalesced into runs.
+pub struct LeafRuns<'a> {
+    leaves: Leaves<'a>,
+    run: Option<LeafRun>,
+}
+
+impl Mapping {
+    /// Translates `addr` like the MMU would, but without checking permissions.
+    ///
+    /// Returns the physical address, with the flags and level of the leaf that maps it.
+    pub fn translate(
+        &self,
+        addr: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PageTableEntryFlags, usize)> {
+        let (entry, level) = self.leaf(addr.vpn())?;
+        let offset = addr.0 & (pages_per_entry(level) * 4096 - 1);
+        Some((
+            PhysicalAddress(entry.ppn().start_address().0 + offset),
+            entry.flags(),
+            level,
+        ))
+    }
+
+    /// Returns the valid leaf entry that maps `vpn` and its level.
+    pub(super) fn leaf(&self, vpn: VirtualPageNumber) -> Option<(PageTableEntry, usize)> {
+        let mut table: *const PageTable = self.root_table();
+        for (level, &index) in vpn.levels().iter().enumerate() {
+            let entry = unsafe { &*table }.entries[index];
+            if !entry.flags().contains(PageTableEntryFlags::VALID) {
+                return None;
+            }
+            if level == 2 || entry.is_leaf() {
+                return Some((entry, level));
+            }
+            table = entry.next_level();
+        }
+        None
+    }
+
+    pub fn leaves(&self) -> Leaves {
+        Leaves {
+            tables: [self.root_table(), ptr::null(), ptr::null()],
+            indices: [0; 3],
+            level: 0,
+            _mapping: PhantomData,
+        }
+    }
+
+    pub fn leaf_runs(&self) -> LeafRuns {
+        LeafRuns {
+            leaves: self.leaves(),
+            run: None,
+        }
+    }
+
+    /// Prints all leaf runs.
+    pub fn print(&self) {
+        for run in self.leaf_runs() {
+            println!("{}", run);
+        }
+    }
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (VirtualPageNumber, usize, PageTableEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let level = self.level;
+            let index = self.indices[level];
+            if index == 512 {
+                if level == 0 {
+                    return None;
+                }
+                self.level -= 1;
+                continue;
+            }
+            self.indices[level] += 1;
+            let entry = unsafe { &*self.tables[level] }.entries[index];
+            if !entry.flags().contains(PageTableEntryFlags::VALID) {
+                continue;
+            }
+            if level == 2 || entry.is_leaf() {
+                return Some((self.vpn(), level, entry));
+            }
+            self.tables[level + 1] = entry.next_level();
+            self.indices[level + 1] = 0;
+            self.level += 1;
+        }
+    }
+}
+
+impl<'a> Leaves<'a> {
+    /// Page mapped by the entry last returned.
+    fn vpn(&self) -> VirtualPageNumber {
+        let mut vpn = 0;
+        for l in 0..=self.level {
+            vpn |= (self.indices[l] - 1) << (9 * (2 - l));
+        }
+        if self.indices[0] > 256 {
+            // Sign-extend the upper half, as bits 39 and up of the virtual address.
+            vpn |= !0usize << 27;
+        }
+        VirtualPageNumber(vpn & (!0usize >> 12))
+    }
+}
+
+impl<'a> Iterator for LeafRuns<'a> {
+    type Item = LeafRun;
+
+    fn next(&mut self) -> Option<LeafRun> {
+        for (vpn, level, entry) in &mut self.leaves {
+            let next = LeafRun {
+                start: vpn,
+                pages: pages_per_entry(level),
+                ppn: entry.ppn(),
+                flags: entry.flags(),
+                level,
+            };
+            match self.run.take() {
+                Some(run)
+                    if run.level == level
+                        && run.flags == next.flags
+                        && run.start.0 + run.pages == vpn.0
+                        && run.ppn.0 + run.pages == next.ppn.0 =>
+                {
+                    self.run = Some(LeafRun {
+                        pages: run.pages + next.pages,
+                        ..run
+                    });
+                }
+                Some(run) => {
+                    self.run = Some(next);
+                    return Some(run);
+                }
+                None => self.run = Some(next),
+            }
+        }
+        self.run.take()
+    }
+}
+
+impl fmt::Display for LeafRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}-{:#x} -> {:#x} ",
+            self.start.start_address().0,
+            VirtualPageNumber(self.start.0 + self.pages)
+                .start_address()
+                .0,
+            self.ppn.start_address().0
+        )?;
+        let bits = [
+            (PageTableEntryFlags::READABLE, 'r'),
+            (PageTableEntryFlags::WRITABLE, 'w'),
+            (PageTableEntryFlags::EXECUTABLE, 'x'),
+            (PageTableEntryFlags::USER, 'u'),
+            (PageTableEntryFlags::GLOBAL, 'g'),
+            (PageTableEntryFlags::ACCESSED, 'a'),
+            (PageTableEntryFlags::DIRTY, 'd'),
+            (PageTableEntryFlags::COPY_ON_WRITE, 'c'),
+        ];
+        for &(flag, c) in bits.iter() {
+            write!(f, "{}", if self.flags.contains(flag) { c } else { '-' })?;
+        }
+        let size = ["1G", "2M", "4K"][self.level];
+        write!(
+            f,
+            " {} x {}",
+            self.pages / pages_per_entry(self.level),
+            size
+        )
+    }
+}
@@ -0,0 +1,246 @@
+//! Interactive debug monitor on the console.
+//!
+//! Started with `init=monitor`, or at any time by pressing Ctrl-] on the console. Commands only
+//! read kernel state, so the system keeps running underneath.
+
+use crate::allocator;
+use crate::console;
+use crate::log;
+use crate::memory::{boot_mapping, boot_page_pool, PAGES_PER_SET};
+use crate::process::{spawn, KernelTask, Thread, ThreadToken};
+use crate::scheduler::HardwareThread;
+use crate::smp;
+use crate::sync::{global_wait_queue, without_interrupts};
+use crate::timekeeping::{self, Utc};
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+/// Ctrl-].
+const HOTKEY: u8 = 0x1d;
+
+/// Time between checks for console input. The SBI console cannot interrupt on input.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Whether the monitor is running and owns console input.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+const COMMANDS: &[(&str, &str, fn(&HardwareThread, &ThreadToken, Option<&str>))] = &[
+    ("help", "list commands", cmd_help),
+    ("harts", "list harts and their current threads", cmd_harts),
+    ("runq", "[hart] list queued threads", cmd_runq),
+    ("heap", "show heap usage", cmd_heap),
+    ("pool", "show page pool usage", cmd_pool),
+    ("map", "show the kernel mapping", cmd_map),
+    (
+        "waiters",
+        "list threads in the global wait queue",
+        cmd_waiters,
+    ),
+    ("dmesg", "show retained log records", cmd_dmesg),
+    ("time", "show uptime and wall-clock time", cmd_time),
+];
+
+/// Waits for the hotkey and runs the monitor.
+struct HotkeyWatcher;
+
+impl KernelTask for HotkeyWatcher {
+    fn run(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) {
+        loop {
+            if ACTIVE.load(Ordering::SeqCst) {
+                sleep(ht, token);
+                continue;
+            }
+            if getchar(ht, token) == HOTKEY {
+                run(ht, token);
+            }
+        }
+    }
+}
+
+/// Starts a thread that runs the monitor when the hotkey is pressed.
+pub fn start_hotkey_watcher(ht: &HardwareThread, token: &ThreadToken) {
+    spawn(ht, Box::new(HotkeyWatcher), token).expect("monitor: cannot spawn hotkey watcher");
+}
+
+/// Runs the monitor until `exit`. Returns at once if it is already running.
+pub fn run(ht: &HardwareThread, token: &ThreadToken) {
+    if ACTIVE
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return;
+    }
+    println!("Kernel monitor. Type 'help' for commands.");
+    loop {
+        print!("monitor> ");
+        let line = read_line(ht, token);
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(x) => x,
+            None => continue,
+        };
+        if name == "exit" {
+            break;
+        }
+        match COMMANDS.iter().find(|x| x.0 == name) {
+            Some(&(_, _, f)) => f(ht, token, words.next()),
+            None => println!("Unknown command '{}'.", name),
+        }
+    }
+    ACTIVE.store(false, Ordering::SeqCst);
+}
+
+/// Waits for a byte from the console.
+fn getchar(ht: &HardwareThread, token: &ThreadToken) -> u8 {
+    loop {
+        match console::getchar() {
+            Some(x) => return x,
+            None => sleep(ht, token),
+        }
+    }
+}
+
+fn sleep(ht: &HardwareThread, token: &ThreadToken) {
+    ht.sleep_until(timekeeping::now_monotonic() + POLL_INTERVAL, token);
+}
+
+fn read_line(ht: &HardwareThread, token: &ThreadToken) -> String {
+    let mut line = String::new();
+    loop {
+        let c = getchar(ht, token);
+        match c {
+            b'\r' | b'\n' => {
+                println!();
+                return line;
+            }
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            0x20..=0x7e => {
+                line.push(c as char);
+                print!("{}", c as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn print_thread(th: &Thread) {
+    println!(
+        "  thread {} ({}{})",
+        th.id().0,
+        if th.raw_thread_state().was_user() {
+            "user"
+        } else {
+            "kernel"
+        },
+        if th.process.is_some() {
+            ", process"
+        } else {
+            ""
+        }
+    );
+}
+
+fn cmd_help(_: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    for &(name, help, _) in COMMANDS {
+        println!("{:8} {}", name, help);
+    }
+    println!("{:8} {}", "exit", "leave the monitor");
+}
+
+fn cmd_harts(ht: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    for hart in smp::harts() {
+        print!("Hart {}: {:?}", hart.id(), hart.status());
+        if let Some(target) = smp::hardware_thread(hart.id()) {
+            let mut queued = 0;
+            without_interrupts(ht, || {
+                target.policy().for_each_thread(&mut |_| queued += 1);
+            });
+            print!(
+                ", current thread {}, {} queued",
+                target.current_thread_id().0,
+                queued
+            );
+        }
+        println!();
+    }
+}
+
+fn cmd_runq(ht: &HardwareThread, _: &ThreadToken, arg: Option<&str>) {
+    let hart_id = match arg {
+        Some(x) => match x.parse() {
+            Ok(x) => Some(x),
+            Err(_) => {
+                println!("Bad hart ID '{}'.", x);
+                return;
+            }
+        },
+        None => None,
+    };
+    for hart in smp::harts() {
+        if hart_id.is_some() && hart_id != Some(hart.id()) {
+            continue;
+        }
+        let target = match smp::hardware_thread(hart.id()) {
+            Some(x) => x,
+            None => continue,
+        };
+        println!(
+            "Hart {}: current thread {}",
+            hart.id(),
+            target.current_thread_id().0
+        );
+        // Printing does not allocate.
+        without_interrupts(ht, || target.policy().for_each_thread(&mut print_thread));
+    }
+}
+
+fn cmd_heap(_: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    println!("Heap: {} bytes", allocator::heap_usage());
+}
+
+fn cmd_pool(_: &HardwareThread, token: &ThreadToken, _: Option<&str>) {
+    let usage = boot_page_pool().set_usage(token);
+    println!(
+        "Boot page pool: {} set(s) of {} pages",
+        usage.len(),
+        PAGES_PER_SET
+    );
+    for (i, used) in usage.iter().enumerate() {
+        println!("  set {}: {} used", i, used);
+    }
+}
+
+fn cmd_map(_: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    println!("Kernel mapping:");
+    boot_mapping().print();
+}
+
+fn cmd_waiters(ht: &HardwareThread, token: &ThreadToken, _: Option<&str>) {
+    global_wait_queue().for_each_waiter(ht, token, &mut |addr, th| {
+        println!(
+            "{:#x}: thread {}, hart {:?}",
+            addr.0,
+            th.id().0,
+            unsafe { th.raw_thread_state().hart.as_ref() }.map(|x| x.id().0)
+        );
+    });
+}
+
+fn cmd_dmesg(_: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    log::for_each_record(|record| log::print_record(record));
+}
+
+fn cmd_time(_: &HardwareThread, _: &ThreadToken, _: Option<&str>) {
+    let uptime = timekeeping::now_monotonic();
+    println!("Up {}.{:06} s", uptime.as_secs(), uptime.subsec_micros());
+    match timekeeping::now_realtime() {
+        Some(x) => println!("{}", Utc(x)),
+        None => println!("Wall-clock time unknown"),
+    }
+}
@@ -0,0 +1,141 @@
+//! ARP for IPv4 over Ethernet.
+//!
+//! Entries never expire. Packets to unresolved addresses wait for a reply for a while, and are
+//! dropped if none comes.
+
+use super::{now_ms, Ipv4Addr, Stack, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::drivers::net::MacAddr;
+use crate::process::ThreadToken;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+
+const PACKET_LEN: usize = 28;
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// How long packets wait for a reply.
+const RESOLVE_TIMEOUT_MS: u64 = 1000;
+
+/// Packets waiting at most.
+const MAX_PENDING: usize = 16;
+
+pub struct Cache {
+    entries: BTreeMap<Ipv4Addr, MacAddr>,
+    pending: Vec<Pending>,
+}
+
+/// An IP packet waiting for the address of its next hop.
+struct Pending {
+    next_hop: Ipv4Addr,
+    packet: Vec<u8>,
+    deadline: u64,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            entries: BTreeMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<MacAddr> {
+        self.entries.get(&addr).copied()
+    }
+}
+
+impl Stack {
+    pub(super) fn handle_arp(&mut self, packet: &[u8], token: &ThreadToken) {
+        if packet.len() < PACKET_LEN
+            || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+            || packet[4] != 6
+            || packet[5] != 4
+        {
+            return;
+        }
+        let op = u16::from_be_bytes([packet[6], packet[7]]);
+        let mut sender_mac = [0; 6];
+        sender_mac.copy_from_slice(&packet[8..14]);
+        let sender_mac = MacAddr(sender_mac);
+        let sender = Ipv4Addr([packet[14], packet[15], packet[16], packet[17]]);
+        let target = Ipv4Addr([packet[24], packet[25], packet[26], packet[27]]);
+
+        // Learn from packets for us, and refresh what we already know.
+        if target == self.address.addr || self.arp.entries.contains_key(&sender) {
+            self.arp.entries.insert(sender, sender_mac);
+            self.flush_pending(sender, sender_mac, token);
+        }
+        if op == OP_REQUEST && target == self.address.addr {
+            self.send_arp(OP_REPLY, sender_mac, sender, token);
+        }
+    }
+
+    /// Queues `packet` until `next_hop` is resolved, and asks for its address.
+    pub(super) fn resolve_and_send(
+        &mut self,
+        next_hop: Ipv4Addr,
+        packet: Vec<u8>,
+        token: &ThreadToken,
+    ) {
+        if self.arp.pending.len() >= MAX_PENDING {
+            trace!("Dropping packet to {:?}: too many unresolved.", next_hop);
+            return;
+        }
+        let asked = self.arp.pending.iter().any(|x| x.next_hop == next_hop);
+        self.arp.pending.push(Pending {
+            next_hop,
+            packet,
+            deadline: now_ms() + RESOLVE_TIMEOUT_MS,
+        });
+        if !asked {
+            self.send_arp(OP_REQUEST, MacAddr::BROADCAST, next_hop, token);
+        }
+    }
+
+    /// Drops packets that waited too long.
+    pub(super) fn poll_arp(&mut self, now: u64) {
+        self.arp.pending.retain(|x| {
+            if now >= x.deadline {
+                debug!("Cannot resolve {:?}.", x.next_hop);
+                return false;
+            }
+            true
+        });
+    }
+
+    fn flush_pending(&mut self, addr: Ipv4Addr, mac: MacAddr, token: &ThreadToken) {
+        let mut i = 0;
+        while i < self.arp.pending.len() {
+            if self.arp.pending[i].next_hop == addr {
+                let pending = self.arp.pending.remove(i);
+                self.send_ethernet(mac, ETHERTYPE_IPV4, &pending.packet, token);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn send_arp(&self, op: u16, target_mac: MacAddr, target: Ipv4Addr, token: &ThreadToken) {
+        let mac = match self.device {
+            Some(x) => x.mac(),
+            None => return,
+        };
+        let mut packet = Vec::with_capacity(PACKET_LEN);
+        packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet.extend_from_slice(&[6, 4]);
+        packet.extend_from_slice(&op.to_be_bytes());
+        packet.extend_from_slice(&mac.0);
+        packet.extend_from_slice(&self.address.addr.0);
+        // Requests leave the target hardware address empty.
+        packet.extend_from_slice(&if op == OP_REQUEST {
+            [0; 6]
+        } else {
+            target_mac.0
+        });
+        packet.extend_from_slice(&target.0);
+        self.send_ethernet(target_mac, ETHERTYPE_ARP, &packet, token);
+    }
+}
@@ -0,0 +1,106 @@
+//! ICMP echo.
+
+use super::ipv4::checksum;
+use super::{now_ms, polls, stack, wait_for_poll, Ipv4Addr, Stack};
+use crate::error::*;
+use crate::process::ThreadToken;
+use crate::scheduler::HardwareThread;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+
+pub const PROTOCOL: u8 = 1;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+const PING_TIMEOUT_MS: u64 = 2000;
+const PING_DATA: &[u8] = b"ping from the kernel";
+
+/// Echo requests we sent, by identifier, and whether they were answered.
+pub struct Echoes {
+    next_id: u16,
+    waiting: BTreeMap<u16, bool>,
+}
+
+impl Echoes {
+    pub fn new() -> Echoes {
+        Echoes {
+            next_id: 1,
+            waiting: BTreeMap::new(),
+        }
+    }
+}
+
+fn echo_message(kind: u8, id: u16, seq: u16, data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + data.len());
+    msg.extend_from_slice(&[kind, 0, 0, 0]);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(data);
+    let sum = checksum(&[&msg]);
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    msg
+}
+
+impl Stack {
+    pub(super) fn handle_icmp(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        msg: &[u8],
+        token: &ThreadToken,
+    ) {
+        if msg.len() < HEADER_LEN || checksum(&[msg]) != 0 {
+            return;
+        }
+        let id = u16::from_be_bytes([msg[4], msg[5]]);
+        let seq = u16::from_be_bytes([msg[6], msg[7]]);
+        match msg[0] {
+            ECHO_REQUEST if self.is_local(dst) => {
+                let reply = echo_message(ECHO_REPLY, id, seq, &msg[HEADER_LEN..]);
+                let _ = self.send_ipv4(dst, src, PROTOCOL, &reply, token);
+            }
+            ECHO_REPLY => {
+                if let Some(x) = self.icmp.waiting.get_mut(&id) {
+                    *x = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends an echo request to `addr` and waits for the reply.
+pub fn ping(addr: Ipv4Addr, ht: &HardwareThread, token: &ThreadToken) -> KernelResult<()> {
+    let stack = stack()?;
+    let id = {
+        let mut stack = stack.lock(token);
+        let id = stack.icmp.next_id;
+        stack.icmp.next_id = id.wrapping_add(1);
+        stack.icmp.waiting.insert(id, false);
+        let src = stack.source_for(addr);
+        let request = echo_message(ECHO_REQUEST, id, 0, PING_DATA);
+        if let Err(e) = stack.send_ipv4(src, addr, PROTOCOL, &request, token) {
+            stack.icmp.waiting.remove(&id);
+            return Err(e);
+        }
+        id
+    };
+    let deadline = now_ms() + PING_TIMEOUT_MS;
+    loop {
+        let since = polls();
+        {
+            let mut stack = stack.lock(token);
+            if stack.icmp.waiting[&id] {
+                stack.icmp.waiting.remove(&id);
+                return Ok(());
+            }
+            if now_ms() >= deadline {
+                stack.icmp.waiting.remove(&id);
+                return Err(KernelError::Timeout);
+            }
+        }
+        wait_for_poll(ht, since, token);
+    }
+}
@@ -0,0 +1,123 @@
+//! IPv4. Fragments are dropped, and packets are sent with DF set.
+
+use super::{icmp, tcp, udp, Ipv4Addr, Stack, ETHERTYPE_IPV4, LOOPBACK_QUEUE_LEN, MTU};
+use crate::drivers::net::MacAddr;
+use crate::error::*;
+use crate::process::ThreadToken;
+use alloc::vec::Vec;
+
+const HEADER_LEN: usize = 20;
+const TTL: u8 = 64;
+
+/// Internet checksum of the concatenation of `parts`. All but the last part must have an even
+/// length.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let high = pair[0] as u32;
+            let low = pair.get(1).copied().unwrap_or(0) as u32;
+            sum += high << 8 | low;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+/// Pseudo-header covered by the UDP and TCP checksums.
+pub fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> [u8; 12] {
+    let len = (len as u16).to_be_bytes();
+    [
+        src.0[0], src.0[1], src.0[2], src.0[3], dst.0[0], dst.0[1], dst.0[2], dst.0[3], 0,
+        protocol, len[0], len[1],
+    ]
+}
+
+impl Stack {
+    pub(super) fn handle_ipv4(&mut self, packet: &[u8], token: &ThreadToken) {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+            return;
+        }
+        if checksum(&[&packet[..header_len]]) != 0 {
+            trace!("Dropping IPv4 packet with bad checksum.");
+            return;
+        }
+        // More fragments, or a fragment offset.
+        if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+            trace!("Dropping IPv4 fragment.");
+            return;
+        }
+        let src = Ipv4Addr([packet[12], packet[13], packet[14], packet[15]]);
+        let dst = Ipv4Addr([packet[16], packet[17], packet[18], packet[19]]);
+        if !self.is_local(dst) && dst != Ipv4Addr::BROADCAST && dst != self.address.broadcast() {
+            return;
+        }
+        let payload = &packet[header_len..total_len];
+        match packet[9] {
+            icmp::PROTOCOL => self.handle_icmp(src, dst, payload, token),
+            tcp::PROTOCOL => self.handle_tcp(src, dst, payload, token),
+            udp::PROTOCOL => self.handle_udp(src, dst, payload),
+            x => trace!("Dropping IPv4 packet with protocol {}.", x),
+        }
+    }
+
+    /// Sends `payload` from `src` to `dst`. Packets to unresolved neighbors wait for ARP.
+    pub(super) fn send_ipv4(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let total_len = HEADER_LEN + payload.len();
+        if total_len > MTU {
+            return Err(KernelError::InvalidArgument);
+        }
+        let id = self.next_ip_id;
+        self.next_ip_id = id.wrapping_add(1);
+
+        let mut packet = Vec::with_capacity(total_len);
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&[0x40, 0, TTL, protocol, 0, 0]);
+        packet.extend_from_slice(&src.0);
+        packet.extend_from_slice(&dst.0);
+        let sum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        if self.is_local(dst) {
+            if self.loopback.len() >= LOOPBACK_QUEUE_LEN {
+                return Err(KernelError::Busy);
+            }
+            self.loopback.push_back(packet);
+            return Ok(());
+        }
+        if self.device.is_none() {
+            return Err(KernelError::NotSupported);
+        }
+        if dst == Ipv4Addr::BROADCAST || dst == self.address.broadcast() {
+            self.send_ethernet(MacAddr::BROADCAST, ETHERTYPE_IPV4, &packet, token);
+            return Ok(());
+        }
+        let next_hop = if self.address.contains(dst) {
+            dst
+        } else {
+            self.gateway
+        };
+        match self.arp.lookup(next_hop) {
+            Some(mac) => self.send_ethernet(mac, ETHERTYPE_IPV4, &packet, token),
+            None => self.resolve_and_send(next_hop, packet, token),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+//! A minimal IPv4 stack: ARP, ICMP echo, UDP and TCP.
+//!
+//! All protocol state is in one `Stack` behind a `YieldMutex`. A daemon thread polls the first
+//! network device and the loopback queue, and drives TCP timers. Packets to our own address or
+//! to 127.0.0.0/8 go through the loopback queue, so the stack works without a device.
+//!
+//! The sockets in `socket` are the kernel API.
+
+mod arp;
+mod icmp;
+mod ipv4;
+mod socket;
+mod tcp;
+mod udp;
+
+pub use icmp::ping;
+pub use socket::{TcpListener, TcpStream, UdpSocket};
+
+use crate::cmdline::{Param, ParamValue};
+use crate::drivers::net::{self as devices, MacAddr, NetDevice, MAX_FRAME};
+use crate::error::*;
+use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::process::{spawn, KernelTask, ThreadToken};
+use crate::scheduler::HardwareThread;
+use crate::sync::{global_wait_queue, Once, YieldMutex};
+use crate::timekeeping;
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Our address on the device's network. The default suits QEMU user-mode networking.
+pub static ADDRESS: Param<Ipv4Cidr> = Param::new(
+    "net.ip",
+    Ipv4Cidr {
+        addr: Ipv4Addr([10, 0, 2, 15]),
+        prefix_len: 24,
+    },
+    "IPv4 address and prefix length",
+);
+
+pub static GATEWAY: Param<Ipv4Addr> = Param::new(
+    "net.gateway",
+    Ipv4Addr([10, 0, 2, 2]),
+    "IPv4 default gateway",
+);
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER_LEN: usize = 14;
+const MIN_FRAME: usize = 60;
+
+/// Largest IP packet.
+const MTU: usize = MAX_FRAME - ETHERNET_HEADER_LEN;
+
+/// Loopback packets queued at most.
+const LOOPBACK_QUEUE_LEN: usize = 256;
+
+/// Time between polls of the device and the timers. The device does not interrupt on receive.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+static STACK: Once<YieldMutex<Stack>> = Once::new();
+
+/// Number of polls by the daemon so far. Blocking calls wait on it for the next poll.
+static POLLS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+/// An address with the prefix length of its network.
+#[derive(Copy, Clone)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Addr {
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+    pub const LOOPBACK: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    fn parse(s: &str) -> Option<Ipv4Addr> {
+        let mut addr = [0; 4];
+        let mut parts = s.split('.');
+        for x in addr.iter_mut() {
+            *x = parts.next()?.parse().ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Ipv4Addr(addr)),
+        }
+    }
+}
+
+impl fmt::Debug for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let x = &self.0;
+        write!(f, "{}.{}.{}.{}", x[0], x[1], x[2], x[3])
+    }
+}
+
+impl fmt::Debug for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl ParamValue for Ipv4Addr {
+    fn parse(s: &'static str) -> Option<Ipv4Addr> {
+        Ipv4Addr::parse(s)
+    }
+}
+
+impl ParamValue for Ipv4Cidr {
+    fn parse(s: &'static str) -> Option<Ipv4Cidr> {
+        let mut parts = s.splitn(2, '/');
+        let addr = Ipv4Addr::parse(parts.next()?)?;
+        let prefix_len = parts.next()?.parse().ok()?;
+        if prefix_len > 32 {
+            return None;
+        }
+        Some(Ipv4Cidr { addr, prefix_len })
+    }
+}
+
+impl Ipv4Cidr {
+    fn netmask(self) -> u32 {
+        match self.prefix_len {
+            0 => 0,
+            n => !0 << (32 - n),
+        }
+    }
+
+    fn contains(self, addr: Ipv4Addr) -> bool {
+        (self.addr.to_u32() ^ addr.to_u32()) & self.netmask() == 0
+    }
+
+    fn broadcast(self) -> Ipv4Addr {
+        Ipv4Addr((self.addr.to_u32() | !self.netmask()).to_be_bytes())
+    }
+}
+
+/// State of all protocols.
+struct Stack {
+    device: Option<&'static dyn NetDevice>,
+    address: Ipv4Cidr,
+    gateway: Ipv4Addr,
+
+    /// IP packets sent to ourselves.
+    loopback: VecDeque<Vec<u8>>,
+
+    arp: arp::Cache,
+    icmp: icmp::Echoes,
+    udp: udp::Sockets,
+    tcp: tcp::Connections,
+
+    next_ip_id: u16,
+    next_port: u16,
+}
+
+impl Stack {
+    /// Source address for packets to `dst`.
+    fn source_for(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        if dst.is_loopback() {
+            Ipv4Addr::LOOPBACK
+        } else {
+            self.address.addr
+        }
+    }
+
+    fn is_local(&self, addr: Ipv4Addr) -> bool {
+        addr.is_loopback() || addr == self.address.addr
+    }
+
+    /// Picks an unused ephemeral port.
+    fn allocate_port(&mut self) -> KernelResult<u16> {
+        const FIRST: u16 = 49152;
+        for _ in FIRST..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = match port {
+                u16::MAX => FIRST,
+                x => x + 1,
+            };
+            if !self.udp.is_bound(port) && !self.tcp.is_port_used(port) {
+                return Ok(port);
+            }
+        }
+        Err(KernelError::Busy)
+    }
+
+    fn send_ethernet(&self, dst: MacAddr, ethertype: u16, payload: &[u8], token: &ThreadToken) {
+        let device = match self.device {
+            Some(x) => x,
+            None => return,
+        };
+        let mut frame = Vec::with_capacity((ETHERNET_HEADER_LEN + payload.len()).max(MIN_FRAME));
+        frame.extend_from_slice(&dst.0);
+        frame.extend_from_slice(&device.mac().0);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if frame.len() < MIN_FRAME {
+            frame.resize(MIN_FRAME, 0);
+        }
+        // Like a full wire, a full device drops the frame. Protocols above recover.
+        if let Err(e) = device.transmit(&frame, token) {
+            trace!("Dropping frame: {:?}", e);
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8], token: &ThreadToken) {
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return;
+        }
+        let mut dst = [0; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        let dst = MacAddr(dst);
+        let mac = match self.device {
+            Some(x) => x.mac(),
+            None => return,
+        };
+        if dst != mac && dst != MacAddr::BROADCAST {
+            return;
+        }
+        let payload = &frame[ETHERNET_HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.handle_arp(payload, token),
+            ETHERTYPE_IPV4 => self.handle_ipv4(payload, token),
+            _ => {}
+        }
+    }
+
+    /// Handles queued loopback packets, device frames and timers.
+    fn poll(&mut self, frame: &mut [u8], token: &ThreadToken) {
+        if let Some(device) = self.device {
+            while let Some(len) = device.receive(frame, token) {
+                self.handle_frame(&frame[..len], token);
+            }
+        }
+        // Packets queued while handling these wait for the next poll.
+        for _ in 0..self.loopback.len() {
+            let packet = self.loopback.pop_front().unwrap();
+            self.handle_ipv4(&packet, token);
+        }
+        let now = now_ms();
+        self.poll_arp(now);
+        self.poll_tcp(now, token);
+    }
+}
+
+fn stack() -> KernelResult<&'static YieldMutex<Stack>> {
+    STACK.r#try().ok_or(KernelError::NotSupported)
+}
+
+fn polls_addr() -> PhysicalAddress {
+    VirtualAddress::from(&POLLS)
+        .to_phys()
+        .expect("net: bad POLLS vaddr")
+}
+
+/// Returns the number of polls so far, to be read before checking for progress.
+fn polls() -> u64 {
+    POLLS.load(Ordering::SeqCst)
+}
+
+/// Blocks until the daemon polls again, unless it already did since `polls()` returned `since`.
+fn wait_for_poll(ht: &HardwareThread, since: u64, token: &ThreadToken) {
+    global_wait_queue().wait(ht, polls_addr(), || polls() == since, token);
+}
+
+/// Milliseconds since boot.
+fn now_ms() -> u64 {
+    timekeeping::now_monotonic().as_millis() as u64
+}
+
+struct NetDaemon;
+
+impl KernelTask for NetDaemon {
+    fn run(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) {
+        let stack = stack().unwrap();
+        let mut frame = vec![0; MAX_FRAME];
+        loop {
+            let next = timekeeping::now_monotonic() + POLL_INTERVAL;
+            let mut stack = stack.lock(token);
+            stack.poll(&mut frame, token);
+            let pending = !stack.loopback.is_empty();
+            drop(stack);
+            POLLS.fetch_add(1, Ordering::SeqCst);
+            global_wait_queue().wake_all(ht, polls_addr(), token);
+            if pending {
+                // Packets queued while polling. Handle them without delay.
+                ht.do_yield(token);
+            } else {
+                ht.sleep_until(next, token);
+            }
+        }
+    }
+}
+
+/// Sets up the stack on the first network device, if any, and starts the daemon.
+///
+/// Must be called from a thread after `drivers::start`.
+pub fn start(ht: &HardwareThread, token: &ThreadToken) {
+    let device = devices::devices().first().copied();
+    let address = ADDRESS.get();
+    match device {
+        Some(x) => info!("{}: {:?}, gateway {:?}.", x.name(), address, GATEWAY.get()),
+        None => info!("No network device. Only loopback is available."),
+    }
+    STACK.call_once(|| {
+        YieldMutex::new(Stack {
+            device,
+            address,
+            gateway: GATEWAY.get(),
+            loopback: VecDeque::new(),
+            arp: arp::Cache::new(),
+            icmp: icmp::Echoes::new(),
+            udp: udp::Sockets::new(),
+            tcp: tcp::Connections::new(),
+            next_ip_id: 0,
+            next_port: 49152,
+        })
+    });
+    spawn(ht, Box::new(NetDaemon), token).expect("net: cannot spawn daemon");
+}
@@ -0,0 +1,174 @@
+//! Kernel sockets. Blocking calls wait for the daemon to poll until it makes progress.
+
+use super::tcp::{ConnId, State};
+use super::{polls, stack, wait_for_poll, Ipv4Addr};
+use crate::error::*;
+use crate::process::ThreadToken;
+use crate::scheduler::HardwareThread;
+
+pub struct UdpSocket {
+    port: u16,
+}
+
+pub struct TcpListener {
+    port: u16,
+}
+
+pub struct TcpStream {
+    id: ConnId,
+}
+
+impl UdpSocket {
+    /// Binds to `port`, or to an unused ephemeral port if it is 0.
+    pub fn bind(port: u16, token: &ThreadToken) -> KernelResult<UdpSocket> {
+        let mut stack = stack()?.lock(token);
+        let port = match port {
+            0 => stack.allocate_port()?,
+            x => x,
+        };
+        stack.udp.bind(port)?;
+        Ok(UdpSocket { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(
+        &self,
+        data: &[u8],
+        addr: Ipv4Addr,
+        port: u16,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        stack()?
+            .lock(token)
+            .send_udp(self.port, addr, port, data, token)
+    }
+
+    /// Waits for a datagram and copies it into `buf`, truncated if needed. Returns the copied
+    /// length and the sender.
+    pub fn recv_from(
+        &self,
+        buf: &mut [u8],
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<(usize, Ipv4Addr, u16)> {
+        let stack = stack()?;
+        loop {
+            let since = polls();
+            if let Some(datagram) = stack.lock(token).udp.pop(self.port) {
+                let n = buf.len().min(datagram.data.len());
+                buf[..n].copy_from_slice(&datagram.data[..n]);
+                return Ok((n, datagram.src, datagram.src_port));
+            }
+            wait_for_poll(ht, since, token);
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let token = unsafe { ThreadToken::assume_thread_context() };
+        stack().unwrap().lock(token).udp.unbind(self.port);
+    }
+}
+
+impl TcpListener {
+    pub fn bind(port: u16, token: &ThreadToken) -> KernelResult<TcpListener> {
+        stack()?.lock(token).tcp_listen(port)?;
+        Ok(TcpListener { port })
+    }
+
+    /// Waits for an established connection.
+    pub fn accept(&self, ht: &HardwareThread, token: &ThreadToken) -> KernelResult<TcpStream> {
+        let stack = stack()?;
+        loop {
+            let since = polls();
+            if let Some(id) = stack.lock(token).tcp_accept(self.port) {
+                return Ok(TcpStream { id });
+            }
+            wait_for_poll(ht, since, token);
+        }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let token = unsafe { ThreadToken::assume_thread_context() };
+        stack().unwrap().lock(token).tcp_unlisten(self.port, token);
+    }
+}
+
+impl TcpStream {
+    /// Connects to `port` on `addr`, and waits until the connection is established.
+    pub fn connect(
+        addr: Ipv4Addr,
+        port: u16,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<TcpStream> {
+        let stack = stack()?;
+        let stream = TcpStream {
+            id: stack.lock(token).tcp_connect(addr, port, token)?,
+        };
+        loop {
+            let since = polls();
+            match stack.lock(token).tcp_state(stream.id) {
+                (_, true) => return Err(KernelError::IoError),
+                (State::SynSent, _) => {}
+                (State::Closed, _) => return Err(KernelError::IoError),
+                _ => return Ok(stream),
+            }
+            wait_for_poll(ht, since, token);
+        }
+    }
+
+    /// Waits for data and reads it into `buf`. Returns 0 once the peer closed the connection.
+    pub fn read(
+        &self,
+        buf: &mut [u8],
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<usize> {
+        let stack = stack()?;
+        loop {
+            let since = polls();
+            if let Some(n) = stack.lock(token).tcp_read(self.id, buf, token)? {
+                return Ok(n);
+            }
+            wait_for_poll(ht, since, token);
+        }
+    }
+
+    /// Queues all of `data` for sending, waiting for buffer space as needed.
+    pub fn write(&self, data: &[u8], ht: &HardwareThread, token: &ThreadToken) -> KernelResult<()> {
+        let stack = stack()?;
+        let mut written = 0;
+        while written < data.len() {
+            let since = polls();
+            // The lock must not be held while waiting.
+            let result = stack
+                .lock(token)
+                .tcp_write(self.id, &data[written..], token)?;
+            match result {
+                Some(n) => written += n,
+                None => wait_for_poll(ht, since, token),
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the connection for sending. The peer reads the end of the stream.
+    pub fn shutdown(&self, token: &ThreadToken) -> KernelResult<()> {
+        stack()?.lock(token).tcp_shutdown(self.id, token);
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let token = unsafe { ThreadToken::assume_thread_context() };
+        stack().unwrap().lock(token).tcp_release(self.id, token);
+    }
+}
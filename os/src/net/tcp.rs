@@ -0,0 +1,735 @@
+//! TCP.
+//!
+//! Kept basic: out-of-order segments are dropped and acknowledged again, there is no congestion
+//! control, and unacknowledged data is retransmitted go-back-N after a fixed timeout.
+
+use super::ipv4::{checksum, pseudo_header};
+use super::{now_ms, Ipv4Addr, Stack};
+use crate::error::*;
+use crate::process::ThreadToken;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use riscv::register::time;
+
+pub const PROTOCOL: u8 = 6;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const HEADER_LEN: usize = 20;
+const MSS: usize = 1460;
+
+/// Size of the send and receive buffers of a connection.
+const BUFFER_SIZE: usize = 16384;
+
+/// Connections waiting in an accept queue at most.
+const BACKLOG: usize = 16;
+
+const RETRANSMIT_MS: u64 = 500;
+const MAX_RETRIES: u32 = 8;
+
+/// Much shorter than 2 MSL, as we only talk to nearby hosts.
+const TIME_WAIT_MS: u64 = 1000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ConnId(u32);
+
+pub struct Connections {
+    /// Accept queues of listening ports.
+    listeners: BTreeMap<u16, VecDeque<ConnId>>,
+
+    conns: BTreeMap<ConnId, Tcb>,
+    next_id: u32,
+}
+
+/// State of a connection.
+struct Tcb {
+    local: Ipv4Addr,
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+    state: State,
+
+    /// Listening port the connection will be accepted from, until it is.
+    listener: Option<u16>,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    rcv_nxt: u32,
+
+    /// Data from `snd_una` on: first sent but unacknowledged, then unsent.
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+
+    /// A FIN follows the data in `send_buf`.
+    fin_queued: bool,
+    fin_sent: bool,
+
+    /// The peer sends no more data.
+    peer_fin: bool,
+
+    /// Reset by the peer, or timed out.
+    reset: bool,
+
+    /// No socket refers to the connection, so it is removed once closed.
+    released: bool,
+
+    /// Deadline for retransmission, or the end of TIME-WAIT.
+    timer: Option<u64>,
+    retries: u32,
+}
+
+/// A received segment.
+struct Incoming<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u32,
+    payload: &'a [u8],
+}
+
+/// A segment to send.
+struct Segment {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: Vec<u8>,
+}
+
+/// Clock-driven, as in RFC 793.
+fn initial_sequence() -> u32 {
+    (time::read() >> 2) as u32
+}
+
+impl Segment {
+    fn encode(&self) -> Vec<u8> {
+        // Announce our MSS with SYN.
+        let options: &[u8] = if self.flags & SYN != 0 {
+            &[2, 4, (MSS >> 8) as u8, MSS as u8]
+        } else {
+            &[]
+        };
+        let header_len = HEADER_LEN + options.len();
+        let mut bytes = Vec::with_capacity(header_len + self.payload.len());
+        bytes.extend_from_slice(&self.src_port.to_be_bytes());
+        bytes.extend_from_slice(&self.dst_port.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+        bytes.extend_from_slice(&[((header_len / 4) << 4) as u8, self.flags]);
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend_from_slice(options);
+        bytes.extend_from_slice(&self.payload);
+        let sum = checksum(&[
+            &pseudo_header(self.src, self.dst, PROTOCOL, bytes.len()),
+            &bytes,
+        ]);
+        bytes[16..18].copy_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+}
+
+impl Tcb {
+    fn new(
+        local: Ipv4Addr,
+        local_port: u16,
+        remote: Ipv4Addr,
+        remote_port: u16,
+        state: State,
+    ) -> Tcb {
+        let iss = initial_sequence();
+        Tcb {
+            local,
+            local_port,
+            remote,
+            remote_port,
+            state,
+            listener: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            peer_fin: false,
+            reset: false,
+            released: false,
+            timer: None,
+            retries: 0,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv_buf.len()) as u16
+    }
+
+    fn segment(&self, seq: u32, flags: u8, payload: Vec<u8>) -> Segment {
+        Segment {
+            src: self.local,
+            dst: self.remote,
+            src_port: self.local_port,
+            dst_port: self.remote_port,
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.window(),
+            payload,
+        }
+    }
+
+    fn send_ack(&self, out: &mut Vec<Segment>) {
+        out.push(self.segment(self.snd_nxt, ACK, Vec::new()));
+    }
+
+    fn abort(&mut self) {
+        self.state = State::Closed;
+        self.reset = true;
+        self.timer = None;
+        self.send_buf.clear();
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.timer = Some(now + TIME_WAIT_MS);
+    }
+
+    fn can_receive(&self) -> bool {
+        if self.peer_fin {
+            return false;
+        }
+        match self.state {
+            State::Established | State::FinWait1 | State::FinWait2 => true,
+            _ => false,
+        }
+    }
+
+    /// Sends what the state, the buffer and the peer's window allow.
+    fn output(&mut self, now: u64, out: &mut Vec<Segment>) {
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                if self.snd_nxt == self.iss {
+                    let flags = match self.state {
+                        State::SynSent => SYN,
+                        _ => SYN | ACK,
+                    };
+                    out.push(self.segment(self.iss, flags, Vec::new()));
+                    self.snd_nxt = self.iss.wrapping_add(1);
+                }
+            }
+            State::Established
+            | State::CloseWait
+            | State::FinWait1
+            | State::Closing
+            | State::LastAck => {
+                loop {
+                    let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                    if offset >= self.send_buf.len() {
+                        break;
+                    }
+                    // Probe a closed window one byte at a time.
+                    let window = match offset {
+                        0 => (self.snd_wnd as usize).max(1),
+                        _ => self.snd_wnd as usize,
+                    };
+                    if offset >= window {
+                        break;
+                    }
+                    let n = (self.send_buf.len() - offset).min(MSS).min(window - offset);
+                    let payload = self.send_buf.iter().skip(offset).take(n).copied().collect();
+                    out.push(self.segment(self.snd_nxt, ACK | PSH, payload));
+                    self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+                }
+                let all_sent =
+                    self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.send_buf.len();
+                if self.fin_queued && !self.fin_sent && all_sent {
+                    out.push(self.segment(self.snd_nxt, FIN | ACK, Vec::new()));
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                }
+            }
+            _ => {}
+        }
+        if self.snd_nxt != self.snd_una && self.timer.is_none() {
+            self.timer = Some(now + RETRANSMIT_MS);
+        }
+    }
+
+    fn input(&mut self, seg: &Incoming, now: u64, out: &mut Vec<Segment>) {
+        if seg.flags & RST != 0 {
+            // Only trust resets that fit the connection.
+            let valid = match self.state {
+                State::SynSent => seg.flags & ACK != 0 && seg.ack == self.snd_nxt,
+                _ => seg.seq == self.rcv_nxt,
+            };
+            if valid {
+                self.abort();
+            }
+            return;
+        }
+        match self.state {
+            State::SynSent => {
+                if seg.flags & (SYN | ACK) == SYN | ACK && seg.ack == self.snd_nxt {
+                    self.rcv_nxt = seg.seq.wrapping_add(1);
+                    self.snd_una = seg.ack;
+                    self.snd_wnd = seg.window;
+                    self.state = State::Established;
+                    self.timer = None;
+                    self.retries = 0;
+                    self.send_ack(out);
+                }
+                return;
+            }
+            State::SynReceived => {
+                if seg.flags & SYN != 0 {
+                    // Our SYN-ACK was lost. `output` sends it again.
+                    self.snd_nxt = self.iss;
+                    return;
+                }
+                if seg.flags & ACK == 0 || seg.ack != self.iss.wrapping_add(1) {
+                    return;
+                }
+                self.snd_una = seg.ack;
+                self.snd_wnd = seg.window;
+                self.state = State::Established;
+                self.timer = None;
+                self.retries = 0;
+            }
+            State::Closed => return,
+            _ => {
+                if seg.flags & SYN != 0 {
+                    // Our ACK of the SYN-ACK was lost.
+                    self.send_ack(out);
+                    return;
+                }
+            }
+        }
+        if seg.flags & ACK != 0 {
+            self.handle_ack(seg, now);
+        }
+        if seg.payload.is_empty() && seg.flags & FIN == 0 {
+            return;
+        }
+        if seg.seq == self.rcv_nxt && self.can_receive() {
+            let n = seg.payload.len().min(BUFFER_SIZE - self.recv_buf.len());
+            self.recv_buf.extend(&seg.payload[..n]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+            if seg.flags & FIN != 0 && n == seg.payload.len() {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.peer_fin = true;
+                match self.state {
+                    State::Established => self.state = State::CloseWait,
+                    State::FinWait1 => self.state = State::Closing,
+                    State::FinWait2 => self.enter_time_wait(now),
+                    _ => {}
+                }
+            }
+        }
+        // Acknowledge anyway, so the peer resends what we dropped.
+        self.send_ack(out);
+    }
+
+    fn handle_ack(&mut self, seg: &Incoming, now: u64) {
+        let acked = seg.ack.wrapping_sub(self.snd_una);
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        if acked > in_flight {
+            return;
+        }
+        self.snd_wnd = seg.window;
+        if acked == 0 {
+            return;
+        }
+        let data = (acked as usize).min(self.send_buf.len());
+        self.send_buf.drain(..data);
+        self.snd_una = seg.ack;
+        self.retries = 0;
+        self.timer = if self.snd_una == self.snd_nxt {
+            None
+        } else {
+            Some(now + RETRANSMIT_MS)
+        };
+        if self.fin_sent && self.snd_una == self.snd_nxt {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+    }
+
+    fn on_timer(&mut self, now: u64, out: &mut Vec<Segment>) {
+        match self.timer {
+            Some(x) if now >= x => self.timer = None,
+            _ => return,
+        }
+        if self.state == State::TimeWait {
+            self.state = State::Closed;
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            debug!(
+                "Connection to {:?}:{} timed out.",
+                self.remote, self.remote_port
+            );
+            self.abort();
+            return;
+        }
+        // Go back to the first unacknowledged byte. The timer is only set while something is.
+        self.snd_nxt = self.snd_una;
+        self.fin_sent = false;
+        self.output(now, out);
+    }
+
+    /// Queues a FIN after the buffered data.
+    fn close(&mut self) {
+        match self.state {
+            State::SynSent | State::SynReceived => self.state = State::Closed,
+            State::Established => {
+                self.fin_queued = true;
+                self.state = State::FinWait1;
+            }
+            State::CloseWait => {
+                self.fin_queued = true;
+                self.state = State::LastAck;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections {
+            listeners: BTreeMap::new(),
+            conns: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn is_port_used(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port) || self.conns.values().any(|x| x.local_port == port)
+    }
+
+    fn find(
+        &self,
+        local: Ipv4Addr,
+        local_port: u16,
+        remote: Ipv4Addr,
+        remote_port: u16,
+    ) -> Option<ConnId> {
+        self.conns
+            .iter()
+            .find(|(_, x)| {
+                x.local_port == local_port
+                    && x.remote_port == remote_port
+                    && x.local == local
+                    && x.remote == remote
+            })
+            .map(|(&id, _)| id)
+    }
+
+    fn insert(&mut self, tcb: Tcb) -> ConnId {
+        let id = ConnId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.conns.insert(id, tcb);
+        id
+    }
+
+    fn get(&mut self, id: ConnId) -> &mut Tcb {
+        self.conns.get_mut(&id).expect("tcp: unknown connection")
+    }
+}
+
+/// Reply to a segment that belongs to no connection.
+fn reset_for(src: Ipv4Addr, dst: Ipv4Addr, seg: &Incoming) -> Segment {
+    let (seq, ack, flags) = if seg.flags & ACK != 0 {
+        (seg.ack, 0, RST)
+    } else {
+        let len = seg.payload.len() as u32
+            + (seg.flags & SYN != 0) as u32
+            + (seg.flags & FIN != 0) as u32;
+        (0, seg.seq.wrapping_add(len), RST | ACK)
+    };
+    Segment {
+        src: dst,
+        dst: src,
+        src_port: seg.dst_port,
+        dst_port: seg.src_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        payload: Vec::new(),
+    }
+}
+
+impl Stack {
+    pub(super) fn handle_tcp(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        segment: &[u8],
+        token: &ThreadToken,
+    ) {
+        if segment.len() < HEADER_LEN {
+            return;
+        }
+        if checksum(&[&pseudo_header(src, dst, PROTOCOL, segment.len()), segment]) != 0 {
+            trace!("Dropping TCP segment with bad checksum.");
+            return;
+        }
+        let header_len = (segment[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > segment.len() {
+            return;
+        }
+        let word = |i: usize| {
+            u32::from_be_bytes([segment[i], segment[i + 1], segment[i + 2], segment[i + 3]])
+        };
+        let seg = Incoming {
+            src_port: u16::from_be_bytes([segment[0], segment[1]]),
+            dst_port: u16::from_be_bytes([segment[2], segment[3]]),
+            seq: word(4),
+            ack: word(8),
+            flags: segment[13],
+            window: u16::from_be_bytes([segment[14], segment[15]]) as u32,
+            payload: &segment[header_len..],
+        };
+
+        let now = now_ms();
+        let mut out = Vec::new();
+        let tcp = &mut self.tcp;
+        match tcp.find(dst, seg.dst_port, src, seg.src_port) {
+            Some(id) => {
+                let tcb = tcp.conns.get_mut(&id).unwrap();
+                tcb.input(&seg, now, &mut out);
+                tcb.output(now, &mut out);
+                if let Some(port) = tcb.listener {
+                    if tcb.state != State::SynReceived {
+                        tcb.listener = None;
+                        match tcp.listeners.get_mut(&port) {
+                            Some(queue) if tcb.state != State::Closed => queue.push_back(id),
+                            _ => {
+                                tcb.close();
+                                tcb.output(now, &mut out);
+                            }
+                        }
+                    }
+                }
+            }
+            None if seg.flags & (SYN | ACK | RST) == SYN => {
+                let backlog = tcp.listeners.get(&seg.dst_port).map(|x| x.len() < BACKLOG);
+                match backlog {
+                    Some(true) => {
+                        let mut tcb =
+                            Tcb::new(dst, seg.dst_port, src, seg.src_port, State::SynReceived);
+                        tcb.rcv_nxt = seg.seq.wrapping_add(1);
+                        tcb.snd_wnd = seg.window;
+                        tcb.listener = Some(seg.dst_port);
+                        // Until accepted.
+                        tcb.released = true;
+                        tcb.output(now, &mut out);
+                        tcp.insert(tcb);
+                    }
+                    // The peer retries.
+                    Some(false) => {}
+                    None => out.push(reset_for(src, dst, &seg)),
+                }
+            }
+            None => {
+                if seg.flags & RST == 0 {
+                    out.push(reset_for(src, dst, &seg));
+                }
+            }
+        }
+        self.send_segments(out, token);
+    }
+
+    /// Drives timers, and removes closed connections no socket refers to.
+    pub(super) fn poll_tcp(&mut self, now: u64, token: &ThreadToken) {
+        let mut out = Vec::new();
+        let tcp = &mut self.tcp;
+        for tcb in tcp.conns.values_mut() {
+            tcb.on_timer(now, &mut out);
+        }
+        tcp.conns
+            .retain(|_, x| !(x.released && x.state == State::Closed));
+        // Including connections reset while waiting to be accepted.
+        let conns = &tcp.conns;
+        for queue in tcp.listeners.values_mut() {
+            queue.retain(|id| conns.contains_key(id));
+        }
+        self.send_segments(out, token);
+    }
+
+    fn send_segments(&mut self, out: Vec<Segment>, token: &ThreadToken) {
+        for seg in out {
+            // Lost segments are retransmitted.
+            let _ = self.send_ipv4(seg.src, seg.dst, PROTOCOL, &seg.encode(), token);
+        }
+    }
+
+    pub(super) fn tcp_listen(&mut self, port: u16) -> KernelResult<()> {
+        if port == 0 || self.tcp.is_port_used(port) {
+            return Err(KernelError::Busy);
+        }
+        self.tcp.listeners.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    /// Stops listening, and closes connections that were not accepted.
+    pub(super) fn tcp_unlisten(&mut self, port: u16, token: &ThreadToken) {
+        if let Some(queue) = self.tcp.listeners.remove(&port) {
+            for id in queue {
+                if self.tcp.conns.contains_key(&id) {
+                    self.tcp_release(id, token);
+                }
+            }
+        }
+    }
+
+    /// Takes the next queued connection. Skips those closed meanwhile, which stay released.
+    pub(super) fn tcp_accept(&mut self, port: u16) -> Option<ConnId> {
+        loop {
+            let id = self.tcp.listeners.get_mut(&port)?.pop_front()?;
+            match self.tcp.conns.get_mut(&id) {
+                Some(tcb) if tcb.state != State::Closed => {
+                    tcb.released = false;
+                    return Some(id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub(super) fn tcp_connect(
+        &mut self,
+        remote: Ipv4Addr,
+        remote_port: u16,
+        token: &ThreadToken,
+    ) -> KernelResult<ConnId> {
+        if !self.is_local(remote) && self.device.is_none() {
+            return Err(KernelError::NotSupported);
+        }
+        let local = self.source_for(remote);
+        let local_port = self.allocate_port()?;
+        let mut tcb = Tcb::new(local, local_port, remote, remote_port, State::SynSent);
+        let mut out = Vec::new();
+        tcb.output(now_ms(), &mut out);
+        let id = self.tcp.insert(tcb);
+        self.send_segments(out, token);
+        Ok(id)
+    }
+
+    /// Returns the state of a connection, and whether it was reset or timed out.
+    pub(super) fn tcp_state(&mut self, id: ConnId) -> (State, bool) {
+        let tcb = self.tcp.get(id);
+        (tcb.state, tcb.reset)
+    }
+
+    /// Reads buffered data. Returns 0 at the end of the stream, and `None` if there is nothing
+    /// to read yet.
+    pub(super) fn tcp_read(
+        &mut self,
+        id: ConnId,
+        buf: &mut [u8],
+        token: &ThreadToken,
+    ) -> KernelResult<Option<usize>> {
+        let tcb = self.tcp.get(id);
+        if tcb.recv_buf.is_empty() {
+            if tcb.reset {
+                return Err(KernelError::IoError);
+            }
+            if tcb.peer_fin || tcb.state == State::Closed {
+                return Ok(Some(0));
+            }
+            return Ok(None);
+        }
+        let was_small = (tcb.window() as usize) < MSS;
+        let n = buf.len().min(tcb.recv_buf.len());
+        for (x, y) in buf.iter_mut().zip(tcb.recv_buf.drain(..n)) {
+            *x = y;
+        }
+        // Tell the peer about the space, as it may be waiting for it.
+        let mut out = Vec::new();
+        if was_small && tcb.window() as usize >= MSS {
+            tcb.send_ack(&mut out);
+        }
+        self.send_segments(out, token);
+        Ok(Some(n))
+    }
+
+    /// Buffers data for sending. Returns how much, or `None` if the buffer is full or the
+    /// connection is not established yet.
+    pub(super) fn tcp_write(
+        &mut self,
+        id: ConnId,
+        data: &[u8],
+        token: &ThreadToken,
+    ) -> KernelResult<Option<usize>> {
+        let tcb = self.tcp.get(id);
+        if tcb.reset {
+            return Err(KernelError::IoError);
+        }
+        match tcb.state {
+            State::Established | State::CloseWait => {}
+            State::SynSent | State::SynReceived => return Ok(None),
+            // Closed for sending.
+            _ => return Err(KernelError::InvalidArgument),
+        }
+        let n = data.len().min(BUFFER_SIZE - tcb.send_buf.len());
+        if n == 0 {
+            return Ok(None);
+        }
+        tcb.send_buf.extend(&data[..n]);
+        let mut out = Vec::new();
+        tcb.output(now_ms(), &mut out);
+        self.send_segments(out, token);
+        Ok(Some(n))
+    }
+
+    /// Closes the connection for sending.
+    pub(super) fn tcp_shutdown(&mut self, id: ConnId, token: &ThreadToken) {
+        let tcb = self.tcp.get(id);
+        tcb.close();
+        let mut out = Vec::new();
+        tcb.output(now_ms(), &mut out);
+        self.send_segments(out, token);
+    }
+
+    /// Closes the connection, and lets it be removed once the peer is done.
+    pub(super) fn tcp_release(&mut self, id: ConnId, token: &ThreadToken) {
+        self.tcp_shutdown(id, token);
+        self.tcp.get(id).released = true;
+    }
+}
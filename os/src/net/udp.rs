@@ -0,0 +1,117 @@
+//! UDP.
+
+use super::ipv4::{checksum, pseudo_header};
+use super::{Ipv4Addr, Stack};
+use crate::error::*;
+use crate::process::ThreadToken;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+
+pub const PROTOCOL: u8 = 17;
+
+const HEADER_LEN: usize = 8;
+
+/// Datagrams queued per socket at most. Later ones are dropped.
+const QUEUE_LEN: usize = 64;
+
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+/// Receive queues of bound ports.
+pub struct Sockets {
+    bound: BTreeMap<u16, VecDeque<Datagram>>,
+}
+
+impl Sockets {
+    pub fn new() -> Sockets {
+        Sockets {
+            bound: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.bound.contains_key(&port)
+    }
+
+    pub fn bind(&mut self, port: u16) -> KernelResult<()> {
+        if port == 0 || self.is_bound(port) {
+            return Err(KernelError::Busy);
+        }
+        self.bound.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, port: u16) {
+        self.bound.remove(&port);
+    }
+
+    pub fn pop(&mut self, port: u16) -> Option<Datagram> {
+        self.bound.get_mut(&port)?.pop_front()
+    }
+}
+
+impl Stack {
+    pub(super) fn handle_udp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) {
+        if datagram.len() < HEADER_LEN {
+            return;
+        }
+        let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+        let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+        let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        if len < HEADER_LEN || len > datagram.len() {
+            return;
+        }
+        let datagram = &datagram[..len];
+        // Zero means the sender did not compute a checksum.
+        if datagram[6..8] != [0, 0]
+            && checksum(&[&pseudo_header(src, dst, PROTOCOL, len), datagram]) != 0
+        {
+            trace!("Dropping UDP datagram with bad checksum.");
+            return;
+        }
+        let queue = match self.udp.bound.get_mut(&dst_port) {
+            Some(x) => x,
+            None => return,
+        };
+        if queue.len() >= QUEUE_LEN {
+            trace!("Dropping UDP datagram to full port {}.", dst_port);
+            return;
+        }
+        queue.push_back(Datagram {
+            src,
+            src_port,
+            data: datagram[HEADER_LEN..].to_vec(),
+        });
+    }
+
+    pub(super) fn send_udp(
+        &mut self,
+        src_port: u16,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        data: &[u8],
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let src = self.source_for(dst);
+        let len = HEADER_LEN + data.len();
+        if len > u16::MAX as usize {
+            return Err(KernelError::InvalidArgument);
+        }
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&src_port.to_be_bytes());
+        datagram.extend_from_slice(&dst_port.to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let sum = match checksum(&[&pseudo_header(src, dst, PROTOCOL, len), &datagram]) {
+            0 => 0xffff,
+            x => x,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        self.send_ipv4(src, dst, PROTOCOL, &datagram, token)
+    }
+}
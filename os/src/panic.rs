@@ -1,8 +1,27 @@
-use crate::sbi;
+use crate::console;
+use crate::init;
+use crate::log;
+use crate::power::{self, ExitStatus};
+use crate::smp;
 use core::panic::PanicInfo;
 
+// Exit status codes reported to the host.
+const PANIC_EXIT_CODE: u16 = 1;
+const TEST_FAILURE_EXIT_CODE: u16 = 2;
+
 #[panic_handler]
 fn on_panic(info: &PanicInfo) -> ! {
+    smp::ipi::stop_others();
+    console::panic_steal();
+    log::panic_flush();
     println!("\x1b[1;31mpanic: '{:?}'\x1b[0m", info);
-    sbi::shutdown()
+    let code = match init::current_test() {
+        Some(name) => {
+            println!("\x1b[1;31mtest {} failed\x1b[0m", name);
+            TEST_FAILURE_EXIT_CODE
+        }
+        None => PANIC_EXIT_CODE,
+    };
+    console::flush();
+    power::exit(ExitStatus::Failure(code))
 }
@@ -0,0 +1,72 @@
+//! Power-off and reboot.
+//!
+//! An exit status goes to the SiFive test device if there is one, since only that device passes
+//! the code on to QEMU. Otherwise SBI SRST reports a failure as a system failure, and legacy
+//! firmware cannot report a status at all.
+
+use crate::drivers::sifive_test;
+use crate::error::*;
+use crate::sbi::{self, ResetReason, ResetType, SbiError};
+use core::num::NonZeroU16;
+use riscv::asm::wfi;
+
+/// Status reported to the host on power-off.
+#[derive(Copy, Clone, Debug)]
+pub enum ExitStatus {
+    Success,
+
+    /// A failure with a nonzero code. A code of 0 is reported as 1.
+    Failure(u16),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum RebootKind {
+    Warm,
+    Cold,
+}
+
+/// Powers off with a success status.
+pub fn poweroff() -> ! {
+    exit(ExitStatus::Success)
+}
+
+/// Powers off, reporting `status` to the host. Halts this hart if nothing can power off.
+pub fn exit(status: ExitStatus) -> ! {
+    if let Some(device) = sifive_test::device() {
+        match status {
+            ExitStatus::Success => device.pass(),
+            ExitStatus::Failure(code) => device.fail(NonZeroU16::new(code.max(1)).unwrap()),
+        }
+    }
+    let reason = match status {
+        ExitStatus::Success => ResetReason::NoReason,
+        ExitStatus::Failure(_) => ResetReason::SystemFailure,
+    };
+    let e = sbi::system_reset(ResetType::Shutdown, reason);
+    sbi::legacy_shutdown();
+
+    println!("Cannot power off: {:?}. Halting.", e);
+    loop {
+        unsafe {
+            wfi();
+        }
+    }
+}
+
+/// Reboots the machine. Only returns on failure.
+///
+/// The test device only does one kind of reset, so it is tried after SBI.
+pub fn reboot(kind: RebootKind) -> KernelError {
+    let reset_type = match kind {
+        RebootKind::Warm => ResetType::WarmReboot,
+        RebootKind::Cold => ResetType::ColdReboot,
+    };
+    let e = sbi::system_reset(reset_type, ResetReason::NoReason);
+    if let Some(device) = sifive_test::device() {
+        device.reset();
+    }
+    match e {
+        SbiError::NotSupported => KernelError::NotSupported,
+        _ => KernelError::IoError,
+    }
+}
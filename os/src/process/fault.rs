@@ -0,0 +1,108 @@
+//! Page faults, handled in the context of the faulting thread.
+//!
+//! The interrupt handler cannot lock the process or allocate, so it redirects the thread to an
+//! entry point that resolves the fault in thread context and then resumes the faulting code.
+
+use super::{RawThreadState, ThreadToken};
+use crate::error::*;
+use crate::interrupt::Context;
+use crate::memory::{AccessType, PageFault, VirtualAddress};
+use crate::scheduler::HardwareThread;
+use bit_field::BitField;
+use core::mem;
+use riscv::register::sstatus;
+
+impl RawThreadState {
+    /// Makes the thread run a fault handler when it is resumed.
+    ///
+    /// Returns `false` if the fault cannot be handled in thread context: it was raised by the
+    /// kernel with interrupts disabled, or on a kernel address.
+    pub unsafe fn prepare_fault_upcall(&mut self, fault: PageFault) -> bool {
+        if self.was_user() {
+            // The kernel stack is unused while in user mode.
+            let ts_ptr = self as *mut RawThreadState as usize;
+            self.kcontext.sepc = user_fault_entry as usize;
+            self.kcontext.sstatus = 0x120;
+            self.kcontext.gregs[2] = ts_ptr; // sp
+            self.kcontext.gregs[10] = ts_ptr; // a0
+            self.kcontext.gregs[11] = fault.address.0; // a1
+            self.kcontext.gregs[12] = fault.access.to_index(); // a2
+            self.kcontext_valid = 1;
+            return true;
+        }
+
+        // SPIE: whether interrupts were enabled.
+        if !fault.is_user_address() || !self.kcontext.sstatus.get_bit(5) {
+            return false;
+        }
+        // Save the faulting context below its stack pointer. This is where the trap frame
+        // already is, so nothing live is overwritten.
+        let sp = (self.kcontext.gregs[2] - mem::size_of::<Context>()) & !15;
+        let saved = sp as *mut Context;
+        *saved = Context {
+            gregs: self.kcontext.gregs,
+            sstatus: self.kcontext.sstatus,
+            sepc: self.kcontext.sepc,
+        };
+        let ts_ptr = self as *mut RawThreadState as usize;
+        self.kcontext.sepc = kernel_fault_entry as usize;
+        self.kcontext.gregs[2] = sp; // sp
+        self.kcontext.gregs[10] = ts_ptr; // a0
+        self.kcontext.gregs[11] = saved as usize; // a1
+        self.kcontext.gregs[12] = fault.address.0; // a2
+        self.kcontext.gregs[13] = fault.access.to_index(); // a3
+        true
+    }
+}
+
+/// Dispatches a fault to the process of the current thread.
+fn resolve(ht: &HardwareThread, fault: PageFault, token: &ThreadToken) -> KernelResult<()> {
+    match ht.with_current(|th| th.process.clone()) {
+        Some(process) => process.lock(token).handle_page_fault(fault, token),
+        None => Err(KernelError::InvalidArgument),
+    }
+}
+
+unsafe extern "C" fn user_fault_entry(ts: &mut RawThreadState, address: usize, access: usize) -> ! {
+    let token = ThreadToken::assume_thread_context();
+    let ht = &*ts.hart;
+    let fault = PageFault {
+        address: VirtualAddress(address),
+        access: AccessType::from_index(access).unwrap(),
+        user: true,
+    };
+    match resolve(ht, fault, token) {
+        Ok(()) => ht.return_to_user(token),
+        Err(e) => {
+            warn!(
+                "Killing thread {}: unresolved {:?} at {:#x}: {:?}",
+                ht.current_thread_id().0,
+                fault,
+                ts.ucontext.sepc,
+                e
+            );
+            ht.exit_thread(token)
+        }
+    }
+}
+
+unsafe extern "C" fn kernel_fault_entry(
+    ts: &mut RawThreadState,
+    saved: *const Context,
+    address: usize,
+    access: usize,
+) -> ! {
+    let token = ThreadToken::assume_thread_context();
+    let ht = &*ts.hart;
+    let fault = PageFault {
+        address: VirtualAddress(address),
+        access: AccessType::from_index(access).unwrap(),
+        user: false,
+    };
+    if let Err(e) = resolve(ht, fault, token) {
+        panic!("Unresolved kernel {:?}: {:?}\n{:#x?}", fault, e, &*saved);
+    }
+    // `leave` restores `sstatus`, which enables interrupts again.
+    sstatus::clear_sie();
+    (*saved).leave()
+}
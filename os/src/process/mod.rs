@@ -1,3 +1,4 @@
+mod fault;
 mod kernel_task;
 mod process;
 mod thread;
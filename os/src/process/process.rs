@@ -1,7 +1,7 @@
 use super::ThreadToken;
 use crate::error::*;
-use crate::memory::{boot_mapping, LockedPagePool, Mapping, Segment};
-use crate::sync::lock::Mutex;
+use crate::memory::{LockedPagePool, Mapping, PageFault, Segment};
+use crate::sync::lock::{Mutex, MutexGuard};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::pin::Pin;
@@ -26,7 +26,7 @@ impl Process {
     pub fn new(pool: LockedPagePool, token: &ThreadToken) -> KernelResult<Process> {
         Ok(Process {
             id: Id(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
-            mapping: boot_mapping().fork(pool, token)?,
+            mapping: Mapping::new(pool, token)?,
             segments: vec![],
         })
     }
@@ -34,10 +34,80 @@ impl Process {
     pub fn id(&self) -> Id {
         self.id
     }
+
+    /// Duplicates this process. Writable pages are copied on the first write in either process.
+    pub fn fork(&mut self, token: &ThreadToken) -> KernelResult<Process> {
+        Ok(Process {
+            id: Id(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            mapping: self.mapping.fork(token)?,
+            segments: self.segments.clone(),
+        })
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    /// Returns all memory of this process to the pool. No thread may run in it.
+    pub fn release(self, token: &ThreadToken) {
+        self.mapping.release(token);
+    }
+
+    /// Maps `seg` and records it, so that faults in it are resolved.
+    pub fn add_segment(&mut self, seg: Segment, token: &ThreadToken) -> KernelResult<()> {
+        if self
+            .segments
+            .iter()
+            .any(|x| x.range.start < seg.range.end && seg.range.start < x.range.end)
+        {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.mapping.map_segment(&seg, token)?;
+        self.segments.push(seg);
+        Ok(())
+    }
+
+    /// Resolves a page fault of a thread in this process.
+    ///
+    /// Fails with `InvalidArgument` if the address is outside all segments, or the segment does
+    /// not permit the access.
+    pub fn handle_page_fault(&mut self, fault: PageFault, token: &ThreadToken) -> KernelResult<()> {
+        let vpn = fault.address.vpn();
+        let seg = self
+            .segments
+            .iter()
+            .find(|x| x.range.start <= vpn && vpn < x.range.end)
+            .ok_or(KernelError::InvalidArgument)?;
+        if !fault.is_permitted_by(seg.flags) {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.mapping.handle_page_fault(seg, fault, token)
+    }
 }
 
 impl LockedProcess {
     pub fn new(pool: LockedPagePool, token: &ThreadToken) -> KernelResult<LockedProcess> {
         Process::new(pool, token).map(|x| LockedProcess(Arc::pin(Mutex::new(x))))
     }
+
+    pub fn fork(&self, token: &ThreadToken) -> KernelResult<LockedProcess> {
+        let child = self.lock(token).fork(token)?;
+        Ok(LockedProcess(Arc::pin(Mutex::new(child))))
+    }
+
+    /// Releases the process, unless it is referenced elsewhere, in which case it is given back.
+    pub fn release(self, token: &ThreadToken) -> Result<(), LockedProcess> {
+        // The mutex is only moved out once no other reference to it remains.
+        match Arc::try_unwrap(unsafe { Pin::into_inner_unchecked(self.0) }) {
+            Ok(x) => {
+                x.into_inner().release(token);
+                Ok(())
+            }
+            Err(x) => Err(LockedProcess(unsafe { Pin::new_unchecked(x) })),
+        }
+    }
+
+    pub fn lock<'a>(&'a self, token: &'a ThreadToken) -> MutexGuard<'a, Process> {
+        self.0.as_ref().lock(token)
+    }
 }
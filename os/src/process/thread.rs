@@ -126,6 +126,10 @@ impl Thread {
         Ok(Box::new(th))
     }
 
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
     fn check_ts_size() {
         assert!(mem::size_of::<RawThreadState>() % 16 == 0);
         assert!(mem::size_of::<RawThreadState>() == (34 * 2 + 2) * 8);
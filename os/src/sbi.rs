@@ -1,5 +1,12 @@
+//! SBI calls.
+//!
+//! Extensions from SBI v0.2+ are probed through the Base extension. The legacy (v0.1) calls are
+//! only used when the corresponding extension is missing.
+
 #![allow(unused)]
 
+use crate::sync::Once;
+
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -10,52 +17,440 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
-/// Invokes an SBI method.
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x54494d45;
+const EID_IPI: usize = 0x735049;
+const EID_RFENCE: usize = 0x52464e43;
+const EID_HSM: usize = 0x48534d;
+const EID_SRST: usize = 0x53525354;
+
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+
+const TIME_SET_TIMER: usize = 0;
+
+const IPI_SEND_IPI: usize = 0;
+
+const RFENCE_REMOTE_FENCE_I: usize = 0;
+const RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+const RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+
+const SRST_SYSTEM_RESET: usize = 0;
+
+static EXTENSIONS: Once<Extensions> = Once::new();
+
+/// Return value of an SBI v0.2+ call, as defined by the calling convention.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(isize),
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+/// Extensions implemented by the SBI firmware.
+#[derive(Copy, Clone, Debug)]
+pub struct Extensions {
+    /// `(major, minor)` of the SBI specification. `(0, 1)` for legacy firmware.
+    pub spec_version: (usize, usize),
+    pub impl_id: usize,
+    pub impl_version: usize,
+    pub time: bool,
+    pub ipi: bool,
+    pub rfence: bool,
+    pub hsm: bool,
+    pub srst: bool,
+}
+
+/// Hart states reported by `hart_get_status`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HsmStatus {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Unknown(usize),
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(usize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(usize)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> SbiError {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            x => SbiError::Unknown(x),
+        }
+    }
+}
+
+impl SbiRet {
+    pub fn into_result(self) -> SbiResult<usize> {
+        match self.error {
+            0 => Ok(self.value),
+            x => Err(SbiError::from_code(x)),
+        }
+    }
+}
+
+/// Invokes a legacy SBI method.
 ///
 /// # Safety
 ///
 /// Calling into SBI allows powerful system control operations. The caller is responsible to ensure
 /// that the arguments passed to `sbi_call` are valid.
 #[inline(always)]
-unsafe fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+unsafe fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
     let ret;
     llvm_asm!("ecall"
         : "={x10}" (ret)
-        : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x17}" (which)
+        : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x13}" (arg3), "{x17}" (which)
         : "memory"
         : "volatile");
     ret
 }
 
-pub unsafe fn send_ipi(hart_mask: *const usize) {
-    unsafe {
-        sbi_call(SBI_SEND_IPI, hart_mask as _, 0, 0);
+/// Invokes a function of an SBI v0.2+ extension.
+///
+/// # Safety
+///
+/// Same as `sbi_call`.
+#[inline(always)]
+unsafe fn sbi_call_ext(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> SbiRet {
+    let error;
+    let value;
+    llvm_asm!("ecall"
+        : "={x10}" (error), "={x11}" (value)
+        : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x13}" (arg3), "{x14}" (arg4),
+          "{x16}" (fid), "{x17}" (eid)
+        : "memory"
+        : "volatile");
+    SbiRet { error, value }
+}
+
+fn probe() -> Extensions {
+    let base = |fid| unsafe { sbi_call_ext(EID_BASE, fid, 0, 0, 0, 0, 0) }.into_result();
+    let spec_version = match base(BASE_GET_SPEC_VERSION) {
+        Ok(x) => x,
+        Err(_) => {
+            // Legacy firmware does not implement the Base extension.
+            return Extensions {
+                spec_version: (0, 1),
+                impl_id: 0,
+                impl_version: 0,
+                time: false,
+                ipi: false,
+                rfence: false,
+                hsm: false,
+                srst: false,
+            };
+        }
+    };
+    let has = |eid| match unsafe { sbi_call_ext(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0, 0, 0) }
+        .into_result()
+    {
+        Ok(x) => x != 0,
+        Err(_) => false,
+    };
+    Extensions {
+        spec_version: ((spec_version >> 24) & 0x7f, spec_version & 0xffffff),
+        impl_id: base(BASE_GET_IMPL_ID).unwrap_or(0),
+        impl_version: base(BASE_GET_IMPL_VERSION).unwrap_or(0),
+        time: has(EID_TIME),
+        ipi: has(EID_IPI),
+        rfence: has(EID_RFENCE),
+        hsm: has(EID_HSM),
+        srst: has(EID_SRST),
+    }
+}
+
+/// Returns the extensions implemented by the firmware. Probed on first use.
+pub fn extensions() -> &'static Extensions {
+    EXTENSIONS.call_once(probe)
+}
+
+pub fn init() {
+    let ext = extensions();
+    println!(
+        "sbi: Initialized. Spec v{}.{}, implementation {} (version {:#x}).",
+        ext.spec_version.0, ext.spec_version.1, ext.impl_id, ext.impl_version
+    );
+    println!(
+        "sbi: Extensions: TIME={} IPI={} RFENCE={} HSM={} SRST={}",
+        ext.time, ext.ipi, ext.rfence, ext.hsm, ext.srst
+    );
+}
+
+/// Converts a `(hart_mask, hart_mask_base)` pair into a legacy hart mask based at hart 0.
+fn legacy_hart_mask(hart_mask: usize, hart_mask_base: usize) -> SbiResult<usize> {
+    if hart_mask_base == usize::MAX {
+        // All harts.
+        return Ok(usize::MAX);
+    }
+    match hart_mask.checked_shl(hart_mask_base as u32) {
+        Some(x) if x >> hart_mask_base == hart_mask => Ok(x),
+        _ => Err(SbiError::InvalidParam),
+    }
+}
+
+/// Sends an IPI to the harts in `hart_mask`, whose bit 0 is hart `hart_mask_base`.
+///
+/// `hart_mask_base == usize::MAX` selects all harts.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    if extensions().ipi {
+        unsafe { sbi_call_ext(EID_IPI, IPI_SEND_IPI, hart_mask, hart_mask_base, 0, 0, 0) }
+            .into_result()
+            .map(|_| ())
+    } else {
+        let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+        unsafe {
+            sbi_call(SBI_SEND_IPI, &mask as *const usize as usize, 0, 0, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Executes `fence.i` on the harts in `hart_mask`.
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    if extensions().rfence {
+        unsafe {
+            sbi_call_ext(
+                EID_RFENCE,
+                RFENCE_REMOTE_FENCE_I,
+                hart_mask,
+                hart_mask_base,
+                0,
+                0,
+                0,
+            )
+        }
+        .into_result()
+        .map(|_| ())
+    } else {
+        let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+        unsafe {
+            sbi_call(SBI_REMOTE_FENCE_I, &mask as *const usize as usize, 0, 0, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Executes `sfence.vma` for `[start, start + size)` on the harts in `hart_mask`.
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> SbiResult<()> {
+    if extensions().rfence {
+        unsafe {
+            sbi_call_ext(
+                EID_RFENCE,
+                RFENCE_REMOTE_SFENCE_VMA,
+                hart_mask,
+                hart_mask_base,
+                start,
+                size,
+                0,
+            )
+        }
+        .into_result()
+        .map(|_| ())
+    } else {
+        let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+        unsafe {
+            sbi_call(
+                SBI_REMOTE_SFENCE_VMA,
+                &mask as *const usize as usize,
+                start,
+                size,
+                0,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Executes `sfence.vma` for `[start, start + size)` and `asid` on the harts in `hart_mask`.
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> SbiResult<()> {
+    if extensions().rfence {
+        unsafe {
+            sbi_call_ext(
+                EID_RFENCE,
+                RFENCE_REMOTE_SFENCE_VMA_ASID,
+                hart_mask,
+                hart_mask_base,
+                start,
+                size,
+                asid,
+            )
+        }
+        .into_result()
+        .map(|_| ())
+    } else {
+        let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+        unsafe {
+            sbi_call(
+                SBI_REMOTE_SFENCE_VMA_ASID,
+                &mask as *const usize as usize,
+                start,
+                size,
+                asid,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Starts a stopped hart at physical address `start_addr`, with `a0 = hart_id` and `a1 = opaque`.
+///
+/// # Safety
+///
+/// `start_addr` must point to valid entry code that expects `satp = 0`.
+pub unsafe fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiResult<()> {
+    if !extensions().hsm {
+        return Err(SbiError::NotSupported);
+    }
+    sbi_call_ext(EID_HSM, HSM_HART_START, hart_id, start_addr, opaque, 0, 0)
+        .into_result()
+        .map(|_| ())
+}
+
+/// Stops the calling hart. Only returns on failure.
+///
+/// # Safety
+///
+/// The caller must make sure that nothing on this hart is needed anymore.
+pub unsafe fn hart_stop() -> SbiError {
+    if !extensions().hsm {
+        return SbiError::NotSupported;
+    }
+    match sbi_call_ext(EID_HSM, HSM_HART_STOP, 0, 0, 0, 0, 0).into_result() {
+        Ok(_) => unreachable!("hart_stop: returned without error"),
+        Err(e) => e,
+    }
+}
+
+/// Returns the HSM status of a hart.
+pub fn hart_get_status(hart_id: usize) -> SbiResult<HsmStatus> {
+    if !extensions().hsm {
+        return Err(SbiError::NotSupported);
+    }
+    unsafe { sbi_call_ext(EID_HSM, HSM_HART_GET_STATUS, hart_id, 0, 0, 0, 0) }
+        .into_result()
+        .map(|x| match x {
+            0 => HsmStatus::Started,
+            1 => HsmStatus::Stopped,
+            2 => HsmStatus::StartPending,
+            3 => HsmStatus::StopPending,
+            x => HsmStatus::Unknown(x),
+        })
+}
+
+/// Resets the system. Only returns on failure.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    if !extensions().srst {
+        return SbiError::NotSupported;
+    }
+    match unsafe {
+        sbi_call_ext(
+            EID_SRST,
+            SRST_SYSTEM_RESET,
+            reset_type as usize,
+            reason as usize,
+            0,
+            0,
+            0,
+        )
+    }
+    .into_result()
+    {
+        Ok(_) => unreachable!("system_reset: returned without error"),
+        Err(e) => e,
     }
 }
 
 /// Writes a character to the console.
 pub fn console_putchar(c: u8) {
     unsafe {
-        sbi_call(SBI_CONSOLE_PUTCHAR, c as _, 0, 0);
+        sbi_call(SBI_CONSOLE_PUTCHAR, c as _, 0, 0, 0);
     }
 }
 
 /// Reads a character from the console. Returns `-1` for nothing.
 pub fn console_getchar() -> i32 {
-    unsafe { sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0) as _ }
+    unsafe { sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0, 0) as _ }
 }
 
-/// Shuts down the system.
-pub fn shutdown() -> ! {
+/// Shuts down the system with the legacy extension. Only returns on failure.
+pub fn legacy_shutdown() {
     unsafe {
-        sbi_call(SBI_SHUTDOWN, 0, 0, 0);
+        sbi_call(SBI_SHUTDOWN, 0, 0, 0, 0);
     }
-    unreachable!()
 }
 
 /// Schedules a timer interrupt after the `time`-th cycle.
 pub fn set_timer(time: usize) {
-    unsafe {
-        sbi_call(SBI_SET_TIMER, time, 0, 0);
+    if extensions().time {
+        unsafe {
+            sbi_call_ext(EID_TIME, TIME_SET_TIMER, time, 0, 0, 0, 0);
+        }
+    } else {
+        unsafe {
+            sbi_call(SBI_SET_TIMER, time, 0, 0, 0);
+        }
     }
 }
@@ -1,10 +1,18 @@
+use super::timer::{self, Action, TimerQueue};
 use super::EntryReason;
 use super::{Policy, PolicyContext, SwitchReason};
+use crate::drivers;
+use crate::error::*;
 use crate::interrupt::{Context, InterruptToken};
-use crate::process::{create_kernel_thread, KernelTask, RawThreadState, Thread, ThreadToken};
-use crate::sbi::set_timer;
+use crate::memory::PageFault;
+use crate::process::{
+    create_kernel_thread, KernelTask, RawThreadState, Thread, ThreadId, ThreadToken,
+};
+use crate::sbi::{self, set_timer};
+use crate::smp;
 use crate::sync::YieldMutexGuard;
-use crate::sync::{without_interrupts, IntrCell, IntrGuardMut};
+use crate::sync::{global_wait_queue, without_interrupts, IntrCell, IntrGuardMut};
+use crate::timekeeping;
 use alloc::boxed::Box;
 use alloc::collections::linked_list::LinkedList;
 use alloc::sync::Arc;
@@ -12,14 +20,16 @@ use bit_field::BitField;
 use core::cell::Cell;
 use core::mem;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use riscv::asm::wfi;
 use riscv::register::{
-    sie::{clear_stimer, set_stimer},
+    sie::{clear_stimer, set_sext, set_ssoft, set_stimer},
     sstatus::{self, clear_sie, set_sie},
 };
-use riscv::{asm::wfi, register::time};
 
-const DEFAULT_SCHEDULER_REENTRY_TIMEOUT: usize = 100000;
+/// Scheduler re-entry timeout, in `time` ticks. Computed from the timebase frequency at boot.
+static SCHEDULER_REENTRY_TIMEOUT: AtomicUsize = AtomicUsize::new(0);
 
 extern "C" {
     fn save_gregs_assuming_intr_disabled(context: &mut Context) -> usize;
@@ -40,6 +50,9 @@ pub struct HardwareThread {
     /// NOT safe to drop since it contains the stack of the running code itself.
     current: IntrCell<Box<Thread>>,
 
+    /// ID of the current thread, readable without borrowing `current`.
+    current_thread_id: AtomicU64,
+
     /// A list of threads that are waiting to be dropped.
     ///
     /// Avoid using continuous storage due to how our allocator works.
@@ -47,6 +60,16 @@ pub struct HardwareThread {
 
     /// Allocator mutex guard.
     allocator_mutex_guard: IntrCell<Option<YieldMutexGuard<'static, ()>>>,
+
+    /// Sleeping threads and kernel timers.
+    timers: IntrCell<TimerQueue>,
+
+    /// Deadline of the next scheduler tick, in `time` ticks.
+    next_tick: Cell<u64>,
+
+    /// Threads of this hart waiting on a `Completion`. They cannot migrate, so the hart stays
+    /// online while there are any.
+    completion_waiters: AtomicUsize,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
@@ -68,11 +91,15 @@ impl HardwareThread {
         let ht = Box::pin(HardwareThread {
             id,
             policy,
+            current_thread_id: AtomicU64::new(initial_thread.id().0),
             current: IntrCell::new(initial_thread),
             num_intr_guards: Cell::new(0),
             sie_before_intr_guard: Cell::new(true),
             will_drop: IntrCell::new(LinkedList::new()),
             allocator_mutex_guard: IntrCell::new(None),
+            timers: IntrCell::new(TimerQueue::new()),
+            next_tick: Cell::new(0),
+            completion_waiters: AtomicUsize::new(0),
         });
         ht.populate_thread_state();
 
@@ -83,6 +110,10 @@ impl HardwareThread {
         self.id
     }
 
+    pub fn current_thread_id(&self) -> ThreadId {
+        ThreadId(self.current_thread_id.load(Ordering::Relaxed))
+    }
+
     pub fn has_active_intr_guards(&self) -> bool {
         self.num_intr_guards.get() != 0
     }
@@ -95,10 +126,23 @@ impl HardwareThread {
         x
     }
 
+    /// Like `this_hart`, but returns `None` before the `HardwareThread` of this hart is started.
+    pub fn try_this_hart() -> Option<&'static Self> {
+        let x: *const HardwareThread;
+        unsafe {
+            llvm_asm!("mv $0, gp" : "=r"(x) :::);
+            x.as_ref()
+        }
+    }
+
     pub fn policy(&self) -> &dyn Policy<Thread> {
         &*self.policy
     }
 
+    pub(super) fn timers(&self) -> &IntrCell<TimerQueue> {
+        &self.timers
+    }
+
     pub unsafe fn put_allocator_mutex_guard(&self, g: YieldMutexGuard<'static, ()>) {
         let mut place = self.allocator_mutex_guard.borrow_mut(self);
         assert!(
@@ -140,7 +184,7 @@ impl HardwareThread {
         }
     }
 
-    fn run_scheduler(&self, token: &InterruptToken) -> ! {
+    fn run_scheduler(&self, token: &InterruptToken, reason: SwitchReason) -> ! {
         // Drop all threads in `will_drop`.
         /*
         for th in mem::replace(&mut *self.will_drop.borrow_mut(self), LinkedList::new()) {
@@ -150,18 +194,15 @@ impl HardwareThread {
         }
         */
         // Choose next thread to run.
-        match self
-            .policy
-            .next(self, PolicyContext::Critical, SwitchReason::Periodic)
-        {
+        match self.policy.next(self, PolicyContext::Critical, reason) {
             Some(next) => {
                 let old = self.replace_current(next);
                 self.policy.add_thread(self, PolicyContext::Critical, old);
-                prepare_scheduler_reentry();
+                self.prepare_scheduler_reentry();
                 self.return_to_current(token)
             }
             None => unsafe {
-                prepare_scheduler_reentry();
+                self.prepare_scheduler_reentry();
                 self.return_to_current(token);
             },
         }
@@ -172,7 +213,10 @@ impl HardwareThread {
     /// Should be called each time after `self.current` is changed.
     fn populate_thread_state(&self) {
         let self_ptr = self as *const _ as *mut HardwareThread;
-        self.current.borrow_mut(self).raw_thread_state_mut().hart = self_ptr;
+        let mut current = self.current.borrow_mut(self);
+        current.raw_thread_state_mut().hart = self_ptr;
+        self.current_thread_id
+            .store(current.id().0, Ordering::Relaxed);
     }
 
     fn prepare_return_to_user(&self) {
@@ -206,6 +250,9 @@ impl HardwareThread {
     fn enter_from_user(&self, token: &InterruptToken, reason: EntryReason) -> ! {
         match reason {
             EntryReason::Timer => self.return_to_current(token),
+            EntryReason::Ipi => self.on_ipi(token),
+            EntryReason::External => self.return_to_current(token),
+            EntryReason::PageFault(fault) => self.on_page_fault(token, fault),
             _ => panic!("enter_from_user: Unknown reason: {:?}", reason),
         }
     }
@@ -227,6 +274,9 @@ impl HardwareThread {
                 println!("Breakpoint at {:p}", addr as *mut ());
                 self.return_to_current(token);
             }
+            EntryReason::Ipi => self.on_ipi(token),
+            EntryReason::External => self.return_to_current(token),
+            EntryReason::PageFault(fault) => self.on_page_fault(token, fault),
             _ => panic!("enter_from_kernel: Unknown reason: {:?}", reason),
         }
     }
@@ -235,14 +285,27 @@ impl HardwareThread {
         unsafe { self.force_return_to_current() }
     }
 
+    /// Resumes the user context of the current thread, e.g. after handling a page fault.
+    ///
+    /// # Safety
+    ///
+    /// The current thread must have entered the kernel from user mode.
+    pub unsafe fn return_to_user(&self, _: &ThreadToken) -> ! {
+        // `sscratch` must not point to the thread state while in the kernel.
+        clear_sie();
+        self.force_return_to_current()
+    }
+
     pub fn with_current<F: FnOnce(&mut Thread) -> R, R>(&self, f: F) -> R {
         let mut current = self.current.borrow_mut(self);
         f(&mut **current)
     }
 
     pub unsafe fn start(&self) -> ! {
-        prepare_scheduler_reentry();
+        self.prepare_scheduler_reentry();
         set_stimer();
+        set_ssoft();
+        set_sext();
         self.force_return_to_current();
     }
 
@@ -416,11 +479,296 @@ impl HardwareThread {
     }
 
     fn tick(&self, token: &InterruptToken) -> ! {
-        self.run_scheduler(token)
+        let now = timekeeping::ticks();
+        let woken = self.timers.borrow_mut(self).expire(now, self);
+        if now < self.next_tick.get() {
+            // Entered early for a timer.
+            if woken {
+                self.run_scheduler(token, SwitchReason::Yield)
+            }
+            set_timer(self.next_timer_interrupt() as usize);
+            self.return_to_current(token)
+        }
+        drivers::uart::poll();
+        self.run_scheduler(token, SwitchReason::Periodic)
+    }
+
+    /// Blocks the current thread until the monotonic clock reaches `deadline`.
+    pub fn sleep_until(&self, deadline: Duration, token: &ThreadToken) {
+        assert!(
+            !self.has_active_intr_guards(),
+            "sleep_until: must not hold any interrupt guards"
+        );
+        let deadline = timer::deadline_to_ticks(deadline);
+        while timekeeping::ticks() < deadline {
+            let next =
+                self.policy
+                    .next(self, PolicyContext::NonCritical(token), SwitchReason::Yield);
+            match next {
+                Some(next) => unsafe {
+                    self.ll_yield(
+                        next,
+                        move |th| {
+                            if let Err(Action::Wake(th)) =
+                                self.add_timer(deadline, Action::Wake(th))
+                            {
+                                // Timer queue full. Retry when scheduled again, in the slot the
+                                // next thread was taken from.
+                                self.policy.add_thread(self, PolicyContext::Critical, th);
+                            }
+                        },
+                        token,
+                    );
+                },
+                None => unsafe {
+                    // Nothing else to run. Wait here, but wake up in time.
+                    without_interrupts(self, || {
+                        set_timer(self.next_timer_interrupt().min(deadline) as usize)
+                    });
+                    wfi();
+                },
+            }
+        }
+    }
+
+    /// Queues a timer on this hart, and re-arms the SBI timer if it is the earliest.
+    ///
+    /// Does not allocate. Gives `action` back if the queue is full.
+    pub(super) fn add_timer(&self, deadline: u64, action: Action) -> Result<(), Action> {
+        without_interrupts(self, || {
+            self.timers.borrow_mut(self).push(deadline, action)?;
+            set_timer(self.next_timer_interrupt() as usize);
+            Ok(())
+        })
+    }
+
+    /// Earliest of the next scheduler tick and the next timer deadline, in `time` ticks.
+    fn next_timer_interrupt(&self) -> u64 {
+        let next_tick = self.next_tick.get();
+        match self.timers.borrow_mut(self).next_deadline() {
+            Some(x) => x.min(next_tick),
+            None => next_tick,
+        }
+    }
+
+    /// Sets up the timer for kernel re-entry at the next scheduler tick, or at an earlier timer.
+    fn prepare_scheduler_reentry(&self) {
+        let timeout = SCHEDULER_REENTRY_TIMEOUT.load(Ordering::Relaxed) as u64;
+        self.next_tick.set(timekeeping::ticks() + timeout);
+        set_timer(self.next_timer_interrupt() as usize);
+    }
+
+    /// Makes the current thread handle a page fault in thread context, or panics if it cannot.
+    fn on_page_fault(&self, token: &InterruptToken, fault: PageFault) -> ! {
+        self.with_current(|th| {
+            let ts = th.raw_thread_state_mut();
+            if !unsafe { ts.prepare_fault_upcall(fault) } {
+                panic!(
+                    "Unhandled {:?} in thread {}\n{:#x?}",
+                    fault,
+                    th.id().0,
+                    th.raw_thread_state().last_context()
+                );
+            }
+        });
+        self.return_to_current(token)
+    }
+
+    fn on_ipi(&self, token: &InterruptToken) -> ! {
+        if smp::ipi::handle(self) {
+            self.run_scheduler(token, SwitchReason::Yield)
+        } else {
+            self.return_to_current(token)
+        }
+    }
+
+    /// Makes a thread runnable on its home hart, i.e. the hart it last ran on.
+    ///
+    /// Threads in kernel mode cannot migrate, so a thread woken up from another hart is queued
+    /// there and the hart is notified with a reschedule IPI. A thread in user mode whose home
+    /// hart is not online runs on this hart instead. Gives the thread back if no run queue can
+    /// take it.
+    pub fn wake(&self, th: Box<Thread>, _: &ThreadToken) -> Result<(), Box<Thread>> {
+        let home = th.raw_thread_state().hart;
+        let th = if home.is_null() || home as *const HardwareThread == self as *const HardwareThread
+        {
+            th
+        } else {
+            match unsafe { &*home }.add_thread_from(self, th) {
+                Ok(()) => return Ok(()),
+                Err(th) if th.can_migrate() => th,
+                Err(th) => return Err(th),
+            }
+        };
+        without_interrupts(self, || self.policy.add_thread_remote(th))
+    }
+
+    /// Queues a thread of this hart from hart `from`, and notifies this hart. Gives the thread
+    /// back if this hart is not online or its run queue is full.
+    ///
+    /// Does not allocate.
+    pub fn add_thread_from(
+        &self,
+        from: &HardwareThread,
+        th: Box<Thread>,
+    ) -> Result<(), Box<Thread>> {
+        match smp::hart(self.id.0) {
+            Some(x) if x.status() == smp::HartStatus::Online => {}
+            _ => return Err(th),
+        }
+        // Going offline stops accepting threads before draining the queue, under its lock.
+        without_interrupts(from, || self.policy.add_thread_remote(th))?;
+        if from.id != self.id {
+            // Otherwise the thread is picked up at the next tick.
+            let _ = smp::ipi::send_reschedule(self.id.0);
+        }
+        Ok(())
+    }
+
+    /// Counts threads of this hart waiting on a `Completion`.
+    pub fn completion_waiters(&self) -> &AtomicUsize {
+        &self.completion_waiters
+    }
+
+    /// Takes this hart offline with SBI HSM `hart_stop`.
+    ///
+    /// All queued threads are moved to other online harts first. Fails with `Busy` if a queued
+    /// thread cannot migrate, if a thread of this hart is waiting in the global wait queue or on a
+    /// `Completion`, if timers are pending on this hart, or if no other hart can take the queued
+    /// threads.
+    ///
+    /// Must be called from the daemon thread of this hart, which is left behind as the current
+    /// thread and freed by `restart` when the hart is brought back. Only returns on failure.
+    pub fn go_offline(&self, _: &ThreadToken) -> KernelError {
+        let hart = smp::hart(self.id.0).expect("go_offline: hart not found");
+
+        unsafe {
+            self.acquire_intr_guard();
+        }
+
+        let mut num_pinned = 0;
+        let mut num_queued = 0;
+        self.policy.for_each_thread(&mut |th| {
+            num_queued += 1;
+            if !th.can_migrate() {
+                num_pinned += 1;
+            }
+        });
+
+        let targets = || {
+            smp::harts()
+                .iter()
+                .filter(|x| x.id() != self.id.0 && x.status() == smp::HartStatus::Online)
+                .filter_map(|x| smp::hardware_thread(x.id()))
+        };
+        let has_waiters = global_wait_queue().has_waiters_on(self).unwrap_or(true);
+        let has_timers = !self.timers.borrow_mut(self).is_idle();
+        let has_completion_waiters = self.completion_waiters.load(Ordering::SeqCst) != 0;
+        if num_pinned != 0
+            || has_waiters
+            || has_timers
+            || has_completion_waiters
+            || (num_queued != 0 && targets().next().is_none())
+        {
+            unsafe {
+                self.release_intr_guard();
+            }
+            return KernelError::Busy;
+        }
+
+        // From now on no other hart considers this hart as a target, and threads woken meanwhile
+        // are either queued before the drain or refused.
+        hart.set_status(smp::HartStatus::Stopping);
+        self.policy.set_accepting_remote(false);
+
+        let num_targets = targets().count();
+        let mut target = targets().cycle();
+        while let Some(th) = self
+            .policy
+            .next(self, PolicyContext::Critical, SwitchReason::Yield)
+        {
+            let mut th = Some(th);
+            if th.as_ref().unwrap().can_migrate() {
+                for _ in 0..num_targets {
+                    match target
+                        .next()
+                        .unwrap()
+                        .add_thread_from(self, th.take().unwrap())
+                    {
+                        Ok(()) => break,
+                        Err(x) => th = Some(x),
+                    }
+                }
+            }
+            if let Some(th) = th {
+                // Woken in kernel mode since the check above, or all targets are full.
+                self.policy.add_thread(self, PolicyContext::Critical, th);
+                self.policy.set_accepting_remote(true);
+                hart.set_status(smp::HartStatus::Online);
+                unsafe {
+                    self.release_intr_guard();
+                }
+                return KernelError::Busy;
+            }
+        }
+
+        unsafe {
+            clear_stimer();
+            hart.set_status(smp::HartStatus::Offline);
+            let e = sbi::hart_stop();
+
+            // Failed. Keep running with an empty run queue.
+            self.policy.set_accepting_remote(true);
+            hart.set_status(smp::HartStatus::Online);
+            set_stimer();
+            self.release_intr_guard();
+            match e {
+                sbi::SbiError::NotSupported => KernelError::NotSupported,
+                _ => KernelError::Busy,
+            }
+        }
+    }
+
+    /// Prepares the `HardwareThread` of a stopped hart to be started again, with
+    /// `initial_thread` as its current thread. Frees the threads left behind by the hart.
+    ///
+    /// `HardwareThread`s are never freed, since other harts may hold references to them.
+    ///
+    /// # Safety
+    ///
+    /// The hart must be stopped.
+    pub unsafe fn restart(&self, initial_thread: Box<Thread>) {
+        // Left held by `go_offline`.
+        self.num_intr_guards.set(0);
+        self.sie_before_intr_guard.set(true);
+        self.next_tick.set(0);
+        assert!(
+            self.allocator_mutex_guard.borrow_mut(self).is_none(),
+            "HardwareThread::restart: allocator mutex held by a stopped hart"
+        );
+
+        let new_timers = TimerQueue::new();
+        let old = mem::replace(&mut *self.current.borrow_mut(self), initial_thread);
+        self.populate_thread_state();
+        let will_drop = mem::replace(&mut *self.will_drop.borrow_mut(self), LinkedList::new());
+        let timers = mem::replace(&mut *self.timers.borrow_mut(self), new_timers);
+
+        without_interrupts(self, || self.policy.set_accepting_remote(true));
+
+        timers.drop_assuming_stopped();
+        old.drop_assuming_not_current();
+        for th in will_drop {
+            th.drop_assuming_not_current();
+        }
     }
 }
 
-/// Sets up the timer for kernel re-entry.
-fn prepare_scheduler_reentry() {
-    set_timer(time::read() + DEFAULT_SCHEDULER_REENTRY_TIMEOUT);
+/// Converts the scheduler re-entry timeout into `time` ticks.
+pub fn init_scheduler_reentry_timeout(timebase_frequency: u64, timeout_us: u64) {
+    assert!(
+        timebase_frequency != 0,
+        "init_scheduler_reentry_timeout: unknown timebase frequency"
+    );
+    let ticks = timebase_frequency * timeout_us / 1_000_000;
+    SCHEDULER_REENTRY_TIMEOUT.store(ticks.max(1) as usize, Ordering::Relaxed);
 }
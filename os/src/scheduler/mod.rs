@@ -1,15 +1,38 @@
 mod hart;
 mod plan;
 mod reason;
+mod timer;
 
 pub use hart::{HardwareThread, Id as HardwareThreadId};
 pub use plan::{Policy, PolicyContext, SimplePolicy, SwitchReason};
 pub use reason::EntryReason;
+pub use timer::{Timer, TimerCallback};
 
+use crate::cmdline::Param;
+use crate::dtb;
 use crate::sync::Once;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use core::num::NonZeroU64;
+
+/// Scheduler re-entry timeout, in microseconds.
+pub static QUANTUM_US: Param<NonZeroU64> = Param::new(
+    "sched.quantum",
+    unsafe { NonZeroU64::new_unchecked(10000) },
+    "scheduler timer interval in microseconds",
+);
+
+/// Number of scheduler re-entries before `SimplePolicy` switches threads.
+pub static MAX_TICKS: Param<u32> = Param::new(
+    "sched.max_ticks",
+    10,
+    "timer ticks before a thread is preempted",
+);
 
 pub fn init() {
-    println!("scheduler: Initialized.");
+    hart::init_scheduler_reentry_timeout(
+        dtb::device_tree().timebase_frequency(),
+        QUANTUM_US.get().get(),
+    );
+    info!("Initialized.");
 }
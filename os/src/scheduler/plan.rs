@@ -10,7 +10,7 @@ use arraydeque::ArrayDeque;
 use core::mem;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU32, Ordering};
-use spin::Mutex as SpinMutex;
+use spin::{Mutex as SpinMutex, MutexGuard as SpinMutexGuard};
 
 #[derive(Copy, Clone, Debug)]
 pub enum SwitchReason {
@@ -33,6 +33,24 @@ pub enum PolicyContext<'a> {
 /// Per-hart scheduling policy.
 pub trait Policy<T> {
     fn add_thread(&self, ht: &HardwareThread, context: PolicyContext, thread: Box<T>);
+
+    /// Adds a thread from another hart. Gives it back if the queue is full or not accepting
+    /// threads.
+    ///
+    /// Must be called with interrupts disabled. Does not allocate.
+    fn add_thread_remote(&self, thread: Box<T>) -> Result<(), Box<T>>;
+
+    /// Sets whether `add_thread_remote` accepts threads, e.g. while the hart drains its queue to
+    /// go offline.
+    ///
+    /// Must be called with interrupts disabled.
+    fn set_accepting_remote(&self, accepting: bool);
+
+    /// Calls `f` on each queued thread.
+    ///
+    /// Must be called with interrupts disabled. `f` must not allocate.
+    fn for_each_thread(&self, f: &mut dyn FnMut(&T));
+
     fn next(
         &self,
         ht: &HardwareThread,
@@ -49,12 +67,14 @@ pub struct SimplePolicy<T> {
 
 struct CriticalBuffer<T> {
     local_run_queue: Box<ArrayDeque<[Box<T>; 512]>>,
+    accepting_remote: bool,
 }
 
 impl<T> CriticalBuffer<T> {
     fn new() -> CriticalBuffer<T> {
         CriticalBuffer {
             local_run_queue: Box::new(ArrayDeque::new()),
+            accepting_remote: true,
         }
     }
 }
@@ -64,27 +84,32 @@ impl<T> SimplePolicy<T> {
         SimplePolicy {
             critical_buffer: SpinMutex::new(CriticalBuffer::new()),
             remaining_ticks: AtomicU32::new(0),
-            max_ticks: 10,
+            max_ticks: super::MAX_TICKS.get(),
         }
     }
+
+    /// Locks the critical buffer.
+    ///
+    /// Must be called with interrupts disabled. Since every hart only holds the lock with
+    /// interrupts disabled, spinning here always makes progress, even if another hart is adding
+    /// a thread with `add_thread_remote`.
+    fn lock_critical_buffer(&self) -> SpinMutexGuard<CriticalBuffer<T>> {
+        self.critical_buffer.lock()
+    }
 }
 
 impl<T: Send> Policy<T> for SimplePolicy<T> {
     fn add_thread(&self, ht: &HardwareThread, context: PolicyContext, thread: Box<T>) {
         match context {
             PolicyContext::NonCritical(token) => without_interrupts(ht, || {
-                let mut buffer = self.critical_buffer.try_lock().expect(
-                    "SimplePolicy::add_thread: cannot lock critical buffer in non-critical path",
-                );
+                let mut buffer = self.lock_critical_buffer();
                 buffer
                     .local_run_queue
                     .push_back(thread)
                     .expect("SimplePolicy::add_thread: critical buffer full");
             }),
             PolicyContext::Critical => {
-                let mut buffer = self.critical_buffer.try_lock().expect(
-                    "SimplePolicy::add_thread: cannot lock critical buffer in critical path",
-                );
+                let mut buffer = self.lock_critical_buffer();
                 buffer
                     .local_run_queue
                     .push_back(thread)
@@ -92,6 +117,29 @@ impl<T: Send> Policy<T> for SimplePolicy<T> {
             }
         }
     }
+
+    fn add_thread_remote(&self, thread: Box<T>) -> Result<(), Box<T>> {
+        let mut buffer = self.lock_critical_buffer();
+        if !buffer.accepting_remote {
+            return Err(thread);
+        }
+        buffer
+            .local_run_queue
+            .push_back(thread)
+            .map_err(|e| e.element)
+    }
+
+    fn set_accepting_remote(&self, accepting: bool) {
+        self.lock_critical_buffer().accepting_remote = accepting;
+    }
+
+    fn for_each_thread(&self, f: &mut dyn FnMut(&T)) {
+        let buffer = self.lock_critical_buffer();
+        for th in buffer.local_run_queue.iter() {
+            f(&**th);
+        }
+    }
+
     fn next(
         &self,
         ht: &HardwareThread,
@@ -123,17 +171,12 @@ impl<T: Send> Policy<T> for SimplePolicy<T> {
         if attempt_switch {
             match context {
                 PolicyContext::NonCritical(token) => without_interrupts(ht, || {
-                    let mut buffer = self.critical_buffer.try_lock().expect(
-                        "SimplePolicy::next: cannot lock critical buffer in non-critical path",
-                    );
+                    let mut buffer = self.lock_critical_buffer();
                     buffer.local_run_queue.pop_front()
                 }),
                 PolicyContext::Critical => {
                     // Ensure to free up space for one following `add_thread`.
-                    let mut buffer = self
-                        .critical_buffer
-                        .try_lock()
-                        .expect("SimplePolicy::next: cannot lock critical buffer in critical path");
+                    let mut buffer = self.lock_critical_buffer();
                     buffer.local_run_queue.pop_front()
                 }
             }
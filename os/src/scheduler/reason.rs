@@ -1,9 +1,12 @@
+use crate::memory::PageFault;
 use crate::process::ThreadToken;
 
 #[derive(Debug)]
 pub enum EntryReason {
     Syscall,
-    PageFault,
+    PageFault(PageFault),
     Timer,
     Breakpoint(usize),
+    Ipi,
+    External,
 }
@@ -0,0 +1,324 @@
+//! Per-hart timer queue.
+//!
+//! Each hart keeps its timers in a min-heap of deadlines, in `time` ticks, and programs the single
+//! SBI timer for the earliest of the next deadline and the next scheduler tick. Expired sleeps
+//! make their thread runnable from interrupt context. Expired callbacks are handed to a per-hart
+//! daemon thread, so they run in thread context.
+
+use super::{HardwareThread, PolicyContext};
+use crate::error::*;
+use crate::process::{spawn, KernelTask, Thread, ThreadToken};
+use crate::timekeeping;
+use alloc::boxed::Box;
+use alloc::collections::binary_heap::BinaryHeap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use core::cmp;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Timers queued or expired on one hart at most.
+const MAX_TIMERS: usize = 512;
+
+/// A kernel callback run by a `Timer`.
+pub trait TimerCallback: Send + Sync {
+    /// Called in thread context, on the hart that started the timer.
+    fn fire(&self, ht: &HardwareThread, token: &ThreadToken);
+}
+
+/// A one-shot or periodic callback. Dropping it cancels it.
+pub struct Timer {
+    state: Arc<TimerState>,
+}
+
+pub struct TimerState {
+    callback: Box<dyn TimerCallback>,
+
+    /// Incremented on each start and cancel. Queued entries of older generations are stale.
+    generation: AtomicU64,
+
+    /// Period in ticks, or 0 for a one-shot timer.
+    period: AtomicU64,
+}
+
+pub enum Action {
+    /// Make a sleeping thread runnable.
+    Wake(Box<Thread>),
+
+    /// Run a callback, if the timer is still in the given generation.
+    Callback(Arc<TimerState>, u64),
+}
+
+struct Entry {
+    deadline: u64,
+
+    /// Orders entries with the same deadline first-in, first-out.
+    seq: u64,
+
+    action: Action,
+}
+
+/// A callback entry waiting for the daemon.
+struct Expired {
+    state: Arc<TimerState>,
+    generation: u64,
+    deadline: u64,
+}
+
+pub struct TimerQueue {
+    entries: BinaryHeap<Entry>,
+    expired: VecDeque<Expired>,
+    next_seq: u64,
+
+    /// The daemon, while it waits for expired callbacks.
+    daemon: Option<Box<Thread>>,
+    daemon_spawned: bool,
+}
+
+struct TimerDaemon;
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> cmp::Ordering {
+        // Reversed, so that `BinaryHeap` pops the earliest deadline first.
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl TimerQueue {
+    /// Capacity is reserved up front, so queueing and expiring never allocate.
+    pub fn new() -> TimerQueue {
+        TimerQueue {
+            entries: BinaryHeap::with_capacity(MAX_TIMERS),
+            expired: VecDeque::with_capacity(MAX_TIMERS),
+            next_seq: 0,
+            daemon: None,
+            daemon_spawned: false,
+        }
+    }
+
+    /// Queues `action` to run at `deadline`. Gives it back if the queue is full.
+    ///
+    /// Must be called with interrupts disabled. Does not allocate.
+    pub fn push(&mut self, deadline: u64, action: Action) -> Result<(), Action> {
+        if self.entries.len() + self.expired.len() >= MAX_TIMERS {
+            return Err(action);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.push(Entry {
+            deadline,
+            seq,
+            action,
+        });
+        Ok(())
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries.peek().map(|x| x.deadline)
+    }
+
+    /// Runs all entries due at `now`. Returns whether a thread was made runnable.
+    ///
+    /// Must be called with interrupts disabled. Does not allocate.
+    pub fn expire(&mut self, now: u64, ht: &HardwareThread) -> bool {
+        let mut woken = false;
+        while self.next_deadline().map(|x| x <= now).unwrap_or(false) {
+            let entry = self.entries.pop().unwrap();
+            match entry.action {
+                Action::Wake(th) => {
+                    ht.policy().add_thread(ht, PolicyContext::Critical, th);
+                    woken = true;
+                }
+                Action::Callback(state, generation) => {
+                    // Even stale entries go to the daemon, which may drop the last reference.
+                    self.expired.push_back(Expired {
+                        state,
+                        generation,
+                        deadline: entry.deadline,
+                    });
+                    if let Some(th) = self.daemon.take() {
+                        ht.policy().add_thread(ht, PolicyContext::Critical, th);
+                        woken = true;
+                    }
+                }
+            }
+        }
+        woken
+    }
+
+    /// Whether no timer is queued or waiting for the daemon, including cancelled ones.
+    pub fn is_idle(&self) -> bool {
+        self.entries.is_empty() && self.expired.is_empty()
+    }
+
+    /// Drops the queue together with the parked daemon.
+    ///
+    /// # Safety
+    ///
+    /// The queue must be idle, and must belong to a hart that has been stopped.
+    pub unsafe fn drop_assuming_stopped(mut self) {
+        assert!(
+            self.is_idle(),
+            "TimerQueue::drop_assuming_stopped: timers pending"
+        );
+        if let Some(th) = self.daemon.take() {
+            th.drop_assuming_not_current();
+        }
+    }
+}
+
+impl Timer {
+    pub fn new(callback: Box<dyn TimerCallback>) -> Timer {
+        Timer {
+            state: Arc::new(TimerState {
+                callback,
+                generation: AtomicU64::new(0),
+                period: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Fires once when the monotonic clock reaches `deadline`. Replaces an earlier start.
+    ///
+    /// The callback runs on this hart.
+    pub fn start_oneshot(
+        &self,
+        deadline: Duration,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        self.start(deadline_to_ticks(deadline), 0, ht, token)
+    }
+
+    /// Fires every `period`, starting one period from now. Replaces an earlier start.
+    ///
+    /// The callback runs on this hart. Periods missed while the hart was busy are skipped.
+    pub fn start_periodic(
+        &self,
+        period: Duration,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        let period = timekeeping::duration_to_ticks(period);
+        if period == 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.start(
+            timekeeping::ticks().saturating_add(period),
+            period,
+            ht,
+            token,
+        )
+    }
+
+    /// Stops the timer. A callback that is already running is not waited for.
+    pub fn cancel(&self) {
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn start(
+        &self,
+        deadline: u64,
+        period: u64,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+    ) -> KernelResult<()> {
+        ensure_daemon(ht, token)?;
+        self.state.period.store(period, Ordering::SeqCst);
+        let generation = self.state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        ht.add_timer(deadline, Action::Callback(self.state.clone(), generation))
+            .map_err(|_| KernelError::Busy)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl Expired {
+    fn run(self, ht: &HardwareThread, token: &ThreadToken) {
+        let state = self.state;
+        let current = || state.generation.load(Ordering::SeqCst) == self.generation;
+        if !current() {
+            return;
+        }
+        state.callback.fire(ht, token);
+
+        let period = state.period.load(Ordering::SeqCst);
+        if period == 0 || !current() {
+            return;
+        }
+        let now = timekeeping::ticks();
+        let mut deadline = self.deadline.saturating_add(period);
+        if deadline <= now {
+            deadline = now.saturating_add(period);
+        }
+        let generation = self.generation;
+        if ht
+            .add_timer(deadline, Action::Callback(state, generation))
+            .is_err()
+        {
+            warn!("Timer queue full. Stopping a periodic timer.");
+        }
+    }
+}
+
+impl KernelTask for TimerDaemon {
+    fn run(self: Box<Self>, ht: &HardwareThread, token: &ThreadToken) {
+        loop {
+            let next = ht.timers().borrow_mut(ht).expired.pop_front();
+            match next {
+                Some(x) => x.run(ht, token),
+                None => unsafe {
+                    ht.release_current(
+                        |th| {
+                            let mut timers = ht.timers().borrow_mut(ht);
+                            if timers.expired.is_empty() {
+                                timers.daemon = Some(th);
+                            } else {
+                                // Expired meanwhile. Run again.
+                                ht.policy().add_thread(ht, PolicyContext::Critical, th);
+                            }
+                        },
+                        token,
+                    );
+                },
+            }
+        }
+    }
+}
+
+/// Spawns the daemon of this hart, unless it is already running.
+fn ensure_daemon(ht: &HardwareThread, token: &ThreadToken) -> KernelResult<()> {
+    let spawned = mem::replace(&mut ht.timers().borrow_mut(ht).daemon_spawned, true);
+    if spawned {
+        return Ok(());
+    }
+    spawn(ht, Box::new(TimerDaemon), token).map_err(|e| {
+        ht.timers().borrow_mut(ht).daemon_spawned = false;
+        e
+    })
+}
+
+/// Converts a deadline in monotonic time to ticks, rounding up, so that the deadline has passed
+/// once the `time` CSR reaches it.
+pub fn deadline_to_ticks(deadline: Duration) -> u64 {
+    timekeeping::duration_to_ticks(deadline).saturating_add(1)
+}
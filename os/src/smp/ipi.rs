@@ -0,0 +1,317 @@
+//! Inter-processor interrupt messaging.
+//!
+//! Each hart has a mailbox. Messages without a payload (reschedule, stop, full TLB flush) are
+//! pending bits that can be set from any context, including the panic handler. Messages with a
+//! payload go through a small bounded queue.
+
+use super::{hart, try_harts, HartState, HartStatus};
+use crate::error::*;
+use crate::memory::{tlb, VirtualPageNumber};
+use crate::process::ThreadToken;
+use crate::sbi;
+use crate::scheduler::HardwareThread;
+use crate::sync::without_interrupts;
+use arraydeque::ArrayDeque;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use riscv::asm::wfi;
+use riscv::register::sstatus::clear_sie;
+use spin::Mutex as SpinMutex;
+
+const MAILBOX_CAPACITY: usize = 16;
+
+const PENDING_RESCHEDULE: usize = 1 << 0;
+const PENDING_STOP: usize = 1 << 1;
+const PENDING_TLB_FLUSH_ALL: usize = 1 << 2;
+const PENDING_QUEUE: usize = 1 << 3;
+
+const CALL_PENDING: u8 = 0;
+const CALL_DONE: u8 = 1;
+const CALL_DROPPED: u8 = 2;
+
+/// A message with a payload.
+pub enum Message {
+    /// Calls `f(arg)` in the interrupt context of the target hart, then updates `*state`.
+    Call {
+        f: fn(usize),
+        arg: usize,
+        state: *const AtomicU8,
+    },
+
+    /// Flushes the TLB entries for `range`.
+    TlbFlush { range: Range<VirtualPageNumber> },
+}
+
+// `state` is only dereferenced while its owner waits for it.
+unsafe impl Send for Message {}
+
+pub struct Mailbox {
+    pending: AtomicUsize,
+    queue: SpinMutex<ArrayDeque<[Message; MAILBOX_CAPACITY]>>,
+
+    /// Number of TLB flushes posted so far. Incremented after posting.
+    tlb_flush_requests: AtomicUsize,
+
+    /// Value of `tlb_flush_requests` up to which all flushes are done.
+    tlb_flush_done: AtomicUsize,
+}
+
+impl Mailbox {
+    pub fn new() -> Mailbox {
+        Mailbox {
+            pending: AtomicUsize::new(0),
+            queue: SpinMutex::new(ArrayDeque::new()),
+            tlb_flush_requests: AtomicUsize::new(0),
+            tlb_flush_done: AtomicUsize::new(0),
+        }
+    }
+
+    /// Discards all messages. Waiters of discarded calls get an error.
+    ///
+    /// Called before the hart is started. A starting hart flushes its whole TLB anyway.
+    pub fn clear(&self) {
+        self.pending.store(0, Ordering::SeqCst);
+        self.tlb_flush_done.store(
+            self.tlb_flush_requests.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+        let mut queue = self.queue.lock();
+        while let Some(msg) = queue.pop_front() {
+            if let Message::Call { state, .. } = msg {
+                unsafe {
+                    (*state).store(CALL_DROPPED, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    fn set_pending(&self, bits: usize) {
+        self.pending.fetch_or(bits, Ordering::SeqCst);
+    }
+
+    /// Returns the ticket of a TLB flush that has just been posted.
+    fn tlb_flush_ticket(&self) -> usize {
+        self.tlb_flush_requests.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Pushes `msg` to the queue, or returns it back if the queue is full.
+    fn push(&self, ht: &HardwareThread, msg: Message) -> Result<(), Message> {
+        // The lock is also taken by the IPI handler of the target hart.
+        let result = without_interrupts(ht, || {
+            self.queue.lock().push_back(msg).map_err(|e| e.element)
+        });
+        if result.is_ok() {
+            self.set_pending(PENDING_QUEUE);
+        }
+        result
+    }
+
+    /// Removes the call waited on through `state` if it has not started yet.
+    fn cancel(&self, ht: &HardwareThread, state: *const AtomicU8) -> bool {
+        without_interrupts(ht, || {
+            let mut queue = self.queue.lock();
+            let index = queue.iter().position(|x| match *x {
+                Message::Call { state: x, .. } => x == state,
+                _ => false,
+            });
+            match index {
+                Some(i) => {
+                    queue.remove(i);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+fn accepts_messages(status: HartStatus) -> bool {
+    match status {
+        HartStatus::Starting | HartStatus::Online => true,
+        _ => false,
+    }
+}
+
+fn target(hart_id: u32) -> KernelResult<&'static HartState> {
+    let hart = hart(hart_id).ok_or(KernelError::InvalidArgument)?;
+    if accepts_messages(hart.status()) {
+        Ok(hart)
+    } else {
+        Err(KernelError::InvalidArgument)
+    }
+}
+
+fn notify(hart_id: u32) {
+    if let Err(e) = sbi::send_ipi(1, hart_id as usize) {
+        panic!("ipi: send_ipi to hart {} failed: {:?}", hart_id, e);
+    }
+}
+
+/// Asks a hart to run its scheduler.
+pub fn send_reschedule(hart_id: u32) -> KernelResult<()> {
+    let hart = target(hart_id)?;
+    hart.mailbox().set_pending(PENDING_RESCHEDULE);
+    notify(hart_id);
+    Ok(())
+}
+
+/// Asks a hart to flush its whole TLB. Returns a ticket for `tlb_flush_done`.
+pub fn send_tlb_flush_all(hart_id: u32) -> KernelResult<usize> {
+    let mailbox = target(hart_id)?.mailbox();
+    mailbox.set_pending(PENDING_TLB_FLUSH_ALL);
+    let ticket = mailbox.tlb_flush_ticket();
+    notify(hart_id);
+    Ok(ticket)
+}
+
+/// Asks a hart to flush the TLB entries for `range`. Returns a ticket for `tlb_flush_done`.
+///
+/// Never blocks. Falls back to a full flush if the mailbox of the hart is full.
+pub fn send_tlb_flush(hart_id: u32, range: Range<VirtualPageNumber>) -> KernelResult<usize> {
+    let mailbox = target(hart_id)?.mailbox();
+    if mailbox
+        .push(HardwareThread::this_hart(), Message::TlbFlush { range })
+        .is_err()
+    {
+        mailbox.set_pending(PENDING_TLB_FLUSH_ALL);
+    }
+    let ticket = mailbox.tlb_flush_ticket();
+    notify(hart_id);
+    Ok(ticket)
+}
+
+/// Returns whether the TLB flush with `ticket` is done, or no longer needed because the hart
+/// went offline.
+pub fn tlb_flush_done(hart_id: u32, ticket: usize) -> bool {
+    match hart(hart_id) {
+        Some(x) => {
+            x.mailbox().tlb_flush_done.load(Ordering::SeqCst) >= ticket
+                || !accepts_messages(x.status())
+        }
+        None => true,
+    }
+}
+
+/// Runs `f(arg)` on a hart and waits for it to return.
+///
+/// `f` runs in interrupt context, so it must not allocate or block.
+pub fn call_on_hart(
+    hart_id: u32,
+    f: fn(usize),
+    arg: usize,
+    token: &ThreadToken,
+) -> KernelResult<()> {
+    let ht = HardwareThread::this_hart();
+    if hart_id == ht.id().0 {
+        without_interrupts(ht, || f(arg));
+        return Ok(());
+    }
+
+    let hart = target(hart_id)?;
+    let state = AtomicU8::new(CALL_PENDING);
+    let mut msg = Message::Call {
+        f,
+        arg,
+        state: &state,
+    };
+    loop {
+        match hart.mailbox().push(ht, msg) {
+            Ok(()) => break,
+            Err(x) => msg = x,
+        }
+        if !accepts_messages(hart.status()) {
+            return Err(KernelError::InvalidArgument);
+        }
+        ht.do_yield(token);
+    }
+    notify(hart_id);
+
+    loop {
+        match state.load(Ordering::SeqCst) {
+            CALL_DONE => break Ok(()),
+            CALL_DROPPED => break Err(KernelError::Busy),
+            _ => {}
+        }
+        // Don't wait for a hart that went offline. If the call is no longer queued, it is running
+        // and `state` will be updated shortly.
+        if !accepts_messages(hart.status()) && hart.mailbox().cancel(ht, &state) {
+            break Err(KernelError::Busy);
+        }
+        ht.do_yield(token);
+    }
+}
+
+/// Stops all other harts. Used by the panic handler, so it never allocates or locks.
+pub fn stop_others() {
+    let harts = match try_harts() {
+        Some(x) => x,
+        None => return,
+    };
+    let this_hart = super::current_hart_id();
+    for hart in harts {
+        if hart.id() != this_hart && hart.status() != HartStatus::Offline {
+            hart.mailbox().set_pending(PENDING_STOP);
+            let _ = sbi::send_ipi(1, hart.id() as usize);
+        }
+    }
+}
+
+/// Handles the pending messages of the current hart. Returns whether a reschedule is requested.
+///
+/// Called in interrupt context on `SupervisorSoft`.
+pub fn handle(ht: &HardwareThread) -> bool {
+    let mailbox = hart(ht.id().0)
+        .expect("ipi::handle: hart not found")
+        .mailbox();
+    // All flushes up to this ticket have been posted before we look at the mailbox.
+    let tlb_flush_requests = mailbox.tlb_flush_requests.load(Ordering::SeqCst);
+    let pending = mailbox.pending.swap(0, Ordering::SeqCst);
+
+    if pending & PENDING_STOP != 0 {
+        halt();
+    }
+
+    let mut flush_all = pending & PENDING_TLB_FLUSH_ALL != 0;
+    if pending & PENDING_QUEUE != 0 {
+        loop {
+            let msg = match mailbox.queue.lock().pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            match msg {
+                Message::Call { f, arg, state } => {
+                    f(arg);
+                    unsafe {
+                        (*state).store(CALL_DONE, Ordering::SeqCst);
+                    }
+                }
+                Message::TlbFlush { range } => {
+                    if range.end.0 - range.start.0 > tlb::FLUSH_ALL_THRESHOLD {
+                        flush_all = true;
+                    } else if !flush_all {
+                        tlb::flush_local(Some(range));
+                    }
+                }
+            }
+        }
+    }
+    if flush_all {
+        tlb::flush_local(None);
+    }
+    mailbox
+        .tlb_flush_done
+        .fetch_max(tlb_flush_requests, Ordering::SeqCst);
+
+    pending & PENDING_RESCHEDULE != 0
+}
+
+fn halt() -> ! {
+    unsafe {
+        clear_sie();
+    }
+    loop {
+        unsafe {
+            wfi();
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use crate::dtb;
+use crate::error::*;
+use crate::init;
+use crate::interrupt;
+use crate::memory::{tlb, VirtualAddress};
+use crate::process::ThreadToken;
+use crate::sbi::{self, HsmStatus, SbiError};
+use crate::scheduler::HardwareThread;
+use crate::sync::{lock, Once};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use riscv::register::time;
+
+pub mod ipi;
+
+use ipi::Mailbox;
+
+/// How long to wait for an AP to report in, in microseconds.
+const AP_BOOT_TIMEOUT_US: u64 = 1000000;
+
+/// Size of the stack an AP runs on until it switches to its first thread.
+const AP_BOOT_STACK_SIZE: usize = 16384;
+
+/// How long to wait for a hart to stop after going offline, in microseconds.
+const HART_STOP_TIMEOUT_US: u64 = 1000000;
+
+const OFFLINE_REQUEST_NONE: u8 = 0;
+const OFFLINE_REQUEST_PENDING: u8 = 1;
+const OFFLINE_REQUEST_FAILED: u8 = 2;
+
+const NO_HART: u32 = u32::MAX;
+
+static BOOT_HART: AtomicU32 = AtomicU32::new(NO_HART);
+static HARTS: Once<Vec<HartState>> = Once::new();
+static CURRENT_BOOT_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Serializes hart offlining and onlining.
+static HOTPLUG_LOCK: lock::Mutex<()> = lock::Mutex::new(());
+
+/// Read by parked APs in `entry.asm`.
+#[no_mangle]
+static AP_BOOT_SLOT: ApBootSlot = ApBootSlot {
+    hart_id: AtomicUsize::new(usize::MAX),
+    stack_top: AtomicUsize::new(0),
+};
+
+extern "C" {
+    fn _start();
+}
+
+/// The hart that may leave `park` in `entry.asm`, and the stack it should use.
+#[repr(C)]
+struct ApBootSlot {
+    hart_id: AtomicUsize,
+    stack_top: AtomicUsize,
+}
+
+#[repr(align(16))]
+struct ApBootStack([u8; AP_BOOT_STACK_SIZE]);
+
+/// Per-hart bring-up state.
+pub struct HartState {
+    id: u32,
+    status: AtomicU8,
+
+    /// The `HardwareThread` of this hart, leaked from a `Pin<Box<_>>`.
+    ht: AtomicPtr<HardwareThread>,
+
+    offline_request: AtomicU8,
+
+    mailbox: Mailbox,
+
+    /// Allocated when the hart is first started and reused when it is started again.
+    boot_stack: AtomicPtr<ApBootStack>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum HartStatus {
+    Offline = 0,
+    Starting = 1,
+    Online = 2,
+    Failed = 3,
+
+    /// Draining its run queue before stopping.
+    Stopping = 4,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ApBootError {
+    /// `hart_start` returned an SBI error.
+    Sbi(SbiError),
+
+    /// The hart did not report in before the timeout.
+    Timeout,
+}
+
+impl HartState {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn status(&self) -> HartStatus {
+        match self.status.load(Ordering::SeqCst) {
+            0 => HartStatus::Offline,
+            1 => HartStatus::Starting,
+            2 => HartStatus::Online,
+            3 => HartStatus::Failed,
+            _ => HartStatus::Stopping,
+        }
+    }
+
+    pub fn set_status(&self, status: HartStatus) {
+        self.status.store(status as u8, Ordering::SeqCst);
+    }
+
+    pub fn mailbox(&self) -> &Mailbox {
+        &self.mailbox
+    }
+}
+
+/// Records the ID of the boot hart, which is elected in `entry.asm`.
+///
+/// With legacy firmware every hart enters the kernel at the same time, and with HSM the boot hart
+/// is not necessarily hart 0, so the first hart to get there wins.
+pub unsafe fn set_boot_hart(hart_id: u32) {
+    BOOT_HART.store(hart_id, Ordering::SeqCst);
+}
+
+pub fn boot_hart_id() -> u32 {
+    BOOT_HART.load(Ordering::SeqCst)
+}
+
+/// Enumerates harts from the device tree.
+///
+/// Harts with an ID above `tlb::MAX_HART_ID` are ignored. Must be called on the boot hart after
+/// `dtb::init`.
+pub fn init() {
+    let boot_hart = boot_hart_id();
+    assert!(
+        boot_hart <= tlb::MAX_HART_ID,
+        "smp::init: boot hart ID {} out of range",
+        boot_hart
+    );
+    let harts = HARTS.call_once(|| {
+        dtb::device_tree()
+            .harts()
+            .iter()
+            .filter(|x| x.enabled)
+            .filter(|x| {
+                if x.id > tlb::MAX_HART_ID {
+                    warn!("Ignoring hart {}: ID out of range.", x.id);
+                }
+                x.id <= tlb::MAX_HART_ID
+            })
+            .map(|x| HartState {
+                id: x.id,
+                status: AtomicU8::new(if x.id == boot_hart {
+                    HartStatus::Online as u8
+                } else {
+                    HartStatus::Offline as u8
+                }),
+                ht: AtomicPtr::new(ptr::null_mut()),
+                offline_request: AtomicU8::new(OFFLINE_REQUEST_NONE),
+                mailbox: Mailbox::new(),
+                boot_stack: AtomicPtr::new(ptr::null_mut()),
+            })
+            .collect()
+    });
+    assert!(
+        harts.iter().any(|x| x.id == boot_hart),
+        "smp::init: boot hart not found in device tree"
+    );
+    info!(
+        "Initialized. {} hart(s), boot hart is {}. HSM {}.",
+        harts.len(),
+        boot_hart,
+        if sbi::extensions().hsm {
+            "available"
+        } else {
+            "not available"
+        }
+    );
+}
+
+pub fn harts() -> &'static [HartState] {
+    try_harts().expect("smp::harts: not initialized")
+}
+
+pub fn try_harts() -> Option<&'static [HartState]> {
+    HARTS.r#try().map(|x| &x[..])
+}
+
+pub fn hart(hart_id: u32) -> Option<&'static HartState> {
+    harts().iter().find(|x| x.id == hart_id)
+}
+
+/// Returns the number of online harts.
+pub fn num_harts() -> u32 {
+    harts()
+        .iter()
+        .filter(|x| x.status() == HartStatus::Online)
+        .count() as u32
+}
+
+/// Returns the ID of the current hart.
+///
+/// Also works before the `HardwareThread` of the current hart is started, where `tp` still holds
+/// the hart ID passed in by the firmware.
+pub fn current_hart_id() -> u32 {
+    match HardwareThread::try_this_hart() {
+        Some(ht) => ht.id().0,
+        None => {
+            let tp: usize;
+            unsafe {
+                llvm_asm!("mv $0, tp" : "=r"(tp) :::);
+            }
+            tp as u32
+        }
+    }
+}
+
+/// Makes `ht` the `HardwareThread` of its hart.
+pub fn register_hardware_thread(ht: Pin<Box<HardwareThread>>) -> &'static HardwareThread {
+    let hart = hart(ht.id().0).expect("register_hardware_thread: hart not found");
+    let ht = Box::into_raw(unsafe { Pin::into_inner_unchecked(ht) });
+    let prev = hart.ht.swap(ht, Ordering::SeqCst);
+    assert!(
+        prev.is_null(),
+        "register_hardware_thread: hart already has a HardwareThread"
+    );
+    unsafe { &*ht }
+}
+
+/// Returns the `HardwareThread` of a hart. It lives as long as the kernel, even while the hart
+/// is offline.
+pub fn hardware_thread(hart_id: u32) -> Option<&'static HardwareThread> {
+    let ht = hart(hart_id)?.ht.load(Ordering::SeqCst);
+    if ht.is_null() {
+        None
+    } else {
+        Some(unsafe { &*ht })
+    }
+}
+
+fn hotplug_lock() -> Pin<&'static lock::Mutex<()>> {
+    unsafe { Pin::new_unchecked(&HOTPLUG_LOCK) }
+}
+
+/// Entry of an AP, on the stack handed out by `start_ap`.
+pub unsafe fn ap_boot(hart_id: u32) -> ! {
+    interrupt::ap_init();
+    init::ap_start(hart_id);
+}
+
+/// Starts all application processors, one at a time.
+///
+/// Harts that fail to come up are reported and marked as `Failed`.
+///
+/// # Safety
+///
+/// Can only be called once from the init thread, before locking is enabled.
+pub unsafe fn boot_aps() {
+    let use_hsm = sbi::extensions().hsm;
+    let mut failed = 0;
+    for hart in harts() {
+        if hart.id == boot_hart_id() {
+            continue;
+        }
+        match start_ap(hart, use_hsm) {
+            Ok(()) => debug!("Hart {} started.", hart.id),
+            Err(e) => {
+                warn!("Hart {} failed to start: {:?}", hart.id, e);
+                failed += 1;
+            }
+        }
+    }
+    info!(
+        "{} of {} hart(s) online, {} failed.",
+        num_harts(),
+        harts().len(),
+        failed
+    );
+}
+
+unsafe fn start_ap(hart: &HartState, use_hsm: bool) -> Result<(), ApBootError> {
+    if hart.ht.load(Ordering::SeqCst).is_null() {
+        // Allocate on this hart. The AP cannot allocate before it has a `HardwareThread`.
+        register_hardware_thread(init::make_ap_hardware_thread(hart.id));
+    }
+    if hart.boot_stack.load(Ordering::SeqCst).is_null() {
+        let stack: Box<ApBootStack> = Box::new_zeroed().assume_init();
+        hart.boot_stack
+            .store(Box::into_raw(stack), Ordering::SeqCst);
+    }
+    let stack_top = hart.boot_stack.load(Ordering::SeqCst) as usize + AP_BOOT_STACK_SIZE;
+
+    hart.mailbox.clear();
+    hart.set_status(HartStatus::Starting);
+    clear_ap_boot_done();
+    AP_BOOT_SLOT.stack_top.store(stack_top, Ordering::SeqCst);
+    AP_BOOT_SLOT
+        .hart_id
+        .store(hart.id as usize, Ordering::SeqCst);
+
+    let result = start_ap_inner(hart, use_hsm);
+    AP_BOOT_SLOT.hart_id.store(usize::MAX, Ordering::SeqCst);
+    hart.set_status(match result {
+        Ok(()) => HartStatus::Online,
+        Err(_) => HartStatus::Failed,
+    });
+    result
+}
+
+unsafe fn start_ap_inner(hart: &HartState, use_hsm: bool) -> Result<(), ApBootError> {
+    if use_hsm {
+        let entry = VirtualAddress(_start as usize)
+            .to_phys()
+            .expect("smp::start_ap: bad entry address");
+        // The hart goes through the boot hart election again, loses and picks up its stack from
+        // `AP_BOOT_SLOT`.
+        match sbi::hart_start(hart.id as usize, entry.0, 0) {
+            Ok(()) => {}
+            // Already running. Legacy boot protocol: the hart is parked in `entry.asm`.
+            Err(SbiError::AlreadyAvailable) => {}
+            Err(e) => return Err(ApBootError::Sbi(e)),
+        }
+    }
+
+    if wait_until(AP_BOOT_TIMEOUT_US, ap_boot_done) {
+        Ok(())
+    } else {
+        Err(ApBootError::Timeout)
+    }
+}
+
+/// Spins until `f` returns `true` or `timeout_us` has passed. Returns the last result of `f`.
+fn wait_until<F: FnMut() -> bool>(timeout_us: u64, mut f: F) -> bool {
+    let timebase = dtb::device_tree().timebase_frequency();
+    let deadline = time::read() as u64 + timebase * timeout_us / 1000000;
+    loop {
+        if f() {
+            break true;
+        }
+        if time::read() as u64 >= deadline {
+            break f();
+        }
+    }
+}
+
+/// Takes a hart offline.
+///
+/// The daemon thread of the target hart drains its run queue to other harts and stops it with
+/// SBI HSM. The boot hart and the current hart cannot be taken offline.
+pub fn offline_hart(hart_id: u32, token: &ThreadToken) -> KernelResult<()> {
+    if !sbi::extensions().hsm {
+        return Err(KernelError::NotSupported);
+    }
+    let hart = hart(hart_id).ok_or(KernelError::InvalidArgument)?;
+    let ht = HardwareThread::this_hart();
+    if hart_id == boot_hart_id() || hart_id == ht.id().0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let _guard = hotplug_lock().lock(token);
+    if hart.status() != HartStatus::Online {
+        return Err(KernelError::InvalidArgument);
+    }
+    hart.offline_request
+        .store(OFFLINE_REQUEST_PENDING, Ordering::SeqCst);
+    loop {
+        if hart.status() == HartStatus::Offline {
+            hart.offline_request
+                .store(OFFLINE_REQUEST_NONE, Ordering::SeqCst);
+            info!("Hart {} is offline.", hart_id);
+            break Ok(());
+        }
+        if hart.offline_request.load(Ordering::SeqCst) == OFFLINE_REQUEST_FAILED {
+            hart.offline_request
+                .store(OFFLINE_REQUEST_NONE, Ordering::SeqCst);
+            break Err(KernelError::Busy);
+        }
+        ht.do_yield(token);
+    }
+}
+
+/// Brings an offline hart back online, reusing its `HardwareThread`.
+pub fn online_hart(hart_id: u32, token: &ThreadToken) -> KernelResult<()> {
+    let hart = hart(hart_id).ok_or(KernelError::InvalidArgument)?;
+
+    let _guard = hotplug_lock().lock(token);
+    if hart.status() != HartStatus::Offline {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    // `Offline` is set right before `hart_stop`. Wait until the hart is actually stopped.
+    let stopped = wait_until(HART_STOP_TIMEOUT_US, || {
+        sbi::hart_get_status(hart_id as usize) == Ok(HsmStatus::Stopped)
+    });
+    if !stopped {
+        return Err(KernelError::Timeout);
+    }
+
+    if let Some(ht) = hardware_thread(hart_id) {
+        unsafe {
+            ht.restart(init::make_apd_thread());
+        }
+    }
+
+    match unsafe { start_ap(hart, true) } {
+        Ok(()) => {
+            info!("Hart {} is online.", hart_id);
+            Ok(())
+        }
+        Err(ApBootError::Timeout) => Err(KernelError::Timeout),
+        Err(ApBootError::Sbi(_)) => Err(KernelError::Busy),
+    }
+}
+
+/// Returns whether the hart has been asked to go offline. Polled by its daemon thread.
+pub fn offline_requested(hart_id: u32) -> bool {
+    match hart(hart_id) {
+        Some(x) => x.offline_request.load(Ordering::SeqCst) == OFFLINE_REQUEST_PENDING,
+        None => false,
+    }
+}
+
+/// Reports that the hart failed to go offline.
+pub fn offline_failed(hart_id: u32) {
+    if let Some(x) = hart(hart_id) {
+        x.offline_request
+            .store(OFFLINE_REQUEST_FAILED, Ordering::SeqCst);
+    }
+}
+
+pub unsafe fn set_ap_boot_done() {
+    CURRENT_BOOT_DONE.store(true, Ordering::SeqCst);
+}
+
+pub fn clear_ap_boot_done() {
+    CURRENT_BOOT_DONE.store(false, Ordering::SeqCst);
+}
+
+pub fn ap_boot_done() -> bool {
+    CURRENT_BOOT_DONE.load(Ordering::SeqCst)
+}
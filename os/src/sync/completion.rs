@@ -0,0 +1,93 @@
+//! One-shot completion, signaled from interrupt context and waited on by a thread.
+
+use crate::error::*;
+use crate::process::{Thread, ThreadToken};
+use crate::scheduler::{HardwareThread, PolicyContext};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const PENDING: usize = 0;
+const DONE: usize = 1;
+
+pub struct Completion {
+    /// `PENDING`, `DONE`, or a pointer to the `Thread` waiting.
+    state: AtomicUsize,
+
+    value: AtomicUsize,
+}
+
+impl Completion {
+    pub const fn new() -> Completion {
+        Completion {
+            state: AtomicUsize::new(PENDING),
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == DONE
+    }
+
+    /// Signals completion with `value`, and wakes the waiting thread on its home hart.
+    ///
+    /// Fails with `Busy` if the run queue of the home hart is full, in which case nothing
+    /// changes and the call can be retried.
+    ///
+    /// Must be called with interrupts disabled. Does not allocate, so can be called from an
+    /// interrupt handler.
+    pub fn complete(&self, value: usize) -> KernelResult<()> {
+        self.value.store(value, Ordering::SeqCst);
+        match self.state.swap(DONE, Ordering::SeqCst) {
+            PENDING => Ok(()),
+            DONE => panic!("Completion::complete: completed twice"),
+            th => {
+                let th = unsafe { Box::from_raw(th as *mut Thread) };
+                // Harts with completion waiters stay online.
+                let home = unsafe { &*th.raw_thread_state().hart };
+                match home.add_thread_from(HardwareThread::this_hart(), th) {
+                    Ok(()) => {
+                        home.completion_waiters().fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                    Err(th) => {
+                        self.state
+                            .store(Box::into_raw(th) as usize, Ordering::SeqCst);
+                        Err(KernelError::Busy)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits until completed, and returns the value passed to `complete`.
+    pub fn wait(&self, ht: &HardwareThread, token: &ThreadToken) -> usize {
+        if !self.is_done() {
+            unsafe {
+                ht.release_current(
+                    |current| {
+                        let th = Box::into_raw(current);
+                        ht.completion_waiters().fetch_add(1, Ordering::SeqCst);
+                        if self
+                            .state
+                            .compare_exchange(
+                                PENDING,
+                                th as usize,
+                                Ordering::SeqCst,
+                                Ordering::SeqCst,
+                            )
+                            .is_err()
+                        {
+                            // Completed meanwhile. Run again, in the slot the next thread
+                            // was taken from.
+                            ht.completion_waiters().fetch_sub(1, Ordering::SeqCst);
+                            ht.policy()
+                                .add_thread(ht, PolicyContext::Critical, Box::from_raw(th));
+                        }
+                    },
+                    token,
+                );
+            }
+        }
+        self.value.load(Ordering::SeqCst)
+    }
+}
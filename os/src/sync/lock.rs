@@ -28,6 +28,11 @@ impl<T> Mutex<T> {
         }
     }
 
+    /// Takes the value out of a mutex that is no longer pinned.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
     /// Locks a pinned mutex.
     pub fn lock<'a>(self: Pin<&'a Self>, token: &'a ThreadToken) -> MutexGuard<'a, T> {
         let ht = HardwareThread::this_hart();
@@ -1,7 +1,9 @@
+mod completion;
 pub mod lock;
 mod waitqueue;
 mod yield_mutex;
 
+pub use completion::Completion;
 pub use spin::{Mutex, MutexGuard, Once};
 pub use waitqueue::{global_wait_queue, WaitQueue};
 pub use yield_mutex::{YieldMutex, YieldMutexGuard};
@@ -11,6 +13,7 @@ use crate::scheduler::HardwareThread;
 use core::cell::{Ref, RefCell, RefMut};
 use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use riscv::register::sstatus;
 
 pub struct IntrCell<T> {
     inner: RefCell<T>,
@@ -23,6 +26,10 @@ impl<T> IntrCell<T> {
         }
     }
 
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
     pub fn borrow_mut<'a>(&'a self, ht: &'a HardwareThread) -> IntrGuardMut<'a, T> {
         unsafe {
             ht.acquire_intr_guard();
@@ -67,3 +74,20 @@ pub fn without_interrupts<F: FnOnce() -> R, R>(ht: &HardwareThread, f: F) -> R {
     let _guard = cell.borrow_mut(ht);
     f()
 }
+
+/// Like `without_interrupts`, but usable before this hart has a `HardwareThread`.
+///
+/// `f` must not yield.
+pub fn without_interrupts_early<F: FnOnce() -> R, R>(f: F) -> R {
+    let sie = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    let ret = f();
+    if sie {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+    ret
+}
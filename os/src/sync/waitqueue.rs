@@ -2,7 +2,7 @@ use super::{without_interrupts, IntrCell};
 use crate::memory::PhysicalAddress;
 use crate::process::Thread;
 use crate::process::ThreadToken;
-use crate::scheduler::{HardwareThread, PolicyContext};
+use crate::scheduler::HardwareThread;
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::collections::linked_list::LinkedList;
@@ -69,10 +69,68 @@ impl WaitQueue {
         drop(wakeup_sets);
 
         if let Some(th) = th {
-            // FIXME: This is incorrect. Threads in kernel mode must not be migrated to
-            // a different hardware thread.
-            ht.policy()
-                .add_thread(ht, PolicyContext::NonCritical(token), th);
+            if let Err(th) = ht.wake(th, token) {
+                warn!(
+                    "wake_one: Cannot wake thread {:?}, keeping it waiting",
+                    th.id()
+                );
+                self.lock_wakeup_sets(ht, token)
+                    .entry(addr)
+                    .or_default()
+                    .push_front(Some(th));
+            }
+        }
+    }
+
+    /// Wakes all threads waiting on `addr`.
+    ///
+    /// Must only be called from a thread context because of possible allocator reentry.
+    pub fn wake_all(&self, ht: &HardwareThread, addr: PhysicalAddress, token: &ThreadToken) {
+        let threads = match self.lock_wakeup_sets(ht, token).remove(&addr) {
+            Some(x) => x,
+            None => return,
+        };
+        for th in threads {
+            let th = th.expect("WaitQueue::wake_all: got empty thread");
+            if let Err(th) = ht.wake(th, token) {
+                warn!(
+                    "wake_all: Cannot wake thread {:?}, keeping it waiting",
+                    th.id()
+                );
+                self.lock_wakeup_sets(ht, token)
+                    .entry(addr)
+                    .or_default()
+                    .push_back(Some(th));
+            }
+        }
+    }
+
+    /// Returns whether any thread of `ht` is waiting, or `None` if the queue is locked.
+    ///
+    /// Does not allocate or yield.
+    pub fn has_waiters_on(&self, ht: &HardwareThread) -> Option<bool> {
+        let wakeup_sets = self.wakeup_sets.try_lock()?;
+        let ht = ht as *const HardwareThread as *mut HardwareThread;
+        Some(wakeup_sets.values().flatten().any(|th| match th {
+            Some(th) => th.raw_thread_state().hart == ht,
+            None => false,
+        }))
+    }
+
+    /// Calls `f` on each waiting thread, with the address it waits on.
+    ///
+    /// `f` must not allocate.
+    pub fn for_each_waiter(
+        &self,
+        ht: &HardwareThread,
+        token: &ThreadToken,
+        f: &mut dyn FnMut(PhysicalAddress, &Thread),
+    ) {
+        let wakeup_sets = self.lock_wakeup_sets(ht, token);
+        for (&addr, threads) in wakeup_sets.iter() {
+            for th in threads.iter().flatten() {
+                f(addr, &**th);
+            }
         }
     }
 
@@ -1,5 +1,10 @@
+use crate::memory::{
+    boot_page_pool, PageTableEntryFlags, Segment, SegmentBacking, VirtualPageNumber,
+};
 use crate::process::{spawn, KernelTask, ThreadToken};
+use crate::sbi;
 use crate::scheduler::HardwareThread;
+use crate::smp;
 use alloc::boxed::Box;
 use core::mem;
 use core::pin::Pin;
@@ -68,3 +73,484 @@ pub fn test_mutex(ht: &HardwareThread, token: &ThreadToken) {
 
     println!("test_mutex ok");
 }
+
+pub fn test_hart_hotplug(ht: &HardwareThread, token: &ThreadToken) {
+    println!("running test: test_hart_hotplug");
+
+    if !sbi::extensions().hsm {
+        println!("test_hart_hotplug skipped: HSM not available");
+        return;
+    }
+
+    let target = match smp::harts()
+        .iter()
+        .rev()
+        .find(|x| x.id() != ht.id().0 && x.status() == smp::HartStatus::Online)
+    {
+        Some(x) => x,
+        None => {
+            println!("test_hart_hotplug skipped: no other online hart");
+            return;
+        }
+    };
+    let num_harts = smp::num_harts();
+
+    smp::offline_hart(target.id(), token).expect("test_hart_hotplug: offline_hart failed");
+    assert_eq!(target.status(), smp::HartStatus::Offline);
+    assert_eq!(smp::num_harts(), num_harts - 1);
+
+    smp::online_hart(target.id(), token).expect("test_hart_hotplug: online_hart failed");
+    assert_eq!(target.status(), smp::HartStatus::Online);
+    assert_eq!(smp::num_harts(), num_harts);
+
+    println!("test_hart_hotplug ok");
+}
+
+pub fn test_ipi_call(ht: &HardwareThread, token: &ThreadToken) {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    static LAST_HART: AtomicU32 = AtomicU32::new(u32::MAX);
+
+    fn record_hart(_: usize) {
+        LAST_HART.store(HardwareThread::this_hart().id().0, Ordering::SeqCst);
+    }
+
+    println!("running test: test_ipi_call");
+
+    for hart in smp::harts() {
+        if hart.status() != smp::HartStatus::Online {
+            continue;
+        }
+        smp::ipi::call_on_hart(hart.id(), record_hart, 0, token)
+            .expect("test_ipi_call: call_on_hart failed");
+        assert_eq!(
+            LAST_HART.load(Ordering::SeqCst),
+            hart.id(),
+            "test_ipi_call: call ran on the wrong hart"
+        );
+    }
+
+    println!("test_ipi_call ok");
+}
+
+pub fn test_block(ht: &HardwareThread, token: &ThreadToken) {
+    use crate::drivers::block;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    println!("running test: test_block");
+
+    // Only touches the last block of each device, and restores it.
+    for device in block::devices() {
+        if device.num_blocks() == 0 {
+            continue;
+        }
+        let size = device.block_size();
+        let last = device.num_blocks() - 1;
+        let mut saved = vec![0u8; size];
+        device
+            .read(last, &mut saved, ht, token)
+            .expect("test_block: read failed");
+        if device.read_only() {
+            continue;
+        }
+
+        let pattern: Vec<u8> = (0..size).map(|i| (i * 7 + 3) as u8).collect();
+        device
+            .write(last, &pattern, ht, token)
+            .expect("test_block: write failed");
+        let mut check = vec![0u8; size];
+        device
+            .read(last, &mut check, ht, token)
+            .expect("test_block: read back failed");
+        assert!(
+            check == pattern,
+            "test_block: {} lost a write",
+            device.name()
+        );
+
+        device
+            .write(last, &saved, ht, token)
+            .expect("test_block: restore failed");
+    }
+
+    println!("test_block ok");
+}
+
+pub fn test_net(ht: &HardwareThread, token: &ThreadToken) {
+    use crate::drivers::net;
+    use crate::net::{ping, Ipv4Addr, TcpListener, TcpStream, UdpSocket, GATEWAY};
+    use alloc::vec::Vec;
+
+    println!("running test: test_net");
+
+    ping(Ipv4Addr::LOOPBACK, ht, token).expect("test_net: loopback ping failed");
+
+    let a = UdpSocket::bind(0, token).expect("test_net: UDP bind failed");
+    let b = UdpSocket::bind(0, token).expect("test_net: UDP bind failed");
+    a.send_to(b"datagram", Ipv4Addr::LOOPBACK, b.local_port(), token)
+        .expect("test_net: UDP send failed");
+    let mut buf = [0u8; 64];
+    let (n, src, port) = b
+        .recv_from(&mut buf, ht, token)
+        .expect("test_net: UDP receive failed");
+    assert_eq!(&buf[..n], b"datagram");
+    assert!(src == Ipv4Addr::LOOPBACK && port == a.local_port());
+
+    // Larger than the buffers, so windows and acknowledgements matter.
+    let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+    let listener = TcpListener::bind(7777, token).expect("test_net: TCP listen failed");
+    let client = TcpStream::connect(Ipv4Addr::LOOPBACK, 7777, ht, token)
+        .expect("test_net: TCP connect failed");
+    let server = listener
+        .accept(ht, token)
+        .expect("test_net: TCP accept failed");
+    // Both run on this thread, so read each chunk before the buffers fill up.
+    let mut received = Vec::new();
+    let mut sent = 0;
+    for chunk in data.chunks(10000) {
+        client
+            .write(chunk, ht, token)
+            .expect("test_net: TCP write failed");
+        sent += chunk.len();
+        while received.len() < sent {
+            let n = server
+                .read(&mut buf, ht, token)
+                .expect("test_net: TCP read failed");
+            received.extend_from_slice(&buf[..n]);
+        }
+    }
+    client.shutdown(token).unwrap();
+    loop {
+        let n = server
+            .read(&mut buf, ht, token)
+            .expect("test_net: TCP read failed");
+        if n == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert!(received == data, "test_net: TCP data corrupted");
+
+    if !net::devices().is_empty() {
+        ping(GATEWAY.get(), ht, token).expect("test_net: gateway ping failed");
+    }
+
+    println!("test_net ok");
+}
+
+pub fn test_timer(ht: &HardwareThread, token: &ThreadToken) {
+    use crate::scheduler::{Timer, TimerCallback};
+    use crate::timekeeping::now_monotonic;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    struct Counter(Arc<AtomicUsize>);
+    impl TimerCallback for Counter {
+        fn fire(&self, _: &HardwareThread, _: &ThreadToken) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    println!("running test: test_timer");
+
+    let start = now_monotonic();
+    ht.sleep_until(start + Duration::from_millis(50), token);
+    assert!(
+        now_monotonic() - start >= Duration::from_millis(50),
+        "test_timer: woken up early"
+    );
+
+    let oneshot = Arc::new(AtomicUsize::new(0));
+    let periodic = Arc::new(AtomicUsize::new(0));
+    let a = Timer::new(Box::new(Counter(oneshot.clone())));
+    let b = Timer::new(Box::new(Counter(periodic.clone())));
+    let start = now_monotonic();
+    a.start_oneshot(start + Duration::from_millis(20), ht, token)
+        .expect("test_timer: cannot start one-shot timer");
+    b.start_periodic(Duration::from_millis(10), ht, token)
+        .expect("test_timer: cannot start periodic timer");
+    ht.sleep_until(start + Duration::from_millis(100), token);
+    b.cancel();
+    let n = periodic.load(Ordering::SeqCst);
+    assert_eq!(oneshot.load(Ordering::SeqCst), 1);
+    assert!(n >= 3, "test_timer: periodic timer fired {} times", n);
+
+    ht.sleep_until(now_monotonic() + Duration::from_millis(30), token);
+    assert_eq!(periodic.load(Ordering::SeqCst), n);
+
+    println!("test_timer ok");
+}
+
+/// Pages used from the boot page pool, compared to when `start` was called.
+struct PoolUsage(usize);
+
+impl PoolUsage {
+    fn start(token: &ThreadToken) -> PoolUsage {
+        PoolUsage(Self::used_pages(token))
+    }
+
+    fn used_pages(token: &ThreadToken) -> usize {
+        boot_page_pool().set_usage(token).iter().sum()
+    }
+
+    /// Asserts that exactly `expected` pages more are used than at the start.
+    fn check(&self, test: &str, expected: usize, token: &ThreadToken) {
+        assert_eq!(
+            Self::used_pages(token),
+            self.0 + expected,
+            "{}: unexpected pool usage",
+            test
+        );
+    }
+}
+
+/// A readable and writable segment, not accessible from user mode so that tests can access it.
+fn rw_segment(start: VirtualPageNumber, pages: usize, backing: SegmentBacking) -> Segment {
+    Segment {
+        range: start..VirtualPageNumber(start.0 + pages),
+        backing,
+        flags: PageTableEntryFlags::VALID
+            | PageTableEntryFlags::READABLE
+            | PageTableEntryFlags::WRITABLE,
+    }
+}
+
+pub fn test_demand_paging(ht: &HardwareThread, token: &ThreadToken) {
+    use crate::memory::boot_mapping;
+    use crate::process::LockedProcess;
+    use core::ptr;
+
+    println!("running test: test_demand_paging");
+
+    let usage = PoolUsage::start(token);
+    let process = LockedProcess::new(boot_page_pool().clone(), token)
+        .expect("test_demand_paging: cannot create process");
+    // 1 GB in the user half.
+    let start = VirtualPageNumber(0x40000);
+    let num_pages = 0x40000;
+    process
+        .lock(token)
+        .add_segment(
+            rw_segment(start, num_pages, SegmentBacking::OwnedLazy),
+            token,
+        )
+        .expect("test_demand_paging: cannot add segment");
+
+    // Only the root table.
+    usage.check("test_demand_paging", 1, token);
+    ht.with_current(|th| th.process = Some(process.clone()));
+    process.lock(token).mapping().activate_thread(token);
+
+    // One word in each quarter of the segment.
+    let base = start.start_address().as_mut_ptr::<u64>();
+    let word = |i: usize| unsafe { base.add(i * num_pages / 4 * 512) };
+    for i in 0..4 {
+        unsafe {
+            assert_eq!(ptr::read_volatile(word(i)), 0);
+            ptr::write_volatile(word(i), i as u64 + 1);
+        }
+    }
+    for i in 0..4 {
+        assert_eq!(unsafe { ptr::read_volatile(word(i)) }, i as u64 + 1);
+    }
+
+    boot_mapping().activate_thread(token);
+    ht.with_current(|th| th.process = None);
+
+    // Four pages, each in its own 2 MB range, so with four level 2 tables and one level 1 table.
+    usage.check("test_demand_paging", 1 + 4 + 4 + 1, token);
+
+    assert!(process.release(token).is_ok());
+    usage.check("test_demand_paging", 0, token);
+
+    println!("test_demand_paging ok");
+}
+
+pub fn test_fork(ht: &HardwareThread, token: &ThreadToken) {
+    use crate::memory::boot_mapping;
+    use crate::process::LockedProcess;
+    use core::ptr;
+
+    println!("running test: test_fork");
+
+    let usage = PoolUsage::start(token);
+    let parent = LockedProcess::new(boot_page_pool().clone(), token)
+        .expect("test_fork: cannot create process");
+    let start = VirtualPageNumber(0x40000);
+    parent
+        .lock(token)
+        .add_segment(rw_segment(start, 16, SegmentBacking::Owned), token)
+        .expect("test_fork: cannot add segment");
+    // Three tables and 16 pages.
+    usage.check("test_fork", 3 + 16, token);
+
+    let enter = |p: &LockedProcess| {
+        ht.with_current(|th| th.process = Some(p.clone()));
+        p.lock(token).mapping().activate_thread(token);
+    };
+    let word = start.start_address().as_mut_ptr::<u64>();
+
+    enter(&parent);
+    unsafe { ptr::write_volatile(word, 1) };
+    let child = parent.fork(token).expect("test_fork: fork failed");
+    // Only the tables of the child. The pages are shared.
+    usage.check("test_fork", 3 + 16 + 3, token);
+
+    // The parent copies the page, and the child then owns the original alone.
+    unsafe { ptr::write_volatile(word, 2) };
+    enter(&child);
+    assert_eq!(unsafe { ptr::read_volatile(word) }, 1);
+    unsafe { ptr::write_volatile(word, 3) };
+    enter(&parent);
+    assert_eq!(unsafe { ptr::read_volatile(word) }, 2);
+
+    boot_mapping().activate_thread(token);
+    ht.with_current(|th| th.process = None);
+
+    // One copy by the parent. The child took over the original.
+    usage.check("test_fork", 3 + 16 + 3 + 1, token);
+
+    assert!(parent.release(token).is_ok());
+    assert!(child.release(token).is_ok());
+    usage.check("test_fork", 0, token);
+
+    println!("test_fork ok");
+}
+
+pub fn test_unmap(_ht: &HardwareThread, token: &ThreadToken) {
+    use crate::memory::{Mapping, PhysicalPageNumber};
+    use alloc::vec::Vec;
+
+    println!("running test: test_unmap");
+
+    let usage = PoolUsage::start(token);
+    let mut mapping =
+        Mapping::new(boot_page_pool().clone(), token).expect("test_unmap: cannot create mapping");
+    let leaves = |mapping: &Mapping| {
+        mapping
+            .leaves()
+            .take_while(|x| (x.0).0 < 0x4000000)
+            .map(|(vpn, level, entry)| (vpn, level, entry.flags()))
+            .collect::<Vec<_>>()
+    };
+
+    // A 2 MB page, never accessed, and 16 owned pages.
+    let huge = VirtualPageNumber(0x40000);
+    let owned = VirtualPageNumber(0x80000);
+    for seg in &[
+        rw_segment(
+            huge,
+            512,
+            SegmentBacking::Linear {
+                phys_start: PhysicalPageNumber(0x80000),
+            },
+        ),
+        rw_segment(owned, 16, SegmentBacking::Owned),
+    ] {
+        mapping
+            .map_segment(seg, token)
+            .expect("test_unmap: cannot map segment");
+    }
+    assert_eq!(leaves(&mapping).len(), 17);
+    assert_eq!(leaves(&mapping)[0].1, 1);
+    // The root table, a level 1 table for the 2 MB page, and two tables for the owned pages.
+    usage.check("test_unmap", 1 + 1 + 2 + 16, token);
+
+    // Unmapping one page splits the 2 MB page.
+    mapping
+        .unmap_range(
+            VirtualPageNumber(huge.0 + 1)..VirtualPageNumber(huge.0 + 2),
+            token,
+        )
+        .expect("test_unmap: cannot unmap");
+    let split = leaves(&mapping);
+    assert_eq!(split.len(), 511 + 16);
+    assert!(split[..511].iter().all(|x| x.1 == 2));
+    assert_eq!(split[1].0, VirtualPageNumber(huge.0 + 2));
+    usage.check("test_unmap", 1 + 1 + 2 + 16 + 1, token);
+
+    mapping
+        .protect_range(
+            owned..VirtualPageNumber(owned.0 + 8),
+            PageTableEntryFlags::VALID | PageTableEntryFlags::READABLE,
+            token,
+        )
+        .expect("test_unmap: cannot protect");
+    let protected = leaves(&mapping);
+    assert!(protected[511..519]
+        .iter()
+        .all(|x| !x.2.contains(PageTableEntryFlags::WRITABLE)));
+    assert!(protected[519..]
+        .iter()
+        .all(|x| x.2.contains(PageTableEntryFlags::WRITABLE)));
+
+    // Owned pages and the tables of both segments go back to the pool, but not the root table.
+    mapping
+        .unmap_range(huge..VirtualPageNumber(owned.0 + 16), token)
+        .expect("test_unmap: cannot unmap");
+    assert!(leaves(&mapping).is_empty());
+    usage.check("test_unmap", 1, token);
+
+    mapping.release(token);
+    usage.check("test_unmap", 0, token);
+
+    println!("test_unmap ok");
+}
+
+pub fn test_kernel_mapping(_ht: &HardwareThread, _token: &ThreadToken) {
+    use crate::layout;
+    use crate::memory::{boot_mapping, PageTableEntryFlags as Flags, VirtualAddress};
+
+    println!("running test: test_kernel_mapping");
+
+    let mapping = boot_mapping();
+    let check = |name: &str, addr: VirtualAddress, expected: Flags| {
+        let (pa, flags, _) = mapping
+            .translate(addr)
+            .unwrap_or_else(|| panic!("test_kernel_mapping: {} not mapped", name));
+        assert_eq!(Some(pa), addr.to_phys(), "test_kernel_mapping: {}", name);
+        let checked = Flags::READABLE | Flags::WRITABLE | Flags::EXECUTABLE | Flags::USER;
+        assert_eq!(flags & checked, expected, "test_kernel_mapping: {}", name);
+    };
+    check(
+        "text",
+        layout::text_start(),
+        Flags::READABLE | Flags::EXECUTABLE,
+    );
+    check("rodata", layout::rodata_start(), Flags::READABLE);
+    check(
+        "data",
+        layout::data_start(),
+        Flags::READABLE | Flags::WRITABLE,
+    );
+    check(
+        "bss",
+        layout::bss_start(),
+        Flags::READABLE | Flags::WRITABLE,
+    );
+    check(
+        "end of RAM",
+        VirtualAddress(layout::ram_end().0 - 4096),
+        Flags::READABLE | Flags::WRITABLE,
+    );
+    assert!(mapping.translate(VirtualAddress(0)).is_none());
+
+    // Only the kernel half is mapped, and never writable and executable at once.
+    for run in mapping.leaf_runs() {
+        assert!(
+            run.start.start_address() >= layout::kernel_idmap_start(),
+            "test_kernel_mapping: {}",
+            run
+        );
+        assert!(
+            !run.flags.contains(Flags::WRITABLE | Flags::EXECUTABLE),
+            "test_kernel_mapping: {}",
+            run
+        );
+    }
+    // RAM beyond the kernel image is mapped with huge pages.
+    assert!(mapping.leaf_runs().any(|x| x.level < 2));
+
+    println!("test_kernel_mapping ok");
+}
@@ -0,0 +1,109 @@
+//! Monotonic and wall-clock time.
+//!
+//! Monotonic time counts from boot with the `time` CSR, scaled by the timebase frequency from the
+//! device tree. Wall-clock time adds the RTC reading taken at boot, so it never goes backwards,
+//! but drifts with the timebase.
+
+use crate::drivers::rtc;
+use crate::dtb;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use riscv::register::time;
+
+/// Frequency of the `time` CSR. 0 until `init`.
+static TIMEBASE: AtomicU64 = AtomicU64::new(0);
+
+/// Wall-clock time at tick 0, in nanoseconds since the Unix epoch. 0 without an RTC.
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Formats time since the Unix epoch as a UTC date.
+pub struct Utc(pub Duration);
+
+impl fmt::Display for Utc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let (days, rem) = (secs / 86400, secs % 86400);
+
+        // Civil from days, after Howard Hinnant. Eras are 400 years, starting on 0000-03-01.
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        )
+    }
+}
+
+/// Raw value of the `time` CSR.
+pub fn ticks() -> u64 {
+    time::read() as u64
+}
+
+/// Converts ticks of the `time` CSR. Zero until `init`.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    match TIMEBASE.load(Ordering::Relaxed) {
+        0 => Duration::from_secs(0),
+        x => Duration::new(ticks / x, (ticks % x * 1_000_000_000 / x) as u32),
+    }
+}
+
+/// Converts to ticks of the `time` CSR, rounding down. Saturates at `u64::MAX`, i.e. never.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let timebase = TIMEBASE.load(Ordering::Relaxed);
+    duration
+        .as_secs()
+        .saturating_mul(timebase)
+        .saturating_add(duration.subsec_nanos() as u64 * timebase / 1_000_000_000)
+}
+
+/// Time since boot.
+pub fn now_monotonic() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Time since the Unix epoch, or `None` without an RTC.
+pub fn now_realtime() -> Option<Duration> {
+    match BOOT_REALTIME_NS.load(Ordering::Relaxed) {
+        0 => None,
+        x => Some(Duration::from_nanos(x) + now_monotonic()),
+    }
+}
+
+/// Reads the RTC once.
+///
+/// Must be called on the boot hart after `dtb::init` and `drivers::rtc::init`.
+pub fn init() {
+    let timebase = dtb::device_tree().timebase_frequency();
+    assert!(timebase != 0, "timekeeping::init: no timebase frequency");
+    TIMEBASE.store(timebase, Ordering::Relaxed);
+
+    let rtc = match rtc::rtc() {
+        Some(x) => x,
+        None => {
+            warn!("No RTC. Wall-clock time is unknown.");
+            return;
+        }
+    };
+    let realtime = rtc.read_ns();
+    let since_boot = now_monotonic().as_nanos() as u64;
+    BOOT_REALTIME_NS.store(
+        realtime.saturating_sub(since_boot).max(1),
+        Ordering::Relaxed,
+    );
+    info!("Wall-clock time is {}.", Utc(now_realtime().unwrap()));
+}